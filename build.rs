@@ -1,3 +1,12 @@
 fn main() {
-    tonic_prost_build::compile_protos("proto/exec.proto").unwrap();
+    tonic_prost_build::configure()
+        // 资源限制同时也是服务端配置文件的一部分.
+        .type_attribute(
+            "exec.ResourceLimits",
+            "#[derive(serde::Serialize, serde::Deserialize)] #[serde(default, deny_unknown_fields)]",
+        )
+        // 避免 Command 过大, 使 RequestChunk 的各个变体大小相差太多.
+        .boxed(".exec.Command.limits")
+        .compile_protos(&["proto/exec.proto"], &["proto"])
+        .unwrap();
 }
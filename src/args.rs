@@ -1,7 +1,9 @@
-use std::{net::SocketAddr, path::PathBuf};
+use std::{convert::Infallible, ffi::OsString, path::PathBuf, str::FromStr};
 
-use crate::DEFAULT_PORT;
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
+
+use crate::exec::ResourceLimits;
+use crate::server::listener::BindAddress;
 
 #[derive(Parser, PartialEq, Eq, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -18,14 +20,43 @@ pub enum Subcommands {
     Server(ServerArgs),
     #[command(alias = "g")]
     GenCert(GenCertArgs), // 生成证书
+    Ps(PsArgs),                         // 列出服务端的会话
+    Kill(KillArgs),                     // 终止服务端的会话
+    Cp(CpArgs),                         // 在本地和服务端之间复制文件
+    Sync(SyncArgs),                     // 将本地目录同步到服务端
+    InstallService(InstallServiceArgs), // 设置服务端开机自启
+    UninstallService(UninstallServiceArgs),
+    ServiceStatus(ServiceStatusArgs),
+}
+
+/// 连接服务端所需的参数.
+#[derive(clap::Args, PartialEq, Eq, Debug)]
+pub struct ConnectArgs {
+    #[clap(
+        long = "profile",
+        help = "Use a profile in `client.toml` under the config directory, default: $REX_PROFILE or `default_profile`."
+    )]
+    pub profile: Option<String>,
+    #[clap(
+        short = 'a',
+        long = "address",
+        help = "Server address, default: the profile's address or https://[::1]:<DEFAULT_PORT>."
+    )]
+    pub server_address: Option<String>,
+    #[clap(
+        short = 'c',
+        long = "cert",
+        help = "Client and CA cert directory path, default to `rex` under user's home config directory"
+    )]
+    pub cert_dir: Option<PathBuf>,
 }
 
 #[derive(Parser, PartialEq, Eq, Debug)]
 #[command(author, version, about = "run client", long_about = None)]
 pub struct ClientArgs {
     // todo client shell 子命令
-    #[clap(index = 1)]
-    pub executable: String,
+    #[clap(index = 1, required_unless_present = "attach")]
+    pub executable: Option<String>,
     #[clap(
         short = 'd',
         long = "current-dir",
@@ -34,8 +65,8 @@ pub struct ClientArgs {
     pub current_dir: Option<String>,
     #[clap(index = 2, help = "the executable args")]
     pub args: Vec<String>,
-    #[clap(short = 'a', long="address", default_value_t=format!("https://[::1]:{DEFAULT_PORT}"))]
-    pub server_address: String,
+    #[command(flatten)]
+    pub connect: ConnectArgs,
     #[clap(
         short = 'l',
         long = "leak",
@@ -43,29 +74,291 @@ pub struct ClientArgs {
     )]
     pub leak: bool,
     #[clap(
-        short = 'c',
-        long = "cert",
-        help = "Client and CA cert directory path, default to `rex` under user's home config directory"
+        long = "no-leak",
+        conflicts_with = "leak",
+        help = "Do not leak the client even if the profile enables it."
     )]
-    pub cert_dir: Option<PathBuf>,
+    pub no_leak: bool,
+    #[clap(
+        short = 't',
+        long = "tty",
+        help = "Run the executable in a pseudo-terminal, for interactive programs."
+    )]
+    pub pty: bool,
+    #[clap(
+        long = "attach",
+        value_name = "SESSION_ID",
+        conflicts_with = "executable",
+        help = "Attach to a leaked session instead of running a new executable."
+    )]
+    pub attach: Option<String>,
+    #[clap(
+        short = 'e',
+        long = "env",
+        value_name = "KEY=VALUE",
+        value_parser = parse_env_pair,
+        help = "Set an environment variable for the executable, can be repeated."
+    )]
+    pub env: Vec<(String, String)>,
+    #[clap(
+        long = "env-file",
+        help = "Read `KEY=VALUE` lines from a file as environment variables, `--env` takes precedence."
+    )]
+    pub env_file: Option<PathBuf>,
+    #[clap(
+        long = "clear-env",
+        help = "Do not inherit the server's environment variables."
+    )]
+    pub clear_env: bool,
+    #[clap(
+        long = "kill-grace",
+        value_name = "SECONDS",
+        help = "When the executable is killed, send SIGTERM to its process group first and SIGKILL after this many seconds."
+    )]
+    pub kill_grace_period: Option<u32>,
+    #[clap(
+        long = "timeout",
+        value_name = "SECONDS",
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "Kill the executable after it runs for this many seconds."
+    )]
+    pub timeout: Option<u32>,
+    #[clap(
+        long = "idle-timeout",
+        value_name = "SECONDS",
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "Kill the executable when it has no output and receives no input for this many seconds."
+    )]
+    pub idle_timeout: Option<u32>,
+    #[clap(
+        long = "limit",
+        value_name = "NAME=VALUE",
+        value_parser = parse_limit,
+        help = "Limit a resource of the executable (Linux servers only), can be repeated. \
+            NAME is one of cpu_time (seconds), address_space, open_files, processes, core_size, memory and cpu_percent, \
+            sizes accept K, M and G suffixes."
+    )]
+    pub limits: Vec<(String, u64)>,
 }
 
-#[derive(Parser, PartialEq, Eq, Debug)]
+#[derive(Parser, PartialEq, Eq, Debug, Clone)]
 #[command(author, version, about = "run server", long_about = None)]
 pub struct ServerArgs {
+    #[clap(
+        long = "config",
+        help = "Server config file path, default to `server.toml` under the config directory, ignored if missing."
+    )]
+    pub config: Option<PathBuf>,
     #[clap(
         short = 'b',
         long = "bind",
-        help = "Address the server bind to, recommend setting loopback address for safety.",
-        default_value_t = format!("[::1]:{DEFAULT_PORT}").parse().unwrap()
+        help = "Address the server bind to, can be repeated, recommend setting loopback address or `unix:/path/to/rex.sock` for safety. default: `bind` in config or [::1]:<DEFAULT_PORT>. Ignored when started by systemd socket activation."
     )]
-    pub bind_address: SocketAddr,
+    pub bind_address: Vec<BindAddress>,
     #[clap(
         short = 'c',
         long = "cert",
         help = "Server and CA cert directory path, default to `rex` under user's home config directory"
     )]
     pub cert_dir: Option<PathBuf>,
+    #[clap(
+        long = "protected-env",
+        value_name = "NAME",
+        value_delimiter = ',',
+        help = "Environment variables that clients are not allowed to override or remove, e.g. `PATH,LD_PRELOAD`."
+    )]
+    pub protected_env: Vec<String>,
+    #[clap(
+        long = "session-env-file",
+        help = "Re-read desktop session variables (DISPLAY, WAYLAND_DISPLAY, ...) from a `KEY=VALUE` file for every execution, default: snapshot them at server start."
+    )]
+    pub session_env_file: Option<PathBuf>,
+    #[clap(
+        long = "session-env-systemd",
+        conflicts_with = "session_env_file",
+        help = "Re-read desktop session variables from `systemctl --user show-environment` for every execution."
+    )]
+    pub session_env_systemd: bool,
+    #[clap(
+        long = "file-root",
+        help = "Only allow file transfer (cp, sync) inside this directory, relative remote paths are resolved against it."
+    )]
+    pub file_root: Option<PathBuf>,
+}
+
+impl ServerArgs {
+    /// 转换回命令行参数 (不含子命令), 解析后与 `self` 相同.
+    pub fn to_args(&self) -> Vec<OsString> {
+        let mut args: Vec<OsString> = Vec::new();
+        if let Some(config) = &self.config {
+            args.extend(["--config".into(), config.into()]);
+        }
+        for bind_address in &self.bind_address {
+            args.extend(["--bind".into(), bind_address.to_string().into()]);
+        }
+        if let Some(cert_dir) = &self.cert_dir {
+            args.extend(["--cert".into(), cert_dir.into()]);
+        }
+        if !self.protected_env.is_empty() {
+            args.extend([
+                "--protected-env".into(),
+                self.protected_env.join(",").into(),
+            ]);
+        }
+        if let Some(session_env_file) = &self.session_env_file {
+            args.extend(["--session-env-file".into(), session_env_file.into()]);
+        }
+        if self.session_env_systemd {
+            args.push("--session-env-systemd".into());
+        }
+        if let Some(file_root) = &self.file_root {
+            args.extend(["--file-root".into(), file_root.into()]);
+        }
+        args
+    }
+}
+
+/// 服务端开机自启的方式.
+#[derive(ValueEnum, Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum ServiceKind {
+    /// systemd 用户单元 `rex.service`.
+    #[default]
+    #[value(help = "A systemd user unit `rex.service`, enabled and started immediately.")]
+    Systemd,
+    /// XDG autostart 条目 `rex.desktop`, 登录桌面时启动.
+    #[value(help = "An XDG autostart entry `rex.desktop`, started by the desktop at login.")]
+    Autostart,
+}
+
+/// 服务端开机自启文件的位置.
+#[derive(clap::Args, PartialEq, Eq, Debug)]
+pub struct ServiceArgs {
+    #[clap(
+        long = "kind",
+        value_enum,
+        default_value_t,
+        help = "How to start the server at login."
+    )]
+    pub kind: ServiceKind,
+    #[clap(
+        long = "dir",
+        help = "Directory of the generated file, default: `systemd/user` or `autostart` under the user's config directory. systemctl is not called when set."
+    )]
+    pub dir: Option<PathBuf>,
+}
+
+#[derive(Parser, PartialEq, Eq, Debug)]
+#[command(author, version, about = "start the server at login with the given server arguments", long_about = None)]
+pub struct InstallServiceArgs {
+    #[command(flatten)]
+    pub service: ServiceArgs,
+    #[clap(
+        long = "socket",
+        requires = "bind_address",
+        help = "Also generate a systemd socket unit `rex.socket` listening on the `--bind` addresses, the server is started on the first connection. Only for `--kind systemd`."
+    )]
+    pub socket: bool,
+    #[command(flatten)]
+    pub server: ServerArgs,
+}
+
+#[derive(Parser, PartialEq, Eq, Debug)]
+#[command(author, version, about = "stop starting the server at login", long_about = None)]
+pub struct UninstallServiceArgs {
+    #[command(flatten)]
+    pub service: ServiceArgs,
+}
+
+#[derive(Parser, PartialEq, Eq, Debug)]
+#[command(author, version, about = "show whether the server starts at login", long_about = None)]
+pub struct ServiceStatusArgs {
+    #[command(flatten)]
+    pub service: ServiceArgs,
+}
+
+#[derive(Parser, PartialEq, Eq, Debug)]
+#[command(author, version, about = "list sessions on the server", long_about = None)]
+pub struct PsArgs {
+    #[clap(index = 1, help = "Show details of a single session.")]
+    pub session_id: Option<String>,
+    #[command(flatten)]
+    pub connect: ConnectArgs,
+}
+
+#[derive(Parser, PartialEq, Eq, Debug)]
+#[command(author, version, about = "terminate a session on the server", long_about = None)]
+pub struct KillArgs {
+    #[clap(index = 1)]
+    pub session_id: String,
+    #[clap(
+        short = 's',
+        long = "signal",
+        help = "Send this signal to the session's process group instead of killing it, e.g. `TERM`, `HUP` or `15`."
+    )]
+    pub signal: Option<String>,
+    #[command(flatten)]
+    pub connect: ConnectArgs,
+}
+
+/// `rex cp` 的源路径或目标路径, `remote:` 前缀表示服务端上的路径.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum CopyPath {
+    Local(PathBuf),
+    Remote(String),
+}
+
+impl FromStr for CopyPath {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.strip_prefix("remote:") {
+            Some(path) => CopyPath::Remote(path.into()),
+            None => CopyPath::Local(s.into()),
+        })
+    }
+}
+
+#[derive(Parser, PartialEq, Eq, Debug)]
+#[command(author, version, about = "copy a file between local and the server", long_about = None)]
+pub struct CpArgs {
+    #[clap(
+        index = 1,
+        help = "Source file, `remote:PATH` for a file on the server."
+    )]
+    pub source: CopyPath,
+    #[clap(
+        index = 2,
+        help = "Destination, `remote:PATH` for the server, a trailing `/` or an existing local directory keeps the file name."
+    )]
+    pub destination: CopyPath,
+    #[command(flatten)]
+    pub connect: ConnectArgs,
+}
+
+#[derive(Parser, PartialEq, Eq, Debug)]
+#[command(author, version, about = "push a local directory to the server, only changed files are transferred", long_about = None)]
+pub struct SyncArgs {
+    #[clap(index = 1, help = "Local directory to push.")]
+    pub source: PathBuf,
+    #[clap(
+        index = 2,
+        help = "Destination directory on the server, `remote:PATH`."
+    )]
+    pub destination: CopyPath,
+    #[clap(
+        long = "include",
+        value_name = "GLOB",
+        help = "Only sync files whose relative path matches, can be repeated, e.g. `src/**`."
+    )]
+    pub include: Vec<String>,
+    #[clap(
+        long = "exclude",
+        value_name = "GLOB",
+        help = "Skip files whose relative path matches, can be repeated, e.g. `target/**`."
+    )]
+    pub exclude: Vec<String>,
+    #[command(flatten)]
+    pub connect: ConnectArgs,
 }
 
 #[derive(Parser, PartialEq, Eq, Debug)]
@@ -76,16 +369,84 @@ pub struct GenCertArgs {
         help = "Certificates output directory, default to `rex` under user's home config directory."
     )]
     pub output_path: Option<PathBuf>,
+    #[clap(
+        long = "client-name",
+        value_name = "NAME",
+        help = "Subject CN of the client certificate, used by the server to assign a role. default: `Rex Test Client`."
+    )]
+    pub client_name: Option<String>,
+    #[clap(
+        long = "ca",
+        value_name = "DIR",
+        help = "Only issue a client certificate with the existing CA (ca_cert.crt and ca_secret.pem) in this directory."
+    )]
+    pub ca_dir: Option<PathBuf>,
+    #[clap(
+        long = "keep-ca-key",
+        value_name = "DIR",
+        conflicts_with = "ca_dir",
+        help = "Also save the CA certificate and private key to this directory for `--ca`, keep it separate from the cert directory. default: the CA private key is discarded."
+    )]
+    pub keep_ca_key: Option<PathBuf>,
+}
+
+fn parse_env_pair(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some(("", _)) => Err(format!("invalid KEY=VALUE: empty key in `{s}`")),
+        Some((key, value)) => Ok((key.into(), value.into())),
+        None => Err(format!("invalid KEY=VALUE: no `=` found in `{s}`")),
+    }
+}
+
+fn parse_limit(s: &str) -> Result<(String, u64), String> {
+    let Some((name, value)) = s.split_once('=') else {
+        return Err(format!("invalid NAME=VALUE: no `=` found in `{s}`"));
+    };
+    let (number, unit) = value.split_at(
+        value
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(value.len()),
+    );
+    let unit: u64 = match unit {
+        "" => 1,
+        "K" | "k" => 1 << 10,
+        "M" | "m" => 1 << 20,
+        "G" | "g" => 1 << 30,
+        _ => {
+            return Err(format!(
+                "invalid unit `{unit}` in `{s}`, expected K, M or G"
+            ));
+        }
+    };
+    let value = number
+        .parse::<u64>()
+        .map_err(|e| format!("invalid value in `{s}`: {e}"))?
+        .checked_mul(unit)
+        .ok_or_else(|| format!("value in `{s}` is too large"))?;
+    ResourceLimits::default().set(name, value)?;
+    Ok((name.into(), value))
 }
 
 #[cfg(test)]
 mod test {
-    use crate::DEFAULT_PORT;
-    use crate::args::{ClientArgs, ServerArgs, Subcommands};
+    use crate::args::{
+        ClientArgs, ConnectArgs, CopyPath, CpArgs, InstallServiceArgs, KillArgs, PsArgs,
+        ServerArgs, ServiceArgs, ServiceKind, Subcommands, SyncArgs,
+    };
 
-    use super::Args;
+    use super::{Args, parse_env_pair};
     use clap::Parser as _;
 
+    #[test]
+    fn env_pair() {
+        assert_eq!(
+            parse_env_pair("KEY==value"),
+            Ok(("KEY".into(), "=value".into()))
+        );
+        assert!(parse_env_pair("=value").unwrap_err().contains("empty key"));
+        assert!(parse_env_pair("KEY").unwrap_err().contains("no `=` found"));
+    }
+
     #[test]
     fn parse_client() {
         let raw_args = [
@@ -104,12 +465,25 @@ mod test {
         let args = Args::parse_from(raw_args);
         let target = Args {
             command: Subcommands::Client(ClientArgs {
-                executable: "bash".into(),
+                executable: Some("bash".into()),
                 args: ["-c".into(), "sleep 10".into()].into(),
                 current_dir: Some("/usr/bin/".into()),
                 leak: false,
-                server_address: "https://nihao.com:5000".into(),
-                cert_dir: None,
+                no_leak: false,
+                pty: false,
+                attach: None,
+                env: vec![],
+                env_file: None,
+                clear_env: false,
+                kill_grace_period: None,
+                timeout: None,
+                idle_timeout: None,
+                limits: vec![],
+                connect: ConnectArgs {
+                    profile: None,
+                    server_address: Some("https://nihao.com:5000".into()),
+                    cert_dir: None,
+                },
             }),
         };
 
@@ -122,12 +496,25 @@ mod test {
         let args = Args::parse_from(raw_args);
         let target = Args {
             command: Subcommands::Client(ClientArgs {
-                executable: "ls".into(),
+                executable: Some("ls".into()),
                 args: vec![],
                 current_dir: None,
                 leak: false,
-                server_address: format!("https://[::1]:{DEFAULT_PORT}"),
-                cert_dir: None,
+                no_leak: false,
+                pty: false,
+                attach: None,
+                env: vec![],
+                env_file: None,
+                clear_env: false,
+                kill_grace_period: None,
+                timeout: None,
+                idle_timeout: None,
+                limits: vec![],
+                connect: ConnectArgs {
+                    profile: None,
+                    server_address: None,
+                    cert_dir: None,
+                },
             }),
         };
         assert_eq!(args, target);
@@ -140,6 +527,8 @@ mod test {
             "client",
             "bash",
             "-l",
+            "--kill-grace",
+            "5",
             "--",
             "-c",
             "echo hello",
@@ -148,15 +537,224 @@ mod test {
         let args = Args::parse_from(raw_args);
         let target = Args {
             command: Subcommands::Client(ClientArgs {
-                executable: "bash".into(),
+                executable: Some("bash".into()),
                 args: ["-c".into(), "echo hello".into()].into(),
                 current_dir: None,
                 leak: true,
-                server_address: format!("https://[::1]:{}", DEFAULT_PORT),
-                cert_dir: None,
+                no_leak: false,
+                pty: false,
+                attach: None,
+                env: vec![],
+                env_file: None,
+                clear_env: false,
+                kill_grace_period: Some(5),
+                timeout: None,
+                idle_timeout: None,
+                limits: vec![],
+                connect: ConnectArgs {
+                    profile: None,
+                    server_address: None,
+                    cert_dir: None,
+                },
+            }),
+        };
+        assert_eq!(args, target);
+    }
+
+    #[test]
+    fn parse_client_with_limits() {
+        let raw_args = [
+            env!("CARGO_PKG_NAME"),
+            "c",
+            "--timeout",
+            "60",
+            "--idle-timeout",
+            "10",
+            "--limit",
+            "memory=512M",
+            "--limit",
+            "open_files=64",
+            "make",
+        ]
+        .iter();
+        let args = Args::parse_from(raw_args);
+        let target = Args {
+            command: Subcommands::Client(ClientArgs {
+                executable: Some("make".into()),
+                args: vec![],
+                current_dir: None,
+                leak: false,
+                no_leak: false,
+                pty: false,
+                attach: None,
+                env: vec![],
+                env_file: None,
+                clear_env: false,
+                kill_grace_period: None,
+                timeout: Some(60),
+                idle_timeout: Some(10),
+                limits: vec![("memory".into(), 512 << 20), ("open_files".into(), 64)],
+                connect: ConnectArgs {
+                    profile: None,
+                    server_address: None,
+                    cert_dir: None,
+                },
             }),
         };
         assert_eq!(args, target);
+        for invalid in [
+            ["--timeout", "0"],
+            ["--limit", "memory"],
+            ["--limit", "stack=1M"],
+            ["--limit", "memory=1T"],
+        ] {
+            let raw_args = [env!("CARGO_PKG_NAME"), "c", invalid[0], invalid[1], "make"];
+            assert!(Args::try_parse_from(raw_args).is_err(), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn parse_client_with_profile() {
+        let raw_args = [
+            env!("CARGO_PKG_NAME"),
+            "c",
+            "--profile",
+            "work-pc",
+            "--no-leak",
+            "ls",
+        ]
+        .iter();
+        let args = Args::parse_from(raw_args);
+        let target = Args {
+            command: Subcommands::Client(ClientArgs {
+                executable: Some("ls".into()),
+                args: vec![],
+                current_dir: None,
+                leak: false,
+                no_leak: true,
+                pty: false,
+                attach: None,
+                env: vec![],
+                env_file: None,
+                clear_env: false,
+                kill_grace_period: None,
+                timeout: None,
+                idle_timeout: None,
+                limits: vec![],
+                connect: ConnectArgs {
+                    profile: Some("work-pc".into()),
+                    server_address: None,
+                    cert_dir: None,
+                },
+            }),
+        };
+        assert_eq!(args, target);
+        let raw_args = [env!("CARGO_PKG_NAME"), "c", "-l", "--no-leak", "ls"].iter();
+        assert!(Args::try_parse_from(raw_args).is_err());
+    }
+
+    #[test]
+    fn parse_client_with_tty() {
+        let raw_args = [env!("CARGO_PKG_NAME"), "c", "-t", "vim", "notes.txt"].iter();
+        let args = Args::parse_from(raw_args);
+        let target = Args {
+            command: Subcommands::Client(ClientArgs {
+                executable: Some("vim".into()),
+                args: ["notes.txt".into()].into(),
+                current_dir: None,
+                leak: false,
+                no_leak: false,
+                pty: true,
+                attach: None,
+                env: vec![],
+                env_file: None,
+                clear_env: false,
+                kill_grace_period: None,
+                timeout: None,
+                idle_timeout: None,
+                limits: vec![],
+                connect: ConnectArgs {
+                    profile: None,
+                    server_address: None,
+                    cert_dir: None,
+                },
+            }),
+        };
+        assert_eq!(args, target);
+    }
+
+    #[test]
+    fn parse_client_with_env() {
+        let raw_args = [
+            env!("CARGO_PKG_NAME"),
+            "c",
+            "-e",
+            "LANG=C",
+            "--env",
+            "EMPTY=",
+            "--env-file",
+            "/tmp/rex.env",
+            "--clear-env",
+            "env",
+        ]
+        .iter();
+        let args = Args::parse_from(raw_args);
+        let target = Args {
+            command: Subcommands::Client(ClientArgs {
+                executable: Some("env".into()),
+                args: vec![],
+                current_dir: None,
+                leak: false,
+                no_leak: false,
+                pty: false,
+                attach: None,
+                env: vec![("LANG".into(), "C".into()), ("EMPTY".into(), String::new())],
+                env_file: Some("/tmp/rex.env".into()),
+                clear_env: true,
+                kill_grace_period: None,
+                timeout: None,
+                idle_timeout: None,
+                limits: vec![],
+                connect: ConnectArgs {
+                    profile: None,
+                    server_address: None,
+                    cert_dir: None,
+                },
+            }),
+        };
+        assert_eq!(args, target);
+    }
+
+    #[test]
+    fn parse_client_with_attach() {
+        let raw_args = [env!("CARGO_PKG_NAME"), "c", "--attach", "0badcafe", "-t"].iter();
+        let args = Args::parse_from(raw_args);
+        let target = Args {
+            command: Subcommands::Client(ClientArgs {
+                executable: None,
+                args: vec![],
+                current_dir: None,
+                leak: false,
+                no_leak: false,
+                pty: true,
+                attach: Some("0badcafe".into()),
+                env: vec![],
+                env_file: None,
+                clear_env: false,
+                kill_grace_period: None,
+                timeout: None,
+                idle_timeout: None,
+                limits: vec![],
+                connect: ConnectArgs {
+                    profile: None,
+                    server_address: None,
+                    cert_dir: None,
+                },
+            }),
+        };
+        assert_eq!(args, target);
+        let raw_args = [env!("CARGO_PKG_NAME"), "c"].iter();
+        assert!(Args::try_parse_from(raw_args).is_err());
     }
 
     #[test]
@@ -165,12 +763,25 @@ mod test {
         let args = Args::parse_from(raw_args);
         let target = Args {
             command: Subcommands::Client(ClientArgs {
-                executable: "python3".into(),
+                executable: Some("python3".into()),
                 args: ["script.py".into()].into(),
                 current_dir: None,
                 leak: false,
-                server_address: format!("https://[::1]:{}", DEFAULT_PORT),
-                cert_dir: None,
+                no_leak: false,
+                pty: false,
+                attach: None,
+                env: vec![],
+                env_file: None,
+                clear_env: false,
+                kill_grace_period: None,
+                timeout: None,
+                idle_timeout: None,
+                limits: vec![],
+                connect: ConnectArgs {
+                    profile: None,
+                    server_address: None,
+                    cert_dir: None,
+                },
             }),
         };
         assert_eq!(args, target);
@@ -182,11 +793,126 @@ mod test {
         let args = Args::parse_from(raw_args);
         let target = Args {
             command: Subcommands::Server(ServerArgs {
-                bind_address: "[::1]:8080".parse().unwrap(),
+                config: None,
+                bind_address: vec!["[::1]:8080".parse().unwrap()],
+                cert_dir: None,
+                protected_env: vec![],
+                session_env_file: None,
+                session_env_systemd: false,
+                file_root: None,
+            }),
+        };
+        assert_eq!(args, target);
+    }
+
+    #[test]
+    fn parse_server_with_protected_env() {
+        let raw_args = [
+            env!("CARGO_PKG_NAME"),
+            "s",
+            "--protected-env",
+            "PATH,LD_PRELOAD",
+        ]
+        .iter();
+        let args = Args::parse_from(raw_args);
+        let target = Args {
+            command: Subcommands::Server(ServerArgs {
+                config: None,
+                bind_address: vec![],
+                cert_dir: None,
+                protected_env: vec!["PATH".into(), "LD_PRELOAD".into()],
+                session_env_file: None,
+                session_env_systemd: false,
+                file_root: None,
+            }),
+        };
+        assert_eq!(args, target);
+    }
+
+    #[test]
+    fn parse_server_with_session_env() {
+        let raw_args = [env!("CARGO_PKG_NAME"), "s", "--session-env-systemd"].iter();
+        let args = Args::parse_from(raw_args);
+        let target = Args {
+            command: Subcommands::Server(ServerArgs {
+                config: None,
+                bind_address: vec![],
                 cert_dir: None,
+                protected_env: vec![],
+                session_env_file: None,
+                session_env_systemd: true,
+                file_root: None,
+            }),
+        };
+        assert_eq!(args, target);
+        let raw_args = [
+            env!("CARGO_PKG_NAME"),
+            "s",
+            "--session-env-systemd",
+            "--session-env-file",
+            "/tmp/session.env",
+        ]
+        .iter();
+        assert!(Args::try_parse_from(raw_args).is_err());
+    }
+
+    #[test]
+    fn parse_install_service() {
+        let raw_args = [
+            env!("CARGO_PKG_NAME"),
+            "install-service",
+            "--kind",
+            "autostart",
+            "-b",
+            "[::1]:8080",
+            "-b",
+            "unix:/tmp/rex.sock",
+            "--session-env-systemd",
+        ]
+        .iter();
+        let args = Args::parse_from(raw_args);
+        let server = ServerArgs {
+            config: None,
+            bind_address: vec![
+                "[::1]:8080".parse().unwrap(),
+                "unix:/tmp/rex.sock".parse().unwrap(),
+            ],
+            cert_dir: None,
+            protected_env: vec![],
+            session_env_file: None,
+            session_env_systemd: true,
+            file_root: None,
+        };
+        let target = Args {
+            command: Subcommands::InstallService(InstallServiceArgs {
+                service: ServiceArgs {
+                    kind: ServiceKind::Autostart,
+                    dir: None,
+                },
+                socket: false,
+                server: server.clone(),
             }),
         };
         assert_eq!(args, target);
+
+        // 转换回命令行参数后解析得到相同的参数.
+        let server = ServerArgs {
+            config: Some("/etc/rex/server.toml".into()),
+            cert_dir: Some("/etc/rex".into()),
+            protected_env: vec!["PATH".into(), "LD_PRELOAD".into()],
+            session_env_file: Some("/run/session.env".into()),
+            session_env_systemd: false,
+            file_root: Some("/home/me/share".into()),
+            ..server
+        };
+        let raw_args = [env!("CARGO_PKG_NAME").into()]
+            .into_iter()
+            .chain(server.to_args());
+        assert_eq!(ServerArgs::parse_from(raw_args), server);
+
+        // `--socket` 需要监听地址.
+        let raw_args = [env!("CARGO_PKG_NAME"), "install-service", "--socket"].iter();
+        assert!(Args::try_parse_from(raw_args).is_err());
     }
 
     #[test]
@@ -195,8 +921,140 @@ mod test {
         let args = Args::parse_from(raw_args);
         let target = Args {
             command: Subcommands::Server(ServerArgs {
-                bind_address: format!("[::1]:{}", DEFAULT_PORT).parse().unwrap(),
+                config: None,
+                bind_address: vec![],
                 cert_dir: None,
+                protected_env: vec![],
+                session_env_file: None,
+                session_env_systemd: false,
+                file_root: None,
+            }),
+        };
+        assert_eq!(args, target);
+    }
+
+    #[test]
+    fn parse_ps() {
+        let raw_args = [env!("CARGO_PKG_NAME"), "ps"].iter();
+        let args = Args::parse_from(raw_args);
+        let target = Args {
+            command: Subcommands::Ps(PsArgs {
+                session_id: None,
+                connect: ConnectArgs {
+                    profile: None,
+                    server_address: None,
+                    cert_dir: None,
+                },
+            }),
+        };
+        assert_eq!(args, target);
+        let raw_args = [env!("CARGO_PKG_NAME"), "ps", "0badcafe", "-c", "/tmp/certs"].iter();
+        let args = Args::parse_from(raw_args);
+        let target = Args {
+            command: Subcommands::Ps(PsArgs {
+                session_id: Some("0badcafe".into()),
+                connect: ConnectArgs {
+                    profile: None,
+                    server_address: None,
+                    cert_dir: Some("/tmp/certs".into()),
+                },
+            }),
+        };
+        assert_eq!(args, target);
+    }
+
+    #[test]
+    fn parse_kill() {
+        let raw_args = [
+            env!("CARGO_PKG_NAME"),
+            "kill",
+            "0badcafe",
+            "-s",
+            "TERM",
+            "-a",
+            "https://nihao.com:5000",
+        ]
+        .iter();
+        let args = Args::parse_from(raw_args);
+        let target = Args {
+            command: Subcommands::Kill(KillArgs {
+                session_id: "0badcafe".into(),
+                signal: Some("TERM".into()),
+                connect: ConnectArgs {
+                    profile: None,
+                    server_address: Some("https://nihao.com:5000".into()),
+                    cert_dir: None,
+                },
+            }),
+        };
+        assert_eq!(args, target);
+        let raw_args = [env!("CARGO_PKG_NAME"), "kill"].iter();
+        assert!(Args::try_parse_from(raw_args).is_err());
+    }
+
+    #[test]
+    fn parse_cp() {
+        let raw_args = [
+            env!("CARGO_PKG_NAME"),
+            "cp",
+            "input.txt",
+            "remote:/tmp/input.txt",
+        ]
+        .iter();
+        let args = Args::parse_from(raw_args);
+        let target = Args {
+            command: Subcommands::Cp(CpArgs {
+                source: CopyPath::Local("input.txt".into()),
+                destination: CopyPath::Remote("/tmp/input.txt".into()),
+                connect: ConnectArgs {
+                    profile: None,
+                    server_address: None,
+                    cert_dir: None,
+                },
+            }),
+        };
+        assert_eq!(args, target);
+        let raw_args = [env!("CARGO_PKG_NAME"), "cp", "remote:C:\\out.png", "."].iter();
+        let args = Args::parse_from(raw_args);
+        let target = Args {
+            command: Subcommands::Cp(CpArgs {
+                source: CopyPath::Remote("C:\\out.png".into()),
+                destination: CopyPath::Local(".".into()),
+                connect: ConnectArgs {
+                    profile: None,
+                    server_address: None,
+                    cert_dir: None,
+                },
+            }),
+        };
+        assert_eq!(args, target);
+    }
+
+    #[test]
+    fn parse_sync() {
+        let raw_args = [
+            env!("CARGO_PKG_NAME"),
+            "sync",
+            ".",
+            "remote:project",
+            "--exclude",
+            "target/**",
+            "--exclude",
+            ".git/**",
+        ]
+        .iter();
+        let args = Args::parse_from(raw_args);
+        let target = Args {
+            command: Subcommands::Sync(SyncArgs {
+                source: ".".into(),
+                destination: CopyPath::Remote("project".into()),
+                include: vec![],
+                exclude: vec!["target/**".into(), ".git/**".into()],
+                connect: ConnectArgs {
+                    profile: None,
+                    server_address: None,
+                    cert_dir: None,
+                },
             }),
         };
         assert_eq!(args, target);
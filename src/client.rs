@@ -1,22 +1,38 @@
+use std::collections::HashMap;
 use std::env;
-use std::path::PathBuf;
+use std::io::IsTerminal as _;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use crate::args::ClientArgs;
+use crate::args::{ClientArgs, ConnectArgs, CopyPath, CpArgs, KillArgs, PsArgs, SyncArgs};
+use crate::client::terminal::RawModeGuard;
 use crate::exec::execute_client::ExecuteClient;
 use crate::exec::execute_request_chunk::RequestChunk;
 use crate::exec::program_output::Payload;
 use crate::exec::{
-    Command, ExecuteRequestChunk, ProgramOutput, StderrChunk, StdinChunk, StdoutChunk,
+    Attach, Command, DownloadRequest, ExecuteRequestChunk, ExitInfo, GetSessionRequest,
+    ListSessionsRequest, ProgramOutput, ResourceLimits, SessionInfo, SessionState, StderrChunk,
+    StdinChunk, StdinEof, StdoutChunk, SyncRequest, TerminalSize, TerminateSessionRequest,
+    TimeoutKind,
 };
-use crate::{CA_CERT, CLIENT_CERT, CLIENT_SECRET, Error, config_dir};
+use crate::{CA_CERT, CLIENT_CERT, CLIENT_SECRET, Error, parse_env_file, transfer};
 use tokio::fs;
 use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _};
 use tokio::sync::mpsc::Sender;
 use tokio::task::JoinHandle;
+use tokio_stream::StreamExt as _;
 use tonic::transport::{Certificate, Channel, ClientTlsConfig, Identity};
 use tonic::{Status, Streaming};
 use tracing::{debug, info, warn};
 
+mod profile;
+mod signal;
+mod sync;
+mod terminal;
+
+pub use crate::client::profile::{ClientConfig, PROFILE_ENV, Profile};
+pub use crate::client::sync::SyncFilter;
+
 #[derive(bon::Builder)]
 pub struct ExecuteOptions {
     executable: String,
@@ -24,20 +40,88 @@ pub struct ExecuteOptions {
     current_dir: Option<String>,
     args: Vec<String>,
     leak: bool,
+    /// 是否在服务端的伪终端中运行程序.
+    #[builder(default)]
+    pty: bool,
+    /// 伪终端的初始窗口大小.
+    terminal_size: Option<TerminalSize>,
+    /// 为子进程设置的环境变量.
+    #[builder(default)]
+    env: HashMap<String, String>,
+    /// 从子进程环境中移除的变量.
+    #[builder(default)]
+    env_remove: Vec<String>,
+    /// 是否清空子进程从服务端继承的环境变量.
+    #[builder(default)]
+    env_clear: bool,
+    /// 终止远端进程时 `SIGTERM` 和 `SIGKILL` 之间等待的秒数, 不设置时直接 `SIGKILL`.
+    kill_grace_period: Option<u32>,
+    /// 远端进程运行超过这么多秒后被终止.
+    timeout: Option<u32>,
+    /// 远端进程连续这么多秒没有输入输出时被终止.
+    idle_timeout: Option<u32>,
+    /// 远端进程的资源限制.
+    limits: Option<ResourceLimits>,
+    /// 是否将本地收到的信号转发给远端进程, 由 [`ExecutorClient::execute_stream`] 使用.
+    #[builder(default)]
+    forward_signals: bool,
+}
+
+impl From<ExecuteOptions> for Command {
+    fn from(options: ExecuteOptions) -> Self {
+        Command {
+            executable: options.executable,
+            args: options.args,
+            current_dir: options.current_dir,
+            leak: options.leak,
+            pty: options.pty,
+            env: options.env,
+            env_remove: options.env_remove,
+            env_clear: options.env_clear,
+            kill_grace_period: options.kill_grace_period,
+            timeout: options.timeout,
+            idle_timeout: options.idle_timeout,
+            limits: options.limits.map(Box::new),
+            terminal_size: options.terminal_size,
+        }
+    }
 }
 
-#[allow(unused)]
 #[derive(Debug, Clone)]
 pub struct ExecuteOutput {
-    stdout: Vec<u8>,
-    stderr: Vec<u8>,
-    code: i32,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    /// shell 约定的退出码, 见 [`ExitInfo::shell_code`], 没有收到退出信息时为 -1.
+    pub code: i32,
+    pub exit_info: Option<ExitInfo>,
+    /// 服务端为这次执行分配的会话 ID.
+    pub session_id: Option<String>,
+}
+
+/// 一次目录同步的结果.
+#[derive(Debug, Clone)]
+pub struct SyncOutput {
+    /// 本地参与同步的文件数.
+    pub total: usize,
+    /// 上传了的文件的相对路径.
+    pub transferred: Vec<String>,
+    pub bytes: u64,
 }
 
 pub struct ExecutorClient {
     client: ExecuteClient<Channel>,
 }
 
+/// 收到 [`SessionStarted`](crate::exec::SessionStarted) 时的处理.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OnStarted {
+    Ignore,
+    /// 在 stderr 中打印会话 ID.
+    Announce,
+    /// 会话运行在伪终端中且本地标准输入是终端时, 本地终端进入 raw 模式.
+    RawMode,
+}
+
 impl ExecutorClient {
     // pub fn new(client: ExecuteClient<Channel>) -> ExecutorClient {
     //     ExecutorClient { client }
@@ -55,11 +139,7 @@ impl ExecutorClient {
             .ca_certificate(ca)
             .domain_name(domain_name)
             .identity(Identity::from_pem(client_cert, client_secret));
-        let chan = Channel::from_shared(address)
-            .map_err(|_| Error::InvalidUri)?
-            .tls_config(tls_config)?
-            .connect()
-            .await?;
+        let chan = connect_channel(address, Some(tls_config)).await?;
         Ok(Self {
             client: ExecuteClient::new(chan),
         })
@@ -67,8 +147,103 @@ impl ExecutorClient {
 
     /// 无 tls 连接.
     pub async fn connect(address: String) -> Result<Self, Error> {
-        let client = ExecuteClient::connect(address).await?;
-        Ok(Self { client })
+        let chan = connect_channel(address, None).await?;
+        Ok(Self {
+            client: ExecuteClient::new(chan),
+        })
+    }
+
+    /// 列出服务端上的所有会话, 按启动时间排序.
+    pub async fn list_sessions(&mut self) -> Result<Vec<SessionInfo>, Error> {
+        let resp = self.client.list_sessions(ListSessionsRequest {}).await?;
+        Ok(resp.into_inner().sessions)
+    }
+
+    pub async fn get_session(&mut self, session_id: String) -> Result<SessionInfo, Error> {
+        let resp = self
+            .client
+            .get_session(GetSessionRequest { session_id })
+            .await?;
+        Ok(resp.into_inner())
+    }
+
+    /// 终止会话: `signal` 为 [`None`] 时杀死子进程, 否则向子进程所在的进程组发送该信号.
+    ///
+    /// 已经结束的会话会被直接移除.
+    pub async fn terminate_session(
+        &mut self,
+        session_id: String,
+        signal: Option<String>,
+    ) -> Result<(), Error> {
+        self.client
+            .terminate_session(TerminateSessionRequest { session_id, signal })
+            .await?;
+        Ok(())
+    }
+
+    /// 上传本地文件到服务端的 `remote_path`, 保留权限和修改时间, 返回上传的字节数.
+    pub async fn upload(&mut self, local_path: &Path, remote_path: String) -> Result<u64, Error> {
+        let (file, mut header) = transfer::open_file(local_path).await?;
+        header.path = remote_path;
+        // 读取本地文件出错时传输会提前结束, 服务端会因为缺少校验和而拒绝这次上传.
+        let stream = transfer::stream_file(file, header).map_while(Result::ok);
+        let resp = self.client.upload(stream).await?;
+        Ok(resp.into_inner().size)
+    }
+
+    /// 下载服务端上的 `remote_path` 到本地的 `local_path`, 保留权限和修改时间, 返回下载的字节数.
+    pub async fn download(
+        &mut self,
+        remote_path: String,
+        local_path: PathBuf,
+    ) -> Result<u64, Error> {
+        let mut stream = self
+            .client
+            .download(DownloadRequest { path: remote_path })
+            .await?
+            .into_inner();
+        let (_, size) = transfer::receive_file(&mut stream, async |_| Ok(local_path)).await?;
+        Ok(size)
+    }
+
+    /// 将本地目录 `local_dir` 同步到服务端的 `remote_dir`, 只上传服务端上不存在或内容不同的文件.
+    ///
+    /// 服务端上多出来的文件不会被删除.
+    pub async fn sync(
+        &mut self,
+        local_dir: &Path,
+        remote_dir: String,
+        filter: &SyncFilter,
+    ) -> Result<SyncOutput, Error> {
+        let (entries, local_paths) = sync::manifest(local_dir, filter).await?;
+        let total = entries.len();
+        let local_paths: HashMap<_, _> = entries
+            .iter()
+            .map(|entry| entry.path.clone())
+            .zip(local_paths)
+            .collect();
+        let changed = self
+            .client
+            .sync(SyncRequest {
+                path: remote_dir.clone(),
+                entries,
+            })
+            .await?
+            .into_inner()
+            .changed;
+        let mut bytes = 0;
+        for relative in &changed {
+            let Some(local_path) = local_paths.get(relative) else {
+                continue;
+            };
+            let remote_path = format!("{}/{relative}", remote_dir.trim_end_matches('/'));
+            bytes += self.upload(local_path, remote_path).await?;
+        }
+        Ok(SyncOutput {
+            total,
+            transferred: changed,
+            bytes,
+        })
     }
 
     pub async fn execute(
@@ -76,16 +251,12 @@ impl ExecutorClient {
         execute_options: ExecuteOptions,
     ) -> Result<ExecuteOutput, Error> {
         let input_stream = tokio_stream::once(ExecuteRequestChunk {
-            request_chunk: Some(RequestChunk::Command(Command {
-                executable: execute_options.executable,
-                args: execute_options.args,
-                current_dir: execute_options.current_dir,
-                leak: execute_options.leak,
-            })),
+            request_chunk: Some(RequestChunk::Command(execute_options.into())),
         });
         let mut stdout = Vec::new();
         let mut stderr = Vec::new();
-        let mut code = -1i32;
+        let mut exit_info = None;
+        let mut session_id = None;
         let stream = self.client.execute(input_stream).await?;
         let mut stream = stream.into_inner();
         while let Some(msg) = stream.message().await? {
@@ -99,13 +270,17 @@ impl ExecutorClient {
                 Payload::StderrChunk(StderrChunk { mut data }) => {
                     stderr.append(&mut data);
                 }
-                Payload::ExitStatus(c) => code = c,
+                Payload::ExitInfo(info) => exit_info = Some(info),
+                Payload::SessionStarted(started) => session_id = Some(started.session_id),
+                Payload::Queued(_) | Payload::ServerShutdown(_) => {}
             }
         }
         Ok(ExecuteOutput {
             stdout,
             stderr,
-            code,
+            code: exit_info.as_ref().map_or(-1, ExitInfo::shell_code),
+            exit_info,
+            session_id,
         })
     }
 
@@ -121,11 +296,8 @@ impl ExecutorClient {
                 let len = match len {
                     Ok(0) => {
                         debug!("stdin EOF");
-                        // 发送空数据告知输入结束.
                         tx.send(ExecuteRequestChunk {
-                            request_chunk: Some(RequestChunk::StdinChunk(StdinChunk {
-                                data: vec![],
-                            })),
+                            request_chunk: Some(RequestChunk::StdinEof(StdinEof {})),
                         })
                         .await
                         .ok();
@@ -155,23 +327,62 @@ impl ExecutorClient {
         handle
     }
 
+    /// 将输出流写入 `stdout` 和 `stderr`, 收到会话 ID 时按 `on_started` 处理.
     async fn transmit_std_stream(
         &self,
         mut stream: Streaming<ProgramOutput>,
         mut stdout: impl AsyncWrite + Send + Unpin + 'static,
         mut stderr: impl AsyncWrite + Send + Unpin + 'static,
-    ) -> Result<Option<i32>, Status> {
+        on_started: OnStarted,
+    ) -> Result<Option<ExitInfo>, Status> {
+        let mut _raw_mode = None;
         let mut once_warn_stdout = Some(());
         let mut once_warn_stderr = Some(());
-        while let Ok(msg) = stream.message().await {
-            let Some(msg) = msg else {
-                continue;
-            };
+        // 服务端拒绝执行时会在输出流中返回错误状态.
+        while let Some(msg) = stream.message().await? {
             let Some(payload) = msg.payload else {
                 continue;
             };
             match payload {
-                Payload::ExitStatus(code) => return Ok(Some(code)),
+                Payload::ExitInfo(info) => return Ok(Some(info)),
+                Payload::SessionStarted(started) => {
+                    debug!("session started: {started:?}");
+                    if on_started == OnStarted::RawMode
+                        && started.pty
+                        && std::io::stdin().is_terminal()
+                    {
+                        match RawModeGuard::enable() {
+                            Ok(guard) => _raw_mode = Some(guard),
+                            Err(e) => warn!("failed to enable raw mode: {e}"),
+                        }
+                    }
+                    if on_started == OnStarted::Announce {
+                        // 本地终端可能处于 raw 模式, 需要显式回车.
+                        let notice = format!("rex: session {}\r\n", started.session_id);
+                        stderr.write_all(notice.as_bytes()).await.ok();
+                        stderr.flush().await.ok();
+                    }
+                }
+                Payload::Queued(queued) => {
+                    let notice = format!(
+                        "rex: waiting for slot {}/{}\r\n",
+                        queued.position, queued.length
+                    );
+                    stderr.write_all(notice.as_bytes()).await.ok();
+                    stderr.flush().await.ok();
+                }
+                Payload::ServerShutdown(shutdown) => {
+                    let notice = if shutdown.terminate {
+                        format!(
+                            "rex: server is shutting down, the program will be terminated in {}s\r\n",
+                            shutdown.grace_period
+                        )
+                    } else {
+                        "rex: server is shutting down, the program will keep running without a session\r\n".to_string()
+                    };
+                    stderr.write_all(notice.as_bytes()).await.ok();
+                    stderr.flush().await.ok();
+                }
                 Payload::StderrChunk(chunk) => {
                     stderr
                         .write_all(&chunk.data)
@@ -201,35 +412,105 @@ impl ExecutorClient {
         Ok(None)
     }
 
-    /// 流式执行程序, 返回程序的退出码, 当启动的程序 leak 了则可能没有返回码.
+    /// 流式执行程序, 返回程序的退出信息, 当启动的程序 leak 了则可能没有退出信息.
+    ///
+    /// 启动 leak 的程序时, 会在 `stderr` 中打印会话 ID, 用于之后通过 [`ExecutorClient::attach_stream`] 重新连接.
     pub async fn execute_stream(
         &mut self,
         execute_options: ExecuteOptions,
         stdin: impl AsyncRead + Send + Unpin + 'static,
         stdout: impl AsyncWrite + Send + Unpin + 'static,
         stderr: impl AsyncWrite + Send + Unpin + 'static,
-    ) -> Result<Option<i32>, Error> {
+    ) -> Result<Option<ExitInfo>, Error> {
+        let forward_signals = execute_options.forward_signals;
+        let on_started = if execute_options.leak {
+            OnStarted::Announce
+        } else {
+            OnStarted::Ignore
+        };
+        self.stream_session(
+            RequestChunk::Command(execute_options.into()),
+            forward_signals,
+            on_started,
+            stdin,
+            stdout,
+            stderr,
+        )
+        .await
+    }
+
+    /// 重新连接到服务端上已经存在的会话, 先收到会话缓冲区中最近的输出, 之后和 [`ExecutorClient::execute_stream`] 一致.
+    ///
+    /// `raw_mode` 为 true 时, 如果会话运行在伪终端中且本地标准输入是终端, 连接期间本地终端处于 raw 模式.
+    pub async fn attach_stream(
+        &mut self,
+        session_id: String,
+        forward_signals: bool,
+        raw_mode: bool,
+        stdin: impl AsyncRead + Send + Unpin + 'static,
+        stdout: impl AsyncWrite + Send + Unpin + 'static,
+        stderr: impl AsyncWrite + Send + Unpin + 'static,
+    ) -> Result<Option<ExitInfo>, Error> {
+        self.stream_session(
+            RequestChunk::Attach(Attach { session_id }),
+            forward_signals,
+            if raw_mode {
+                OnStarted::RawMode
+            } else {
+                OnStarted::Ignore
+            },
+            stdin,
+            stdout,
+            stderr,
+        )
+        .await
+    }
+
+    async fn stream_session(
+        &mut self,
+        first_chunk: RequestChunk,
+        forward_signals: bool,
+        on_started: OnStarted,
+        stdin: impl AsyncRead + Send + Unpin + 'static,
+        stdout: impl AsyncWrite + Send + Unpin + 'static,
+        stderr: impl AsyncWrite + Send + Unpin + 'static,
+    ) -> Result<Option<ExitInfo>, Error> {
         let (tx, rx) = tokio::sync::mpsc::channel(10);
+        let signal_forwarder = if forward_signals {
+            Some(signal::spawn_signal_forwarder(tx.clone())?)
+        } else {
+            None
+        };
         tx.send(ExecuteRequestChunk {
-            request_chunk: Some(RequestChunk::Command(Command {
-                executable: execute_options.executable,
-                args: execute_options.args,
-                current_dir: execute_options.current_dir,
-                leak: execute_options.leak,
-            })),
+            request_chunk: Some(first_chunk),
         })
         .await
         .unwrap();
+        if let Some(size) = terminal::terminal_size() {
+            tx.send(ExecuteRequestChunk {
+                request_chunk: Some(RequestChunk::TerminalSize(size)),
+            })
+            .await
+            .unwrap();
+        }
 
+        #[cfg(unix)]
+        let resize_watcher = terminal::spawn_resize_watcher(tx.clone())?;
         self.spawn_stdin_transmitter(tx.clone(), stdin);
 
         let resp = self
             .client
             .execute(tokio_stream::wrappers::ReceiverStream::new(rx))
             .await?;
-        Ok(self
-            .transmit_std_stream(resp.into_inner(), stdout, stderr)
-            .await?)
+        let result = self
+            .transmit_std_stream(resp.into_inner(), stdout, stderr, on_started)
+            .await;
+        #[cfg(unix)]
+        resize_watcher.abort();
+        if let Some(signal_forwarder) = signal_forwarder {
+            signal_forwarder.abort();
+        }
+        Ok(result?)
     }
 }
 
@@ -238,30 +519,280 @@ pub async fn client_main(args: ClientArgs) -> Result<Option<i32>, Error> {
     tracing_subscriber::fmt()
         .with_max_level(tracing::Level::DEBUG)
         .init();
+    let profile = Profile::load(args.connect.profile.as_deref()).await?;
     let mut client = ExecutorClient::connect_tls(
-        args.server_address,
-        args.cert_dir.unwrap_or(config_dir()?),
-        "localhost".into(),
+        profile.address(args.connect.server_address),
+        profile.cert_dir(args.connect.cert_dir)?,
+        profile.domain(),
     )
     .await?;
-    let result = client
-        .execute_stream(
-            ExecuteOptions::builder()
-                .executable(args.executable)
-                .current_dir(Some(args.current_dir.unwrap_or_else(|| {
-                    env::current_dir()
-                        .unwrap_or_default()
-                        .to_string_lossy()
-                        .into()
-                })))
-                .leak(args.leak)
-                .args(args.args)
-                .build(),
-            tokio::io::stdin(),
-            tokio::io::stdout(),
-            tokio::io::stderr(),
-        )
-        .await?;
+    // 优先级: --env > --env-file > profile.
+    let mut env = profile.env.clone();
+    if let Some(env_file) = &args.env_file {
+        env.extend(parse_env_file(&fs::read_to_string(env_file).await?));
+    }
+    env.extend(args.env);
+    // 伪终端模式下由远端终端处理行编辑和回显, 本地终端需要进入 raw 模式.
+    // 重新连接时由会话是否运行在伪终端中决定.
+    let _raw_mode = if args.pty && args.attach.is_none() && std::io::stdin().is_terminal() {
+        Some(RawModeGuard::enable()?)
+    } else {
+        None
+    };
+    let result = if let Some(session_id) = args.attach {
+        client
+            .attach_stream(
+                session_id,
+                true,
+                true,
+                tokio::io::stdin(),
+                tokio::io::stdout(),
+                tokio::io::stderr(),
+            )
+            .await?
+    } else {
+        client
+            .execute_stream(
+                ExecuteOptions::builder()
+                    .executable(args.executable.unwrap_or_default())
+                    .current_dir(Some(
+                        args.current_dir.or(profile.current_dir).unwrap_or_else(|| {
+                            env::current_dir()
+                                .unwrap_or_default()
+                                .to_string_lossy()
+                                .into()
+                        }),
+                    ))
+                    .leak(args.leak || (!args.no_leak && profile.leak == Some(true)))
+                    .pty(args.pty)
+                    .maybe_terminal_size(args.pty.then(terminal::terminal_size).flatten())
+                    .env(env)
+                    .env_clear(args.clear_env)
+                    .maybe_kill_grace_period(args.kill_grace_period)
+                    .maybe_timeout(args.timeout)
+                    .maybe_idle_timeout(args.idle_timeout)
+                    .maybe_limits(resource_limits(&args.limits))
+                    .forward_signals(true)
+                    .args(args.args)
+                    .build(),
+                tokio::io::stdin(),
+                tokio::io::stdout(),
+                tokio::io::stderr(),
+            )
+            .await?
+    };
     info!("execute over: {result:?}");
-    Ok(result)
+    let Some(exit_info) = result else {
+        return Ok(None);
+    };
+    if let Some(e) = &exit_info.spawn_error {
+        eprintln!("rex: failed to spawn executable: {e}");
+    } else if let Some(limit) = &exit_info.limit_exceeded {
+        eprintln!("rex: killed by the server after exceeding the {limit} limit");
+    } else if exit_info.timed_out {
+        match exit_info.timeout_kind() {
+            TimeoutKind::Idle => eprintln!("rex: killed by the server after idle timeout"),
+            _ => eprintln!("rex: killed by the server after timeout"),
+        }
+    }
+    Ok(Some(exit_info.shell_code()))
+}
+
+/// 将 `--limit` 参数合并为 [`ResourceLimits`], 没有参数时为 [`None`].
+fn resource_limits(limits: &[(String, u64)]) -> Option<ResourceLimits> {
+    if limits.is_empty() {
+        return None;
+    }
+    let mut resource_limits = ResourceLimits::default();
+    for (name, value) in limits {
+        resource_limits
+            .set(name, *value)
+            .expect("checked when parsing args");
+    }
+    Some(resource_limits)
+}
+
+/// 连接 URI 地址, 或者 `unix:` 开头的 Unix 域套接字地址.
+async fn connect_channel(
+    address: String,
+    tls_config: Option<ClientTlsConfig>,
+) -> Result<Channel, Error> {
+    if let Some(path) = address.strip_prefix("unix:") {
+        return connect_unix(path.into(), tls_config).await;
+    }
+    let mut endpoint = Channel::from_shared(address).map_err(|_| Error::InvalidUri)?;
+    if let Some(tls_config) = tls_config {
+        endpoint = endpoint.tls_config(tls_config)?;
+    }
+    Ok(endpoint.connect().await?)
+}
+
+#[cfg(unix)]
+async fn connect_unix(
+    path: PathBuf,
+    tls_config: Option<ClientTlsConfig>,
+) -> Result<Channel, Error> {
+    use hyper_util::rt::TokioIo;
+    use tokio::net::UnixStream;
+
+    // URI 中的主机名不会被使用, 使用 TLS 时需要 https, 证书中的域名由 `tls_config` 指定.
+    let endpoint = match tls_config {
+        Some(tls_config) => Channel::from_static("https://localhost").tls_config(tls_config)?,
+        None => Channel::from_static("http://localhost"),
+    };
+    let channel = endpoint
+        .connect_with_connector(tower::service_fn(move |_| {
+            let path = path.clone();
+            async move { UnixStream::connect(path).await.map(TokioIo::new) }
+        }))
+        .await?;
+    Ok(channel)
+}
+
+#[cfg(not(unix))]
+async fn connect_unix(
+    _path: PathBuf,
+    _tls_config: Option<ClientTlsConfig>,
+) -> Result<Channel, Error> {
+    Err(Error::InvalidArgs(
+        "unix domain sockets are not supported on this platform".into(),
+    ))
+}
+
+async fn connect_with_args(args: ConnectArgs) -> Result<ExecutorClient, Error> {
+    let profile = Profile::load(args.profile.as_deref()).await?;
+    ExecutorClient::connect_tls(
+        profile.address(args.server_address),
+        profile.cert_dir(args.cert_dir)?,
+        profile.domain(),
+    )
+    .await
+}
+
+fn state_name(info: &SessionInfo) -> &'static str {
+    match info.state() {
+        SessionState::Running => "running",
+        SessionState::Detached => "detached",
+        SessionState::Exited => "exited",
+        SessionState::Unspecified => "unknown",
+    }
+}
+
+/// 将会话启动至今的时长格式化为 `1d2h`, `3m4s` 这样的形式.
+fn format_age(start_time: u64) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let secs = now.saturating_sub(start_time);
+    match secs {
+        0..60 => format!("{secs}s"),
+        60..3600 => format!("{}m{}s", secs / 60, secs % 60),
+        3600..86400 => format!("{}h{}m", secs / 3600, secs % 3600 / 60),
+        _ => format!("{}d{}h", secs / 86400, secs % 86400 / 3600),
+    }
+}
+
+fn command_line(info: &SessionInfo) -> String {
+    std::iter::once(&info.executable)
+        .chain(&info.args)
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+pub async fn ps_main(args: PsArgs) -> Result<(), Error> {
+    let mut client = connect_with_args(args.connect).await?;
+    if let Some(session_id) = args.session_id {
+        let info = client.get_session(session_id).await?;
+        println!("session:     {}", info.session_id);
+        println!("state:       {}", state_name(&info));
+        if let Some(pid) = info.pid {
+            println!("pid:         {pid}");
+        }
+        println!("command:     {}", command_line(&info));
+        println!("current dir: {}", info.current_dir);
+        println!("age:         {}", format_age(info.start_time));
+        println!("client:      {}", info.peer_address);
+        if !info.identity.is_empty() {
+            println!("identity:    {}", info.identity);
+        }
+        println!("leak:        {}", info.leak);
+        println!("tty:         {}", info.pty);
+        if let Some(exit_info) = &info.exit_info {
+            println!("exit code:   {}", exit_info.shell_code());
+        }
+        return Ok(());
+    }
+    println!(
+        "{:<16}  {:>7}  {:<8}  {:>6}  {:<24}  COMMAND",
+        "ID", "PID", "STATE", "AGE", "CLIENT"
+    );
+    for info in client.list_sessions().await? {
+        println!(
+            "{:<16}  {:>7}  {:<8}  {:>6}  {:<24}  {}",
+            info.session_id,
+            info.pid.map_or_else(|| "-".into(), |pid| pid.to_string()),
+            state_name(&info),
+            format_age(info.start_time),
+            info.peer_address,
+            command_line(&info),
+        );
+    }
+    Ok(())
+}
+
+pub async fn kill_main(args: KillArgs) -> Result<(), Error> {
+    let mut client = connect_with_args(args.connect).await?;
+    client.terminate_session(args.session_id, args.signal).await
+}
+
+pub async fn cp_main(args: CpArgs) -> Result<(), Error> {
+    let mut client = connect_with_args(args.connect).await?;
+    match (args.source, args.destination) {
+        (CopyPath::Local(local), CopyPath::Remote(mut remote)) => {
+            // 目标是服务端上的目录时, 保留本地的文件名.
+            if remote.ends_with(['/', '\\'])
+                && let Some(name) = local.file_name()
+            {
+                remote.push_str(&name.to_string_lossy());
+            }
+            client.upload(&local, remote).await?;
+        }
+        (CopyPath::Remote(remote), CopyPath::Local(mut local)) => {
+            if local.is_dir()
+                && let Some(name) = Path::new(&remote).file_name()
+            {
+                local.push(name);
+            }
+            client.download(remote, local).await?;
+        }
+        _ => {
+            return Err(Error::InvalidArgs(
+                "exactly one of source and destination must be `remote:PATH`".into(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+pub async fn sync_main(args: SyncArgs) -> Result<(), Error> {
+    let CopyPath::Remote(remote_dir) = args.destination else {
+        return Err(Error::InvalidArgs(
+            "destination must be `remote:PATH`".into(),
+        ));
+    };
+    let filter = SyncFilter::new(&args.include, &args.exclude)?;
+    let mut client = connect_with_args(args.connect).await?;
+    let output = client.sync(&args.source, remote_dir, &filter).await?;
+    for path in &output.transferred {
+        println!("{path}");
+    }
+    println!(
+        "{} of {} files transferred, {} bytes",
+        output.transferred.len(),
+        output.total,
+        output.bytes
+    );
+    Ok(())
 }
@@ -0,0 +1,87 @@
+//! 客户端配置文件 (配置目录下的 `client.toml`) 中的连接 profile.
+
+use std::{
+    collections::HashMap,
+    env,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+use crate::{CLIENT_CONFIG, ConfigError, DEFAULT_PORT, Error, config_dir, load_config};
+
+/// 没有指定 `--profile` 时, 从该环境变量中读取使用的 profile.
+pub const PROFILE_ENV: &str = "REX_PROFILE";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConfig {
+    /// 没有指定 profile 时使用的 profile.
+    pub default_profile: Option<String>,
+    pub profiles: HashMap<String, Profile>,
+}
+
+/// 一组连接参数和执行默认值, 命令行参数会覆盖其中的对应项.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    pub address: Option<String>,
+    pub cert_dir: Option<PathBuf>,
+    /// 验证服务端证书时使用的域名.
+    pub domain: Option<String>,
+    pub current_dir: Option<String>,
+    pub env: HashMap<String, String>,
+    pub leak: Option<bool>,
+}
+
+impl ClientConfig {
+    pub async fn load(path: &Path, required: bool) -> Result<ClientConfig, ConfigError> {
+        load_config(path, required).await
+    }
+
+    /// 按名称选择 profile, 没有名称时使用 `default_profile`, 都没有时返回空的 profile.
+    pub fn profile(&self, name: Option<&str>) -> Result<Profile, ConfigError> {
+        let Some(name) = name.or(self.default_profile.as_deref()) else {
+            return Ok(Profile::default());
+        };
+        self.profiles
+            .get(name)
+            .cloned()
+            .ok_or_else(|| ConfigError::Invalid {
+                key: "profiles",
+                message: format!("profile `{name}` not found"),
+            })
+    }
+}
+
+impl Profile {
+    /// 读取配置目录下的 `client.toml` 并选择 profile, `--profile` 优先于 [`PROFILE_ENV`].
+    ///
+    /// 指定了 profile 时配置文件必须存在.
+    pub async fn load(name: Option<&str>) -> Result<Profile, Error> {
+        let name = match name {
+            Some(name) => Some(name.to_string()),
+            None => env::var(PROFILE_ENV).ok().filter(|name| !name.is_empty()),
+        };
+        let path = config_dir()?.join(CLIENT_CONFIG);
+        let config = ClientConfig::load(&path, name.is_some()).await?;
+        Ok(config.profile(name.as_deref())?)
+    }
+
+    pub fn address(&self, address: Option<String>) -> String {
+        address
+            .or_else(|| self.address.clone())
+            .unwrap_or_else(|| format!("https://[::1]:{DEFAULT_PORT}"))
+    }
+
+    pub fn cert_dir(&self, cert_dir: Option<PathBuf>) -> Result<PathBuf, Error> {
+        match cert_dir.or_else(|| self.cert_dir.clone()) {
+            Some(cert_dir) => Ok(cert_dir),
+            None => config_dir(),
+        }
+    }
+
+    pub fn domain(&self) -> String {
+        self.domain.clone().unwrap_or_else(|| "localhost".into())
+    }
+}
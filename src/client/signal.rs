@@ -0,0 +1,62 @@
+//! 将本地收到的信号转发给远端进程.
+
+use std::io;
+
+use tokio::{sync::mpsc::Sender, task::JoinHandle};
+use tracing::debug;
+
+use crate::exec::{ExecuteRequestChunk, Signal, execute_request_chunk::RequestChunk};
+
+async fn forward(tx: &Sender<ExecuteRequestChunk>, name: &str) -> bool {
+    debug!("forward signal: {name}");
+    tx.send(ExecuteRequestChunk {
+        request_chunk: Some(RequestChunk::Signal(Signal {
+            name_or_number: name.into(),
+            // 和终端中的行为一致, 信号发送给整个前台进程组.
+            process_group: true,
+        })),
+    })
+    .await
+    .is_ok()
+}
+
+/// 捕获本地的 `SIGINT`, `SIGTERM`, `SIGHUP`, `SIGQUIT`, `SIGUSR1` 和 `SIGUSR2`,
+/// 并转发给远端进程, 本地进程不会因为这些信号而退出.
+#[cfg(unix)]
+pub fn spawn_signal_forwarder(tx: Sender<ExecuteRequestChunk>) -> io::Result<JoinHandle<()>> {
+    use tokio::signal::unix::{SignalKind, signal};
+    use tokio_stream::{StreamExt as _, StreamMap, wrappers::SignalStream};
+
+    let mut signals = StreamMap::new();
+    for (kind, name) in [
+        (SignalKind::interrupt(), "SIGINT"),
+        (SignalKind::terminate(), "SIGTERM"),
+        (SignalKind::hangup(), "SIGHUP"),
+        (SignalKind::quit(), "SIGQUIT"),
+        (SignalKind::user_defined1(), "SIGUSR1"),
+        (SignalKind::user_defined2(), "SIGUSR2"),
+    ] {
+        signals.insert(name, SignalStream::new(signal(kind)?));
+    }
+    let handle = tokio::spawn(async move {
+        while let Some((name, ())) = signals.next().await {
+            if !forward(&tx, name).await {
+                break;
+            }
+        }
+    });
+    Ok(handle)
+}
+
+/// 捕获本地的 Ctrl-C, 以 `SIGINT` 转发给远端进程.
+#[cfg(not(unix))]
+pub fn spawn_signal_forwarder(tx: Sender<ExecuteRequestChunk>) -> io::Result<JoinHandle<()>> {
+    let handle = tokio::spawn(async move {
+        while tokio::signal::ctrl_c().await.is_ok() {
+            if !forward(&tx, "SIGINT").await {
+                break;
+            }
+        }
+    });
+    Ok(handle)
+}
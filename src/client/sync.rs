@@ -0,0 +1,90 @@
+//! 目录同步: 遍历本地目录, 生成发送给服务端的文件清单.
+
+use std::path::{Path, PathBuf};
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use tokio::fs;
+
+use crate::{Error, exec::ManifestEntry, transfer::file_sha256};
+
+/// 根据 glob 模式筛选需要同步的文件, 模式匹配的是以 `/` 分隔的相对路径, 如 `target/**`, `*.rs`.
+#[derive(Debug, Clone)]
+pub struct SyncFilter {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+}
+
+fn glob_set(patterns: &[String]) -> Result<GlobSet, Error> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern).map_err(|e| Error::InvalidArgs(e.to_string()))?);
+    }
+    builder
+        .build()
+        .map_err(|e| Error::InvalidArgs(e.to_string()))
+}
+
+impl SyncFilter {
+    /// `include` 为空时包含所有文件, 匹配 `exclude` 的文件总是被排除.
+    pub fn new(include: &[String], exclude: &[String]) -> Result<SyncFilter, Error> {
+        Ok(SyncFilter {
+            include: if include.is_empty() {
+                None
+            } else {
+                Some(glob_set(include)?)
+            },
+            exclude: glob_set(exclude)?,
+        })
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        self.include.as_ref().is_none_or(|it| it.is_match(path)) && !self.exclude.is_match(path)
+    }
+
+    /// 匹配 `exclude` 的目录不会被遍历, 其中的文件都被排除.
+    /// `dir` 是以 `/` 结尾的相对路径, `dir` 和 `dir/**` 这样的模式都可以匹配.
+    pub fn excludes_dir(&self, dir: &str) -> bool {
+        self.exclude.is_match(dir) || self.exclude.is_match(dir.trim_end_matches('/'))
+    }
+}
+
+/// 遍历 `dir` 下的所有普通文件, 返回筛选后的相对路径 (以 `/` 分隔) 和本地路径, 符号链接和被排除的目录会被跳过.
+async fn walk(dir: &Path, filter: &SyncFilter) -> Result<Vec<(String, PathBuf)>, Error> {
+    let mut files = Vec::new();
+    let mut pending = vec![(String::new(), dir.to_path_buf())];
+    while let Some((prefix, dir)) = pending.pop() {
+        let mut read_dir = fs::read_dir(&dir).await?;
+        while let Some(entry) = read_dir.next_entry().await? {
+            let relative = format!("{prefix}{}", entry.file_name().to_string_lossy());
+            let file_type = entry.file_type().await?;
+            if file_type.is_dir() {
+                let relative = format!("{relative}/");
+                if !filter.excludes_dir(&relative) {
+                    pending.push((relative, entry.path()));
+                }
+            } else if file_type.is_file() && filter.matches(&relative) {
+                files.push((relative, entry.path()));
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// 生成 `dir` 的文件清单, 返回清单和每个条目对应的本地路径.
+pub(super) async fn manifest(
+    dir: &Path,
+    filter: &SyncFilter,
+) -> Result<(Vec<ManifestEntry>, Vec<PathBuf>), Error> {
+    let mut entries = Vec::new();
+    let mut paths = Vec::new();
+    for (relative, path) in walk(dir, filter).await? {
+        entries.push(ManifestEntry {
+            path: relative,
+            size: fs::metadata(&path).await?.len(),
+            sha256: file_sha256(&path).await?,
+        });
+        paths.push(path);
+    }
+    Ok((entries, paths))
+}
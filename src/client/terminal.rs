@@ -0,0 +1,70 @@
+//! 本地终端的状态控制.
+
+use std::io;
+
+use crossterm::terminal;
+use tokio::{sync::mpsc::Sender, task::JoinHandle};
+use tracing::debug;
+
+use crate::exec::{ExecuteRequestChunk, TerminalSize, execute_request_chunk::RequestChunk};
+
+/// 在存活期间让本地终端处于 raw 模式, 被丢弃时恢复原来的模式.
+pub struct RawModeGuard;
+
+impl RawModeGuard {
+    pub fn enable() -> io::Result<RawModeGuard> {
+        terminal::enable_raw_mode()?;
+        Ok(RawModeGuard)
+    }
+}
+
+impl Drop for RawModeGuard {
+    fn drop(&mut self) {
+        terminal::disable_raw_mode().ok();
+    }
+}
+
+/// 获取本地终端的窗口大小, 没有可用的终端时返回 [`None`].
+pub fn terminal_size() -> Option<TerminalSize> {
+    match terminal::window_size() {
+        Ok(size) => Some(TerminalSize {
+            rows: size.rows.into(),
+            cols: size.columns.into(),
+            xpixel: size.width.into(),
+            ypixel: size.height.into(),
+        }),
+        // 部分平台无法获取像素大小.
+        Err(_) => terminal::size().ok().map(|(cols, rows)| TerminalSize {
+            rows: rows.into(),
+            cols: cols.into(),
+            xpixel: 0,
+            ypixel: 0,
+        }),
+    }
+}
+
+/// 在本地终端窗口大小变化 (`SIGWINCH`) 时向服务端发送新的窗口大小.
+#[cfg(unix)]
+pub fn spawn_resize_watcher(tx: Sender<ExecuteRequestChunk>) -> io::Result<JoinHandle<()>> {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut window_change = signal(SignalKind::window_change())?;
+    let handle = tokio::spawn(async move {
+        while window_change.recv().await.is_some() {
+            let Some(size) = terminal_size() else {
+                continue;
+            };
+            debug!("terminal resized: {size:?}");
+            if tx
+                .send(ExecuteRequestChunk {
+                    request_chunk: Some(RequestChunk::TerminalSize(size)),
+                })
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+    });
+    Ok(handle)
+}
@@ -1,4 +1,9 @@
-use std::{fs, net::IpAddr, path::PathBuf};
+use std::{
+    fs,
+    io::{self, Write as _},
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
 
 use rcgen::{
     CertificateParams, DistinguishedName, DnType, IsCa, Issuer, KeyPair, KeyUsagePurpose, SanType,
@@ -7,19 +12,50 @@ use rcgen::{
 use time::OffsetDateTime;
 
 use crate::{
-    CA_CERT, CLIENT_CERT, CLIENT_SECRET, SERVER_CERT, SERVER_SECRET, args::GenCertArgs, config_dir,
+    CA_CERT, CA_SECRET, CLIENT_CERT, CLIENT_SECRET, Error, SERVER_CERT, SERVER_SECRET,
+    args::GenCertArgs, config_dir,
 };
 
-struct CertGenerator {
+/// 没有指定客户端身份时, 客户端证书使用的 CN.
+pub const DEFAULT_CLIENT_NAME: &str = "Rex Test Client";
+
+/// 写入私钥, 在 Unix 上只有所有者可以读写.
+fn write_secret(path: &Path, pem: &str) -> io::Result<()> {
+    #[cfg(unix)]
+    use std::os::unix::fs::{OpenOptionsExt as _, PermissionsExt as _};
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(path)?;
+    // 已经存在的文件不会使用 `mode`, 需要单独修改权限.
+    #[cfg(unix)]
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    file.write_all(pem.as_bytes())
+}
+
+pub struct CertGenerator {
     output_path: PathBuf,
 }
 
 impl CertGenerator {
-    fn new(output_path: PathBuf) -> CertGenerator {
+    pub fn new(output_path: PathBuf) -> CertGenerator {
         CertGenerator { output_path }
     }
 
-    fn generate_ca(&self) -> Issuer<'_, KeyPair> {
+    /// 读取 `dir` 中已有的 CA 证书和私钥, 用于签发新的客户端证书.
+    pub fn load_ca(dir: &Path) -> Result<Issuer<'static, KeyPair>, Error> {
+        let ca_cert = fs::read_to_string(dir.join(CA_CERT))?;
+        let ca_secret = fs::read_to_string(dir.join(CA_SECRET))?;
+        let ca_keypair =
+            KeyPair::from_pem(&ca_secret).map_err(|e| Error::InvalidArgs(e.to_string()))?;
+        Issuer::from_ca_cert_pem(&ca_cert, ca_keypair)
+            .map_err(|e| Error::InvalidArgs(e.to_string()))
+    }
+
+    /// 生成 CA 证书, 设置了 `key_dir` 时同时在其中保存 CA 证书和私钥,
+    /// 之后可以用 `rex g --ca <key_dir>` 签发更多的客户端证书.
+    pub fn generate_ca(&self, key_dir: Option<&Path>) -> Result<Issuer<'_, KeyPair>, Error> {
         let mut ca_params = CertificateParams::default();
         ca_params.is_ca = IsCa::Ca(rcgen::BasicConstraints::Constrained(0));
         let mut dn = DistinguishedName::new();
@@ -42,11 +78,17 @@ impl CertGenerator {
 
         let ca_keypair = KeyPair::generate().unwrap();
         let root_cert = ca_params.self_signed(&ca_keypair).unwrap();
-        fs::write(self.output_path.join(CA_CERT), root_cert.pem()).unwrap();
-        Issuer::from_ca_cert_der(root_cert.der(), ca_keypair).unwrap()
+        fs::write(self.output_path.join(CA_CERT), root_cert.pem())?;
+        // CA 私钥可以签发任意客户端证书, 不保存在与服务端和客户端共用的证书目录中.
+        if let Some(key_dir) = key_dir {
+            fs::create_dir_all(key_dir)?;
+            fs::write(key_dir.join(CA_CERT), root_cert.pem())?;
+            write_secret(&key_dir.join(CA_SECRET), &ca_keypair.serialize_pem())?;
+        }
+        Ok(Issuer::from_ca_cert_der(root_cert.der(), ca_keypair).unwrap())
     }
 
-    fn generate_server(&self, issuer: &Issuer<KeyPair>) {
+    pub fn generate_server(&self, issuer: &Issuer<KeyPair>) {
         let mut server_params = CertificateParams::default();
         let mut dn = DistinguishedName::new();
         dn.push(DnType::CommonName, "Rex Test Server");
@@ -75,17 +117,18 @@ impl CertGenerator {
         let server_keypair = KeyPair::generate().unwrap();
         let server_cert = server_params.signed_by(&server_keypair, issuer).unwrap();
         fs::write(self.output_path.join(SERVER_CERT), server_cert.pem()).unwrap();
-        fs::write(
-            self.output_path.join(SERVER_SECRET),
-            server_keypair.serialize_pem(),
+        write_secret(
+            &self.output_path.join(SERVER_SECRET),
+            &server_keypair.serialize_pem(),
         )
         .unwrap();
     }
 
-    fn generate_client(&self, issuer: &Issuer<KeyPair>) {
+    /// 签发 subject CN 为 `name` 的客户端证书, 服务端根据 CN 为客户端分配角色.
+    pub fn generate_client(&self, issuer: &Issuer<KeyPair>, name: &str) {
         let mut client_params = CertificateParams::default();
         let mut dn = DistinguishedName::new();
-        dn.push(DnType::CommonName, "Rex Test Client");
+        dn.push(DnType::CommonName, name);
         dn.push(DnType::OrganizationName, "Rex Test Org");
         dn.push(DnType::OrganizationalUnitName, "Rex Test Unit");
         dn.push(DnType::StateOrProvinceName, "Test State");
@@ -100,24 +143,39 @@ impl CertGenerator {
         client_params.not_before = OffsetDateTime::now_utc();
         client_params.not_after = OffsetDateTime::now_utc() + time::Duration::days(365);
         client_params.is_ca = IsCa::NoCa;
-        client_params.serial_number = Some(SerialNumber::from(3));
+        // 同一个 CA 可能签发多个客户端证书, 序列号不能重复.
+        client_params.serial_number = Some(SerialNumber::from(rand::random::<u64>()));
 
         let client_keypair = KeyPair::generate().unwrap();
         let client_cert = client_params.signed_by(&client_keypair, issuer).unwrap();
         fs::write(self.output_path.join(CLIENT_CERT), client_cert.pem()).unwrap();
-        fs::write(
-            self.output_path.join(CLIENT_SECRET),
-            client_keypair.serialize_pem(),
+        write_secret(
+            &self.output_path.join(CLIENT_SECRET),
+            &client_keypair.serialize_pem(),
         )
         .unwrap();
     }
 }
 
-pub fn gen_cert_main(args: GenCertArgs) {
-    let output_path = args.output_path.unwrap_or(config_dir().unwrap());
-    fs::create_dir_all(dbg!(&output_path)).unwrap();
-    let cg = CertGenerator::new(output_path);
-    let issuer = cg.generate_ca();
+pub fn gen_cert_main(args: GenCertArgs) -> Result<(), Error> {
+    let output_path = match args.output_path {
+        Some(output_path) => output_path,
+        None => config_dir()?,
+    };
+    fs::create_dir_all(&output_path)?;
+    let client_name = args.client_name.as_deref().unwrap_or(DEFAULT_CLIENT_NAME);
+    let cg = CertGenerator::new(output_path.clone());
+    if let Some(ca_dir) = args.ca_dir {
+        // 只签发客户端证书, 并复制 CA 证书, 使输出目录可以直接作为客户端的证书目录.
+        let issuer = CertGenerator::load_ca(&ca_dir)?;
+        cg.generate_client(&issuer, client_name);
+        if fs::canonicalize(&ca_dir)? != fs::canonicalize(&output_path)? {
+            fs::copy(ca_dir.join(CA_CERT), output_path.join(CA_CERT))?;
+        }
+        return Ok(());
+    }
+    let issuer = cg.generate_ca(args.keep_ca_key.as_deref())?;
     cg.generate_server(&issuer);
-    cg.generate_client(&issuer);
+    cg.generate_client(&issuer, client_name);
+    Ok(())
 }
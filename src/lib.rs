@@ -1,5 +1,9 @@
-use std::{env, io, path::PathBuf};
+use std::{
+    env, io,
+    path::{Path, PathBuf},
+};
 
+use serde::de::DeserializeOwned;
 use tokio::sync::mpsc::Sender;
 use tonic::Status;
 
@@ -9,17 +13,74 @@ pub mod args;
 pub mod client;
 pub mod gen_cert;
 pub mod server;
+pub mod service;
+pub mod transfer;
 
 pub mod exec {
     #![allow(non_camel_case_types)]
     tonic::include_proto!("exec");
 }
 
+impl exec::ExitInfo {
+    /// 转换为 shell 约定的退出码: 被信号终止时为 128 + 信号编号, 启动失败时为 126,
+    /// 超时被终止时与 `timeout(1)` 一样为 124.
+    pub fn shell_code(&self) -> i32 {
+        if self.spawn_error.is_some() {
+            126
+        } else if self.timed_out {
+            124
+        } else if let Some(signal) = self.signal {
+            128 + signal
+        } else {
+            self.code.unwrap_or(1)
+        }
+    }
+}
+
+impl exec::ResourceLimits {
+    /// 可以通过 [`exec::ResourceLimits::set`] 设置的限制名称.
+    pub const NAMES: &[&str] = &[
+        "cpu_time",
+        "address_space",
+        "open_files",
+        "processes",
+        "core_size",
+        "memory",
+        "cpu_percent",
+    ];
+
+    /// 按名称设置一项限制, 名称见 [`exec::ResourceLimits::NAMES`].
+    pub fn set(&mut self, name: &str, value: u64) -> Result<(), String> {
+        let field = match name {
+            "cpu_time" => &mut self.cpu_time,
+            "address_space" => &mut self.address_space,
+            "open_files" => &mut self.open_files,
+            "processes" => &mut self.processes,
+            "core_size" => &mut self.core_size,
+            "memory" => &mut self.memory,
+            "cpu_percent" => &mut self.cpu_percent,
+            _ => {
+                return Err(format!(
+                    "unknown limit `{name}`, expected one of: {}",
+                    Self::NAMES.join(", ")
+                ));
+            }
+        };
+        *field = Some(value);
+        Ok(())
+    }
+}
+
 pub const CA_CERT: &str = "ca_cert.crt";
+pub const CA_SECRET: &str = "ca_secret.pem";
 pub const SERVER_CERT: &str = "server_cert.crt";
 pub const SERVER_SECRET: &str = "server_secret.pem";
 pub const CLIENT_CERT: &str = "client_cert.crt";
 pub const CLIENT_SECRET: &str = "client_secret.pem";
+pub const SERVER_CONFIG: &str = "server.toml";
+pub const CLIENT_CONFIG: &str = "client.toml";
+/// 服务端关闭时仍在运行的 `leak = true` 的子进程的记录.
+pub const LEAKED_PROCESSES: &str = "leaked_processes.json";
 
 pub const DEFAULT_PORT: u16 = 30521;
 
@@ -29,12 +90,51 @@ pub enum Error {
     IOError(#[from] io::Error),
     #[error("{0}")]
     TonicTransportError(#[from] tonic::transport::Error),
-    #[error("{0}")]
+    #[error("{}", .0.message())]
     TonicStatus(#[from] tonic::Status),
     #[error("{0}")]
     EnvVarError(#[from] env::VarError),
     #[error("invalid uri")]
     InvalidUri,
+    #[error("{0}")]
+    InvalidArgs(String),
+    #[error("{0}")]
+    Config(#[from] ConfigError),
+}
+
+/// 服务端和客户端配置文件的错误.
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("failed to read config {}: {source}", .path.display())]
+    Read { path: PathBuf, source: io::Error },
+    #[error("failed to parse config {}: {source}", .path.display())]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    #[error("invalid config `{key}`: {message}")]
+    Invalid { key: &'static str, message: String },
+}
+
+/// 读取 TOML 配置文件, `required` 为 `false` 时文件不存在则使用默认配置.
+pub async fn load_config<T: DeserializeOwned + Default>(
+    path: &Path,
+    required: bool,
+) -> Result<T, ConfigError> {
+    let content = match tokio::fs::read_to_string(path).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound && !required => return Ok(T::default()),
+        Err(source) => {
+            return Err(ConfigError::Read {
+                path: path.to_path_buf(),
+                source,
+            });
+        }
+    };
+    toml::from_str(&content).map_err(|source| ConfigError::Parse {
+        path: path.to_path_buf(),
+        source,
+    })
 }
 
 pub trait SendStatus {
@@ -56,6 +156,28 @@ impl<T> SendStatus for Result<T, Status> {
     }
 }
 
+/// 解析 `KEY=VALUE` 格式的环境变量文件.
+///
+/// 忽略空行和 `#` 开头的注释行, 支持 `export ` 前缀, 值两侧成对的引号会被去掉
+/// (包括 `systemctl show-environment` 输出的 `$'...'`).
+pub fn parse_env_file(content: &str) -> Vec<(String, String)> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let line = line.strip_prefix("export ").unwrap_or(line);
+            let (key, value) = line.split_once('=')?;
+            let value = value.trim();
+            let value = [("\"", '"'), ("'", '\''), ("$'", '\'')]
+                .iter()
+                .find_map(|&(l, r)| value.strip_prefix(l)?.strip_suffix(r))
+                .unwrap_or(value);
+            Some((key.trim().to_string(), value.to_string()))
+        })
+        .collect()
+}
+
 pub fn config_dir() -> Result<PathBuf, Error> {
     let home: PathBuf = env::var(if cfg!(windows) { "USERPROFILE" } else { "HOME" })?.into();
     let config = home.join(".config").join("rex");
@@ -2,10 +2,12 @@ use std::process::exit;
 
 use clap::Parser;
 use exec_with_local_desktop::{
+    Error,
     args::{Args, Subcommands},
-    client::client_main,
+    client::{client_main, cp_main, kill_main, ps_main, sync_main},
     gen_cert::gen_cert_main,
     server::server_main,
+    service::{install_service_main, service_status_main, uninstall_service_main},
 };
 
 fn main() {
@@ -18,12 +20,30 @@ fn main() {
         Subcommands::Client(args) => {
             let rst = rt.block_on(client_main(args));
             rt.shutdown_background(); // 不知道为什么会有 1 个 task 卡着, 只能强行关闭了.
-            let code = rst.unwrap();
+            let code = rst.unwrap_or_else(|e| {
+                eprintln!("rex: {e}");
+                exit(1);
+            });
             if let Some(code) = code {
                 exit(code);
             }
         }
-        Subcommands::Server(args) => rt.block_on(server_main(args)).unwrap(),
-        Subcommands::GenCert(args) => gen_cert_main(args),
+        Subcommands::Server(args) => exit_on_error(rt.block_on(server_main(args))),
+        Subcommands::GenCert(args) => exit_on_error(gen_cert_main(args)),
+        Subcommands::Ps(args) => exit_on_error(rt.block_on(ps_main(args))),
+        Subcommands::Kill(args) => exit_on_error(rt.block_on(kill_main(args))),
+        Subcommands::Cp(args) => exit_on_error(rt.block_on(cp_main(args))),
+        Subcommands::Sync(args) => exit_on_error(rt.block_on(sync_main(args))),
+        Subcommands::InstallService(args) => exit_on_error(install_service_main(args)),
+        Subcommands::UninstallService(args) => exit_on_error(uninstall_service_main(args)),
+        Subcommands::ServiceStatus(args) => exit_on_error(service_status_main(args)),
+    }
+}
+
+/// 打印错误并以非零状态退出.
+fn exit_on_error(result: Result<(), Error>) {
+    if let Err(e) = result {
+        eprintln!("rex: {e}");
+        exit(1);
     }
 }
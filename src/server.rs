@@ -1,21 +1,300 @@
-use std::time::Duration;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::pin::pin;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
 use tokio::fs;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
 use tokio_stream::wrappers::ReceiverStream;
 use tonic::Streaming;
+use tonic::transport::server::TcpIncoming;
 use tonic::transport::{Certificate, Identity, Server, ServerTlsConfig};
 use tonic::{Request, Response, Status};
+use tracing::{info, warn};
 
 use crate::args::ServerArgs;
+use crate::exec::execute_request_chunk::RequestChunk;
 use crate::exec::execute_server::{Execute, ExecuteServer};
-use crate::exec::{ExecuteRequestChunk, ProgramOutput};
+use crate::exec::{
+    Attach, DownloadRequest, ExecuteRequestChunk, FileChunk, GetSessionRequest, KillCommand,
+    ListSessionsRequest, ListSessionsResponse, ProgramOutput, ResourceLimits, ServerShutdown,
+    SessionInfo, Signal, SyncRequest, SyncResponse, TerminateSessionRequest,
+    TerminateSessionResponse, UploadResponse, program_output::Payload,
+};
+use crate::server::audit::{AuditEvent, AuditLog};
+use crate::server::auth::{AuthPolicy, Peer, Role};
+use crate::server::config::ServerConfig;
 use crate::server::executor::ProgramCaller;
-use crate::{CA_CERT, Error, config_dir};
-use crate::{SERVER_CERT, SERVER_SECRET, SendStatus as _};
+use crate::server::leaked::LeakedProcess;
+use crate::server::listener::{BindAddress, Listener};
+use crate::server::policy::ExecPolicy;
+use crate::server::resources::CgroupRoot;
+use crate::server::session::{Session, SessionRegistry};
+use crate::server::slots::Slots;
+use crate::{CA_CERT, Error, LEAKED_PROCESSES, config_dir, transfer};
+use crate::{SERVER_CERT, SERVER_CONFIG, SERVER_SECRET, SendStatus as _};
 
+pub mod audit;
+pub mod auth;
+pub mod config;
+mod environment;
 mod executor;
+mod files;
+pub mod leaked;
+pub mod listener;
+pub mod policy;
+#[cfg(unix)]
+mod pty;
+pub mod resources;
+mod session;
+mod slots;
 
-pub struct Executor;
+pub use crate::server::environment::{EnvPolicy, SessionEnv, SessionEnvSource};
+pub use crate::server::files::FileRoot;
+
+/// 执行和文件传输的策略, 服务端运行时可以通过 [`Executor::reload`] 整体替换.
+#[derive(Default, bon::Builder)]
+pub struct ExecutorSettings {
+    #[builder(default)]
+    env_policy: EnvPolicy,
+    /// 注入到每个子进程中的桌面会话环境变量.
+    session_env: Option<SessionEnv>,
+    /// 文件传输可以访问的服务端路径.
+    #[builder(default)]
+    file_root: FileRoot,
+    /// 限制可以执行的程序, 参数和工作目录.
+    #[builder(default)]
+    policy: ExecPolicy,
+    /// 根据客户端证书中的身份限制客户端可以使用的功能.
+    #[builder(default)]
+    auth: AuthPolicy,
+    /// 客户端没有指定工作目录时使用的目录, 为空时使用程序所在的目录.
+    default_cwd: Option<PathBuf>,
+    /// 同时存在的会话数上限, 包括已经结束但结果还没有被取回的会话.
+    max_sessions: Option<usize>,
+    /// 同时运行的子进程数上限.
+    max_running: Option<usize>,
+    /// 每个客户端同时运行的子进程数上限, 按客户端证书中的身份计数.
+    max_running_per_client: Option<usize>,
+    /// 达到运行数上限时请求排队等待的最长时间, 为 [`None`] 时直接拒绝请求.
+    max_queue_wait: Option<Duration>,
+    /// 子进程运行时间的上限, 客户端没有指定超时时也会使用.
+    max_timeout: Option<Duration>,
+    /// 子进程没有输入输出的时间的上限, 客户端没有指定空闲超时时也会使用.
+    max_idle_timeout: Option<Duration>,
+    /// 子进程资源限制的上限, 客户端没有请求的项也会使用.
+    #[builder(default)]
+    max_resources: ResourceLimits,
+    /// 用于限制子进程内存和 CPU 使用率的 cgroup.
+    cgroup: Option<CgroupRoot>,
+}
+
+/// 关闭服务端时检查子进程是否都已退出的间隔.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// 关闭服务端时终止子进程后等待它们退出的最长时间, 超过后不再等待, 直接关闭服务端.
+const SHUTDOWN_KILL_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum ShutdownState {
+    #[default]
+    Serving,
+    /// 不再接受新的执行, 等待子进程退出.
+    Draining,
+    /// 子进程已经处理完毕, 断开所有执行连接.
+    Closed,
+}
+
+fn shutting_down() -> Status {
+    Status::unavailable("server is shutting down")
+}
+
+/// 被拒绝的请求写入审计日志, 是否写入由审计日志的设置决定.
+fn audit_denied(audit: Option<&AuditLog>, peer: &Peer, event: &AuditEvent) {
+    if let Some(audit) = audit {
+        audit.record(peer, event);
+    }
+}
+
+#[derive(Default, Clone)]
+pub struct Executor {
+    settings: Arc<RwLock<Arc<ExecutorSettings>>>,
+    sessions: Arc<SessionRegistry>,
+    /// 正在运行的子进程占用的空位和排队等待的请求.
+    slots: Arc<Slots>,
+    /// 记录每次执行的审计日志, 不随配置重新加载.
+    audit: Option<Arc<AuditLog>>,
+    shutdown: Arc<watch::Sender<ShutdownState>>,
+}
+
+impl Executor {
+    pub fn new(settings: ExecutorSettings) -> Executor {
+        let slots = Arc::<Slots>::default();
+        slots.set_limits(settings.max_running, settings.max_running_per_client);
+        Executor {
+            settings: Arc::new(RwLock::new(Arc::new(settings))),
+            sessions: Arc::default(),
+            slots,
+            audit: None,
+            shutdown: Arc::default(),
+        }
+    }
+
+    #[must_use]
+    pub fn with_audit(mut self, audit: AuditLog) -> Executor {
+        self.audit = Some(Arc::new(audit));
+        self
+    }
+
+    /// 替换执行策略, 只影响之后的请求, 已经存在的会话不受影响.
+    pub fn reload(&self, settings: ExecutorSettings) {
+        self.slots
+            .set_limits(settings.max_running, settings.max_running_per_client);
+        *self.settings.write().unwrap() = Arc::new(settings);
+    }
+
+    fn settings(&self) -> Arc<ExecutorSettings> {
+        self.settings.read().unwrap().clone()
+    }
+
+    /// 关闭执行服务: 不再接受新的执行, 通知已连接的客户端, 等待 `leak = false` 的子进程在 `grace_period` 内退出,
+    /// 之后终止仍在运行的子进程, 最后断开所有执行连接.
+    ///
+    /// 整个过程最多持续 `grace_period` 加上 [`SHUTDOWN_KILL_TIMEOUT`], 超时后不再等待子进程退出.
+    ///
+    /// # Returns
+    /// 仍在运行的 `leak = true` 的子进程, 它们会在服务端退出后继续运行.
+    pub async fn shutdown(&self, grace_period: Duration) -> Vec<LeakedProcess> {
+        self.shutdown.send_replace(ShutdownState::Draining);
+        if tokio::time::timeout(
+            grace_period + SHUTDOWN_KILL_TIMEOUT,
+            self.drain(grace_period),
+        )
+        .await
+        .is_err()
+        {
+            warn!("some programs did not exit in time, shutting down anyway");
+        }
+        let mut leaked = Vec::new();
+        for session in self.sessions.list() {
+            if session.leak()
+                && let Some(process) = LeakedProcess::from_info(session.info().await)
+            {
+                leaked.push(process);
+            }
+        }
+        self.shutdown.send_replace(ShutdownState::Closed);
+        leaked
+    }
+
+    /// 通知已连接的客户端, 等待 `leak = false` 的子进程退出, `grace_period` 后终止仍在运行的子进程.
+    async fn drain(&self, grace_period: Duration) {
+        for session in self.sessions.list() {
+            if !session.exited().await {
+                session
+                    .notify(Payload::ServerShutdown(ServerShutdown {
+                        grace_period: u32::try_from(grace_period.as_secs()).unwrap_or(u32::MAX),
+                        terminate: !session.leak(),
+                    }))
+                    .await;
+            }
+        }
+        let deadline = Instant::now() + grace_period;
+        let mut terminated = false;
+        loop {
+            let mut running = Vec::new();
+            for session in self.sessions.list() {
+                if !session.leak() && !session.exited().await {
+                    running.push(session);
+                }
+            }
+            if running.is_empty() {
+                break;
+            }
+            if !terminated && Instant::now() >= deadline {
+                info!("terminating {} running programs", running.len());
+                // 会话任务可能正阻塞在写入子进程的输入上, 不能逐个等待.
+                for session in running {
+                    tokio::spawn(async move { session.shutdown().await });
+                }
+                terminated = true;
+            }
+            tokio::time::sleep(SHUTDOWN_POLL_INTERVAL).await;
+        }
+    }
+
+    /// 在 [`Executor::shutdown`] 处理完所有子进程后完成.
+    fn closed(&self) -> impl Future<Output = ()> + use<> {
+        let mut state = self.shutdown.subscribe();
+        async move {
+            state
+                .wait_for(|state| *state == ShutdownState::Closed)
+                .await
+                .ok();
+        }
+    }
+
+    fn audit_denied(&self, peer: &Peer, event: &AuditEvent) {
+        audit_denied(self.audit.as_deref(), peer, event);
+    }
+
+    /// 根据客户端证书找到发起请求的客户端的角色, 被拒绝的 `rpc` 请求写入审计日志.
+    fn authorize<T>(
+        &self,
+        req: &Request<T>,
+        rpc: &'static str,
+    ) -> Result<(Peer, Arc<Role>), Status> {
+        let peer = Peer::from_request(req);
+        match self.settings().auth.authorize(peer.identity.as_ref()) {
+            Ok(role) => Ok((peer, role)),
+            Err(status) => {
+                self.audit_denied(&peer, &AuditEvent::denied_rpc(rpc, None, &status));
+                Err(status)
+            }
+        }
+    }
+
+    /// 客户端可以管理的会话, 无权管理的会话视为不存在, 并写入审计日志.
+    fn managed_session<T>(
+        &self,
+        req: &Request<T>,
+        rpc: &'static str,
+        session_id: &str,
+    ) -> Result<Arc<Session>, Status> {
+        let (peer, role) = self.authorize(req, rpc)?;
+        let not_found = || Status::not_found("session not found");
+        let session = self.sessions.get(session_id).ok_or_else(not_found)?;
+        if !role.can_manage(peer.identity.as_ref(), session.owner()) {
+            let status = not_found();
+            let event = AuditEvent::denied_rpc(rpc, Some(session_id.to_string()), &status);
+            self.audit_denied(&peer, &event);
+            return Err(status);
+        }
+        Ok(session)
+    }
+
+    /// 解析文件传输请求访问的路径并检查客户端是否可以访问, 被拒绝的请求写入审计日志.
+    async fn check_file(
+        &self,
+        peer: &Peer,
+        role: &Role,
+        rpc: &'static str,
+        path: &str,
+    ) -> Result<PathBuf, Status> {
+        let result = async {
+            let resolved = self.settings().file_root.resolve(path).await?;
+            role.check_file(&resolved)?;
+            Ok(resolved)
+        }
+        .await;
+        if let Err(status) = &result {
+            let event = AuditEvent::denied_rpc(rpc, Some(path.to_string()), status);
+            self.audit_denied(peer, &event);
+        }
+        result
+    }
+}
 
 #[tonic::async_trait]
 impl Execute for Executor {
@@ -24,43 +303,419 @@ impl Execute for Executor {
         &self,
         req: Request<Streaming<ExecuteRequestChunk>>,
     ) -> Result<Response<Self::executeStream>, Status> {
+        if *self.shutdown.borrow() != ShutdownState::Serving {
+            return Err(shutting_down());
+        }
         let (tx, rx) = tokio::sync::mpsc::channel(30);
+        let settings = self.settings();
+        let sessions = self.sessions.clone();
+        let peer = Peer::from_request(&req);
+        let role = match settings.auth.authorize(peer.identity.as_ref()) {
+            Ok(role) => role,
+            Err(status) => {
+                self.audit_denied(&peer, &AuditEvent::denied(None, &status));
+                return Err(status);
+            }
+        };
+        let audit = self.audit.clone();
+        let slots = self.slots.clone();
+        let mut shutdown = self.shutdown.subscribe();
+        let closed = self.closed();
         tokio::spawn(async move {
-            let Some(mut pc) = ProgramCaller::parse(req.into_inner(), tx.clone())
-                .await
-                .send_status(tx.clone())
-                .await
-            else {
-                return;
+            let mut request = req.into_inner();
+            let session = match request.message().await {
+                Ok(Some(ExecuteRequestChunk {
+                    request_chunk: Some(RequestChunk::Command(command)),
+                })) => {
+                    let reservation = match sessions.reserve(settings.max_sessions) {
+                        Ok(reservation) => reservation,
+                        Err(status) => {
+                            audit_denied(
+                                audit.as_deref(),
+                                &peer,
+                                &AuditEvent::denied(Some(&command), &status),
+                            );
+                            tx.send(Err(status)).await.ok();
+                            return;
+                        }
+                    };
+                    let session_env = match &settings.session_env {
+                        Some(session_env) => session_env.resolve().await,
+                        None => HashMap::new(),
+                    };
+                    let pc = match ProgramCaller::parse(
+                        command.clone(),
+                        &settings,
+                        &role,
+                        session_env,
+                    ) {
+                        Ok(pc) => pc,
+                        Err(status) => {
+                            audit_denied(
+                                audit.as_deref(),
+                                &peer,
+                                &AuditEvent::denied(Some(&command), &status),
+                            );
+                            tx.send(Err(status)).await.ok();
+                            return;
+                        }
+                    };
+                    let acquired = tokio::select! {
+                        result = slots.acquire(peer.identity_name(), settings.max_queue_wait, &tx) => result,
+                        _ = shutdown.wait_for(|state| *state != ShutdownState::Serving) => {
+                            Err(shutting_down())
+                        }
+                    };
+                    let permit = match acquired {
+                        Ok(permit) => permit,
+                        Err(status) => {
+                            audit_denied(
+                                audit.as_deref(),
+                                &peer,
+                                &AuditEvent::denied(Some(&command), &status),
+                            );
+                            tx.send(Err(status)).await.ok();
+                            return;
+                        }
+                    };
+                    let Some(session) = pc
+                        .call_program(&sessions, &tx, &peer, audit, permit, reservation)
+                        .await
+                    else {
+                        return;
+                    };
+                    session
+                }
+                Ok(Some(ExecuteRequestChunk {
+                    request_chunk: Some(RequestChunk::Attach(Attach { session_id })),
+                })) => {
+                    // 无权管理的会话视为不存在.
+                    let session = match sessions.get(&session_id) {
+                        Some(it) if role.can_manage(peer.identity.as_ref(), it.owner()) => Ok(it),
+                        Some(_) => {
+                            let status = Status::not_found("session not found");
+                            let target = Some(session_id.clone());
+                            let event = AuditEvent::denied_rpc("attach", target, &status);
+                            audit_denied(audit.as_deref(), &peer, &event);
+                            Err(status)
+                        }
+                        None => Err(Status::not_found("session not found")),
+                    };
+                    let Some(session) = session.send_status(tx.clone()).await else {
+                        return;
+                    };
+                    let Some(exited) = session
+                        .attach(tx.clone())
+                        .await
+                        .send_status(tx.clone())
+                        .await
+                    else {
+                        return;
+                    };
+                    if exited {
+                        // 已经结束的会话的结果被取回后就不再保留.
+                        sessions.remove(&session_id);
+                        return;
+                    }
+                    session
+                }
+                _ => {
+                    tx.send(Err(Status::invalid_argument(
+                        "can not get command or attach in first chunk",
+                    )))
+                    .await
+                    .ok();
+                    return;
+                }
             };
-            let _ = pc.call_program().await.send_status(tx.clone()).await;
+            tokio::select! {
+                () = session.serve(request, tx) => {}
+                // 服务端关闭时 `leak = true` 的子进程继续运行, 只断开连接.
+                () = closed => session.detach().await,
+            }
         });
         Ok(Response::new(ReceiverStream::new(rx)))
     }
+
+    async fn list_sessions(
+        &self,
+        req: Request<ListSessionsRequest>,
+    ) -> Result<Response<ListSessionsResponse>, Status> {
+        let (peer, role) = self.authorize(&req, "list_sessions")?;
+        let mut sessions = Vec::new();
+        for session in self.sessions.list() {
+            if role.can_manage(peer.identity.as_ref(), session.owner()) {
+                sessions.push(session.info().await);
+            }
+        }
+        Ok(Response::new(ListSessionsResponse { sessions }))
+    }
+
+    async fn get_session(
+        &self,
+        req: Request<GetSessionRequest>,
+    ) -> Result<Response<SessionInfo>, Status> {
+        let session = self.managed_session(&req, "get_session", &req.get_ref().session_id)?;
+        Ok(Response::new(session.info().await))
+    }
+
+    async fn terminate_session(
+        &self,
+        req: Request<TerminateSessionRequest>,
+    ) -> Result<Response<TerminateSessionResponse>, Status> {
+        let session = self.managed_session(&req, "terminate_session", &req.get_ref().session_id)?;
+        let TerminateSessionRequest { session_id, signal } = req.into_inner();
+        if session.exited().await {
+            // 已经结束的会话直接丢弃其结果.
+            self.sessions.remove(&session_id);
+            return Ok(Response::new(TerminateSessionResponse {}));
+        }
+        let request_chunk = match signal {
+            Some(name_or_number) => RequestChunk::Signal(Signal {
+                name_or_number,
+                process_group: true,
+            }),
+            None => RequestChunk::Kill(KillCommand {}),
+        };
+        session.control(request_chunk).await?;
+        Ok(Response::new(TerminateSessionResponse {}))
+    }
+
+    async fn upload(
+        &self,
+        req: Request<Streaming<FileChunk>>,
+    ) -> Result<Response<UploadResponse>, Status> {
+        let (peer, role) = self.authorize(&req, "upload")?;
+        let mut stream = req.into_inner();
+        let (path, size) = transfer::receive_file(&mut stream, async |header| {
+            let path = self
+                .check_file(&peer, &role, "upload", &header.path)
+                .await?;
+            // 同步目录时, 文件所在的目录可能还不存在.
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)
+                    .await
+                    .map_err(|e| transfer::io_status(&e, parent))?;
+            }
+            Ok(path)
+        })
+        .await?;
+        info!("received {size} bytes to {}", path.display());
+        Ok(Response::new(UploadResponse { size }))
+    }
+
+    type downloadStream = ReceiverStream<Result<FileChunk, Status>>;
+    async fn download(
+        &self,
+        req: Request<DownloadRequest>,
+    ) -> Result<Response<Self::downloadStream>, Status> {
+        let (peer, role) = self.authorize(&req, "download")?;
+        let path = self
+            .check_file(&peer, &role, "download", &req.get_ref().path)
+            .await?;
+        let (file, mut header) = transfer::open_file(&path).await?;
+        header.path = path.to_string_lossy().into_owned();
+        Ok(Response::new(transfer::stream_file(file, header)))
+    }
+
+    async fn sync(&self, req: Request<SyncRequest>) -> Result<Response<SyncResponse>, Status> {
+        let (peer, role) = self.authorize(&req, "sync")?;
+        let dir = self
+            .check_file(&peer, &role, "sync", &req.get_ref().path)
+            .await?;
+        let entries = req.into_inner().entries;
+        let total = entries.len();
+        let changed = files::changed_files(&self.settings().file_root, &dir, entries).await?;
+        info!(
+            "sync {}: {} of {total} files changed",
+            dir.display(),
+            changed.len()
+        );
+        Ok(Response::new(SyncResponse { changed }))
+    }
 }
 
 pub async fn server_main(args: ServerArgs) -> Result<(), Error> {
-    if cfg!(debug_assertions) {
-        tracing_subscriber::fmt()
-            .with_max_level(tracing::Level::DEBUG)
-            .init();
-    } else {
-        tracing_subscriber::fmt()
-            .with_max_level(tracing::Level::INFO)
-            .init();
-    }
-    let cert_dir = args.cert_dir.unwrap_or(config_dir()?);
+    let config_path = match &args.config {
+        Some(path) => path.clone(),
+        None => config_dir()?.join(SERVER_CONFIG),
+    };
+    // 没有指定配置文件时, 默认位置的配置文件可以不存在.
+    let required = args.config.is_some();
+    let (config, settings) = ServerConfig::resolve(&config_path, required, &args).await?;
+    let log_level = config::init_logging(&config.log)?;
+    let mut executor = Executor::new(settings);
+    if let Some(audit) = config.audit.audit_log()? {
+        info!("writing audit log to {}", audit.path().display());
+        executor = executor.with_audit(audit);
+    }
+    let cert_dir = match &config.cert_dir {
+        Some(cert_dir) => cert_dir.clone(),
+        None => config_dir()?,
+    };
+    let client_ca = match &config.auth.client_ca {
+        Some(client_ca) => client_ca.clone(),
+        None => cert_dir.join(CA_CERT),
+    };
     let server_cert = fs::read(cert_dir.join(SERVER_CERT)).await?;
     let server_secret = fs::read(cert_dir.join(SERVER_SECRET)).await?;
     let tls_config = ServerTlsConfig::new()
-        .client_ca_root(Certificate::from_pem(
-            fs::read(cert_dir.join(CA_CERT)).await?,
-        ))
+        .client_ca_root(Certificate::from_pem(fs::read(client_ca).await?))
         .identity(Identity::from_pem(server_cert, server_secret))
-        .timeout(Duration::from_secs(1));
-    Ok(Server::builder()
-        .tls_config(tls_config)?
-        .add_service(ExecuteServer::new(Executor))
-        .serve(args.bind_address)
-        .await?)
+        .timeout(Duration::from_secs(config.auth.handshake_timeout));
+    let leaked_file = match &config.shutdown.leaked_file {
+        Some(path) => path.clone(),
+        None => config_dir()?.join(LEAKED_PROCESSES),
+    };
+    let grace_period = Duration::from_secs(config.shutdown.grace_period);
+    // 之前的服务端关闭时留下的子进程, 仍在运行的会在这次关闭时一起保存.
+    let mut leaked = leaked::load(&leaked_file).await.unwrap_or_else(|e| {
+        warn!("failed to read {}: {e}", leaked_file.display());
+        Vec::new()
+    });
+    leaked.retain(LeakedProcess::is_running);
+    for process in &leaked {
+        info!(
+            "process {} ({}) leaked by the previous server is still running",
+            process.pid, process.executable
+        );
+    }
+    let (listeners, unix_sockets) = bind_listeners(&config)?;
+    #[cfg(unix)]
+    let same_uid = config.unix.same_uid;
+    let mut servers = JoinSet::new();
+    for listener in listeners {
+        let router = Server::builder()
+            .tls_config(tls_config.clone())?
+            .add_service(ExecuteServer::new(executor.clone()));
+        info!("listening on {listener}");
+        match listener {
+            Listener::Tcp(incoming) => {
+                let incoming = incoming.with_nodelay(Some(true));
+                servers.spawn(router.serve_with_incoming_shutdown(incoming, executor.closed()));
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let incoming = listener::unix_incoming(listener, same_uid);
+                servers.spawn(router.serve_with_incoming_shutdown(incoming, executor.closed()));
+            }
+        }
+    }
+    tokio::spawn(config::watch(
+        config_path,
+        required,
+        args,
+        config,
+        executor.clone(),
+        log_level,
+    ));
+    let mut serving = pin!(async move {
+        while let Some(result) = servers.join_next().await {
+            result.expect("server task panicked")?;
+        }
+        Ok::<_, Error>(())
+    });
+    tokio::select! {
+        result = &mut serving => return result,
+        () = shutdown_signal() => {}
+    }
+    info!(
+        "shutting down, waiting up to {}s for running programs",
+        grace_period.as_secs()
+    );
+    leaked.retain(LeakedProcess::is_running);
+    leaked.extend(executor.shutdown(grace_period).await);
+    match leaked::save(&leaked_file, &leaked).await {
+        Ok(()) if leaked.is_empty() => {}
+        Ok(()) => info!(
+            "{} leaked processes keep running, see {}",
+            leaked.len(),
+            leaked_file.display()
+        ),
+        Err(e) => warn!("failed to write {}: {e}", leaked_file.display()),
+    }
+    // 不接收输出的客户端会使连接无法正常关闭.
+    let result = tokio::time::timeout(SHUTDOWN_KILL_TIMEOUT, serving)
+        .await
+        .unwrap_or_else(|_| {
+            warn!("some connections did not close in time, shutting down anyway");
+            Ok(())
+        });
+    for path in unix_sockets {
+        fs::remove_file(&path).await.ok();
+    }
+    result
+}
+
+/// systemd 传入了监听套接字时使用它们, 否则监听配置中的全部地址.
+///
+/// 同时返回服务端创建的 Unix 域套接字文件, 服务端关闭时删除它们.
+fn bind_listeners(config: &ServerConfig) -> Result<(Vec<Listener>, Vec<PathBuf>), Error> {
+    let activated = listener::listen_fds()?;
+    if !activated.is_empty() {
+        info!(
+            "using {} sockets passed by systemd, `bind` is ignored",
+            activated.len()
+        );
+        return Ok((activated, Vec::new()));
+    }
+    #[cfg(unix)]
+    let unix_options = config.unix.options()?;
+    let mut listeners = Vec::new();
+    let mut unix_sockets = Vec::new();
+    for bind_address in &config.bind {
+        let listener = match bind_address {
+            BindAddress::Tcp(addr) => TcpIncoming::bind(*addr).map(Listener::Tcp),
+            #[cfg(unix)]
+            BindAddress::Unix(path) => {
+                listener::bind_unix_listener(path, &unix_options).map(Listener::Unix)
+            }
+            #[cfg(not(unix))]
+            BindAddress::Unix(_) => unreachable!("rejected by ServerConfig::validate"),
+        };
+        match listener {
+            Ok(listener) => listeners.push(listener),
+            Err(e) => {
+                // 已经创建的套接字文件不会再被使用.
+                for path in &unix_sockets {
+                    std::fs::remove_file(path).ok();
+                }
+                return Err(e.into());
+            }
+        }
+        if let BindAddress::Unix(path) = bind_address {
+            unix_sockets.push(path.clone());
+        }
+    }
+    Ok((listeners, unix_sockets))
+}
+
+/// 收到 `SIGTERM` 或 `SIGINT` 时完成.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                warn!("failed to listen for SIGTERM: {e}");
+                std::future::pending().await
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    let interrupt = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            warn!("failed to listen for SIGINT: {e}");
+            std::future::pending().await
+        }
+    };
+    tokio::select! {
+        () = terminate => {}
+        () = interrupt => {}
+    }
 }
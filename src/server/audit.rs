@@ -0,0 +1,342 @@
+//! 审计日志: 以 JSON Lines 格式记录每次执行的启动和结束, 以及 (可选的) 被拒绝的请求.
+//!
+//! 日志文件超过大小上限时轮转, 旧文件依次重命名为 `<文件名>.1`, `<文件名>.2`, ...,
+//! 超过保留数量的最旧的文件会被删除. 日志文件只有所有者可以读写.
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Write as _},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use serde::Serialize;
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
+use tonic::Status;
+use tracing::warn;
+
+use crate::exec::{Command, ExitInfo, ResourceLimits};
+use crate::server::auth::Peer;
+
+/// 执行请求的内容, 已经启动的程序和工作目录是解析后的路径, 被拒绝的请求则是客户端请求的原始值.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct CommandRecord {
+    pub executable: String,
+    pub args: Vec<String>,
+    pub current_dir: Option<String>,
+    /// 客户端设置的环境变量, 值可能包含密钥, 只在 [`AuditLog`] 开启 `log_env_values` 时记录.
+    pub env: BTreeMap<String, Option<String>>,
+    /// 客户端移除的环境变量.
+    pub env_remove: Vec<String>,
+    /// 客户端是否清空了环境变量.
+    pub env_clear: bool,
+    pub leak: bool,
+    pub pty: bool,
+    /// 客户端请求的超时 (秒).
+    pub timeout: Option<u32>,
+    pub idle_timeout: Option<u32>,
+    /// 客户端请求的资源限制.
+    pub limits: Option<ResourceLimits>,
+}
+
+impl From<&Command> for CommandRecord {
+    fn from(command: &Command) -> Self {
+        CommandRecord {
+            executable: command.executable.clone(),
+            args: command.args.clone(),
+            current_dir: command.current_dir.clone(),
+            env: command
+                .env
+                .iter()
+                .map(|(key, value)| (key.clone(), Some(value.clone())))
+                .collect(),
+            env_remove: command.env_remove.clone(),
+            env_clear: command.env_clear,
+            leak: command.leak,
+            pty: command.pty,
+            timeout: command.timeout,
+            idle_timeout: command.idle_timeout,
+            limits: command.limits.as_deref().copied(),
+        }
+    }
+}
+
+/// [`ExitInfo`] 的审计记录.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ExitRecord {
+    pub code: Option<i32>,
+    pub signal: Option<i32>,
+    pub core_dumped: bool,
+    pub killed_by_request: bool,
+    pub timed_out: bool,
+    pub spawn_error: Option<String>,
+    pub limit_exceeded: Option<String>,
+}
+
+impl From<&ExitInfo> for ExitRecord {
+    fn from(info: &ExitInfo) -> Self {
+        ExitRecord {
+            code: info.code,
+            signal: info.signal,
+            core_dumped: info.core_dumped,
+            killed_by_request: info.killed_by_request,
+            timed_out: info.timed_out,
+            spawn_error: info.spawn_error.clone(),
+            limit_exceeded: info.limit_exceeded.clone(),
+        }
+    }
+}
+
+/// 服务端终止子进程的原因.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KillReason {
+    /// 客户端发送了 `KillCommand` 或终止了会话.
+    Request,
+    /// `leak = false` 的会话的客户端连接断开.
+    Disconnect,
+    /// 运行时间超过了超时.
+    Timeout,
+    /// 超过空闲超时没有输入输出.
+    IdleTimeout,
+    /// 服务端关闭时子进程没有在等待时间内退出.
+    Shutdown,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    /// 子进程已经启动.
+    Start {
+        session_id: String,
+        pid: Option<u32>,
+        #[serde(flatten)]
+        command: CommandRecord,
+    },
+    /// 子进程已经退出, 或者启动失败 (此时没有会话).
+    Exit {
+        session_id: Option<String>,
+        pid: Option<u32>,
+        #[serde(flatten)]
+        command: CommandRecord,
+        duration_ms: u128,
+        exit: ExitRecord,
+        /// 写入子进程标准输入的字节数.
+        bytes_in: u64,
+        /// 子进程 stdout 和 stderr 的输出字节数.
+        bytes_out: u64,
+        kill_reason: Option<KillReason>,
+    },
+    /// 请求被拒绝, 执行请求在读取到请求内容之前被拒绝时没有 `command`.
+    Denied {
+        /// 被拒绝的接口, 如 `execute`, `upload`, `terminate_session`.
+        rpc: &'static str,
+        /// 请求访问的文件路径或会话 ID.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        target: Option<String>,
+        #[serde(flatten)]
+        command: Option<CommandRecord>,
+        code: String,
+        error: String,
+    },
+}
+
+impl AuditEvent {
+    /// 被拒绝的执行请求.
+    pub fn denied(command: Option<&Command>, status: &Status) -> AuditEvent {
+        AuditEvent::Denied {
+            rpc: "execute",
+            target: None,
+            command: command.map(CommandRecord::from),
+            code: format!("{:?}", status.code()),
+            error: status.message().to_string(),
+        }
+    }
+
+    /// 被拒绝的文件传输或会话管理请求, `target` 是请求访问的文件路径或会话 ID.
+    pub fn denied_rpc(rpc: &'static str, target: Option<String>, status: &Status) -> AuditEvent {
+        AuditEvent::Denied {
+            rpc,
+            target,
+            command: None,
+            code: format!("{:?}", status.code()),
+            error: status.message().to_string(),
+        }
+    }
+
+    fn command_mut(&mut self) -> Option<&mut CommandRecord> {
+        match self {
+            AuditEvent::Start { command, .. } | AuditEvent::Exit { command, .. } => Some(command),
+            AuditEvent::Denied { command, .. } => command.as_mut(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    timestamp: String,
+    identity: &'a str,
+    peer_address: &'a str,
+    #[serde(flatten)]
+    event: &'a AuditEvent,
+}
+
+struct AuditFile {
+    file: File,
+    size: u64,
+}
+
+pub struct AuditLog {
+    path: PathBuf,
+    /// 日志文件的大小上限 (字节), 写入后会超过上限时先轮转.
+    max_size: u64,
+    /// 轮转后保留的旧文件数量, 为 0 时直接清空日志文件.
+    max_files: usize,
+    /// 是否记录被拒绝的请求.
+    log_denied: bool,
+    /// 是否记录客户端设置的环境变量的值, 否则只记录名称.
+    log_env_values: bool,
+    file: Mutex<AuditFile>,
+}
+
+/// 打开日志文件, 新建的文件只有所有者可以读写, 轮转后的文件由它重命名而来, 权限相同.
+fn open_append(path: &Path) -> io::Result<AuditFile> {
+    let mut options = OpenOptions::new();
+    options.create(true).append(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let file = options.open(path)?;
+    let size = file.metadata()?.len();
+    Ok(AuditFile { file, size })
+}
+
+#[bon::bon]
+impl AuditLog {
+    #[builder]
+    pub fn new(
+        #[builder(into)] path: PathBuf,
+        max_size: u64,
+        max_files: usize,
+        #[builder(default)] log_denied: bool,
+        #[builder(default)] log_env_values: bool,
+    ) -> io::Result<AuditLog> {
+        let file = open_append(&path)?;
+        Ok(AuditLog {
+            path,
+            max_size,
+            max_files,
+            log_denied,
+            log_env_values,
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{index}"));
+        path.into()
+    }
+
+    fn rotate(&self) -> io::Result<AuditFile> {
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for index in (1..self.max_files).rev() {
+                let from = self.rotated_path(index);
+                if from.exists() {
+                    fs::rename(from, self.rotated_path(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        open_append(&self.path)
+    }
+
+    fn write(&self, line: &[u8]) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        if file.size > 0 && file.size + line.len() as u64 > self.max_size {
+            *file = self.rotate()?;
+        }
+        file.file.write_all(line)?;
+        file.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// 写入一条记录, 写入失败只会输出警告, 不影响请求的处理.
+    pub fn record(&self, peer: &Peer, event: &AuditEvent) {
+        if matches!(event, AuditEvent::Denied { .. }) && !self.log_denied {
+            return;
+        }
+        let mut redacted;
+        let event = if self.log_env_values {
+            event
+        } else {
+            redacted = event.clone();
+            if let Some(command) = redacted.command_mut() {
+                command.env.values_mut().for_each(|value| *value = None);
+            }
+            &redacted
+        };
+        let record = Record {
+            timestamp: OffsetDateTime::now_utc()
+                .format(&Rfc3339)
+                .unwrap_or_default(),
+            identity: peer.identity_name(),
+            peer_address: &peer.address,
+            event,
+        };
+        let mut line = serde_json::to_vec(&record).expect("audit record is serializable");
+        line.push(b'\n');
+        if let Err(e) = self.write(&line) {
+            warn!("failed to write audit log {}: {e}", self.path.display());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{env, fs};
+
+    use rand::Rng as _;
+
+    use super::{AuditEvent, AuditLog};
+    use crate::server::auth::Peer;
+
+    #[test]
+    fn rotate() {
+        let dir = env::temp_dir().join(format!("rex-audit-{:08x}", rand::rng().random::<u32>()));
+        fs::create_dir(&dir).unwrap();
+        let path = dir.join("audit.log");
+        let audit = AuditLog::builder()
+            .path(&path)
+            .max_size(300)
+            .max_files(2)
+            .log_denied(true)
+            .build()
+            .unwrap();
+        let event = AuditEvent::denied(None, &tonic::Status::permission_denied("denied"));
+        for _ in 0..20 {
+            audit.record(&Peer::default(), &event);
+        }
+        let mut files: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+        assert_eq!(files, ["audit.log", "audit.log.1", "audit.log.2"]);
+        for file in files {
+            let content = fs::read_to_string(dir.join(file)).unwrap();
+            assert!(content.len() <= 300);
+            for line in content.lines() {
+                let record: serde_json::Value = serde_json::from_str(line).unwrap();
+                assert_eq!(record["event"], "denied");
+            }
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,285 @@
+//! 根据客户端证书中的身份 (subject CN 和 SAN) 为客户端分配角色, 限制其可以使用的功能.
+
+use std::{collections::HashMap, path::Path, sync::Arc};
+
+use globset::GlobSet;
+use tonic::{Request, Status};
+use x509_parser::{certificate::X509Certificate, extensions::GeneralName, prelude::FromDer as _};
+
+use crate::server::policy::path_glob_set;
+
+/// 发起请求的客户端.
+#[derive(Debug, Clone, Default)]
+pub struct Peer {
+    pub address: String,
+    pub identity: Option<PeerIdentity>,
+}
+
+impl Peer {
+    pub fn from_request<T>(req: &Request<T>) -> Peer {
+        Peer {
+            address: req
+                .remote_addr()
+                .map(|addr| addr.to_string())
+                .or_else(|| unix_peer(req))
+                .unwrap_or_default(),
+            identity: PeerIdentity::from_request(req),
+        }
+    }
+
+    /// 客户端身份的显示名称, 没有身份时为空.
+    pub fn identity_name(&self) -> &str {
+        self.identity.as_ref().map_or("", PeerIdentity::name)
+    }
+}
+
+/// 通过 Unix 域套接字连接的客户端的 UID 和 PID.
+#[cfg(unix)]
+fn unix_peer<T>(req: &Request<T>) -> Option<String> {
+    use tonic::transport::server::{TlsConnectInfo, UdsConnectInfo};
+
+    let info = req
+        .extensions()
+        .get::<TlsConnectInfo<UdsConnectInfo>>()
+        .map(TlsConnectInfo::get_ref)
+        .or_else(|| req.extensions().get::<UdsConnectInfo>())?;
+    let cred = info.peer_cred?;
+    Some(match cred.pid() {
+        Some(pid) => format!("unix:uid={},pid={pid}", cred.uid()),
+        None => format!("unix:uid={}", cred.uid()),
+    })
+}
+
+#[cfg(not(unix))]
+fn unix_peer<T>(_req: &Request<T>) -> Option<String> {
+    None
+}
+
+/// 通过 Unix 域套接字连接的客户端的证书.
+#[cfg(unix)]
+fn unix_peer_certs<T>(
+    req: &Request<T>,
+) -> Option<Arc<Vec<tonic::transport::CertificateDer<'static>>>> {
+    use tonic::transport::server::{TlsConnectInfo, UdsConnectInfo};
+
+    req.extensions()
+        .get::<TlsConnectInfo<UdsConnectInfo>>()?
+        .peer_certs()
+}
+
+#[cfg(not(unix))]
+fn unix_peer_certs<T>(
+    _req: &Request<T>,
+) -> Option<Arc<Vec<tonic::transport::CertificateDer<'static>>>> {
+    None
+}
+
+/// 客户端证书中的身份, 两个身份的完整 subject 和所有名称都相同时才视为同一个客户端.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PeerIdentity {
+    /// 完整的 subject, 如 `CN=alice, O=Rex Test Org`.
+    subject: String,
+    /// 可以用来匹配角色的名称, subject CN 在前, 之后是 SAN 中的 DNS 名称, 邮箱和 URI.
+    names: Vec<String>,
+}
+
+impl PeerIdentity {
+    pub fn new(subject: String, names: Vec<String>) -> PeerIdentity {
+        PeerIdentity { subject, names }
+    }
+
+    /// 解析 DER 格式的证书, 证书无法解析时返回 [`None`].
+    pub fn from_der(der: &[u8]) -> Option<PeerIdentity> {
+        let (_, cert) = X509Certificate::from_der(der).ok()?;
+        let mut names: Vec<String> = cert
+            .subject()
+            .iter_common_name()
+            .filter_map(|cn| cn.as_str().ok())
+            .map(ToString::to_string)
+            .collect();
+        if let Ok(Some(san)) = cert.subject_alternative_name() {
+            for name in &san.value.general_names {
+                match name {
+                    GeneralName::DNSName(name)
+                    | GeneralName::RFC822Name(name)
+                    | GeneralName::URI(name) => names.push((*name).to_string()),
+                    _ => {}
+                }
+            }
+        }
+        Some(PeerIdentity {
+            subject: cert.subject().to_string(),
+            names,
+        })
+    }
+
+    /// 请求的客户端证书中的身份, 没有使用 TLS 时返回 [`None`].
+    pub fn from_request<T>(req: &Request<T>) -> Option<PeerIdentity> {
+        // tonic 只从 TCP 连接中取出客户端证书.
+        let certs = req.peer_certs().or_else(|| unix_peer_certs(req))?;
+        PeerIdentity::from_der(certs.first()?)
+    }
+
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    /// 用于显示的名称.
+    pub fn name(&self) -> &str {
+        self.names.first().map_or("", String::as_str)
+    }
+}
+
+/// 客户端可以使用的功能.
+#[derive(Debug, Clone)]
+pub struct Role {
+    name: String,
+    /// 可以执行的程序, 为 [`None`] 时不限制.
+    executables: Option<GlobSet>,
+    /// 可以作为工作目录和文件传输路径的目录, 为 [`None`] 时不限制.
+    directories: Option<GlobSet>,
+    /// 是否可以查看和终止其他客户端的会话, 客户端总是可以管理自己启动的会话.
+    sessions: bool,
+    /// 是否可以上传, 下载和同步文件.
+    files: bool,
+}
+
+#[bon::bon]
+impl Role {
+    #[builder]
+    pub fn new(
+        #[builder(into)] name: String,
+        #[builder(default)] executables: Vec<String>,
+        #[builder(default)] directories: Vec<String>,
+        #[builder(default)] sessions: bool,
+        #[builder(default)] files: bool,
+    ) -> Result<Role, globset::Error> {
+        Ok(Role {
+            name,
+            executables: path_glob_set(&executables)?,
+            directories: path_glob_set(&directories)?,
+            sessions,
+            files,
+        })
+    }
+
+    /// 没有配置角色时所有客户端使用的角色.
+    fn unrestricted() -> Role {
+        Role {
+            name: String::new(),
+            executables: None,
+            directories: None,
+            sessions: true,
+            files: true,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn denied(&self, what: impl std::fmt::Display) -> Status {
+        Status::permission_denied(format!("role `{}` may not {what}", self.name))
+    }
+
+    pub fn check_exec(&self, executable: &Path, current_dir: &Path) -> Result<(), Status> {
+        if self
+            .executables
+            .as_ref()
+            .is_some_and(|it| !it.is_match(executable))
+        {
+            return Err(self.denied(format_args!("execute `{}`", executable.display())));
+        }
+        if self
+            .directories
+            .as_ref()
+            .is_some_and(|it| !it.is_match(current_dir))
+        {
+            return Err(self.denied(format_args!("run in `{}`", current_dir.display())));
+        }
+        Ok(())
+    }
+
+    /// 检查文件传输是否可以访问 `path`, `path` 应该已经由 [`crate::server::FileRoot`] 解析.
+    pub fn check_file(&self, path: &Path) -> Result<(), Status> {
+        if !self.files {
+            return Err(self.denied("transfer files"));
+        }
+        if self
+            .directories
+            .as_ref()
+            .is_some_and(|it| !it.is_match(path))
+        {
+            return Err(self.denied(format_args!("access `{}`", path.display())));
+        }
+        Ok(())
+    }
+
+    /// 是否可以管理由 `owner` 启动的会话, 只比较 CN 时不同的证书可能冒充会话的所有者.
+    pub fn can_manage(
+        &self,
+        identity: Option<&PeerIdentity>,
+        owner: Option<&PeerIdentity>,
+    ) -> bool {
+        self.sessions || identity == owner
+    }
+}
+
+/// 身份到角色的映射, 没有配置任何映射时所有客户端都不受限制.
+#[derive(Debug, Clone, Default)]
+pub struct AuthPolicy {
+    /// 身份名称到角色名称.
+    clients: HashMap<String, String>,
+    roles: HashMap<String, Arc<Role>>,
+    /// 身份没有匹配任何映射时使用的角色, 为 [`None`] 时拒绝这样的客户端.
+    default_role: Option<String>,
+}
+
+impl AuthPolicy {
+    /// `clients` 和 `default_role` 中的角色必须都在 `roles` 中.
+    pub fn new(
+        clients: HashMap<String, String>,
+        roles: impl IntoIterator<Item = Role>,
+        default_role: Option<String>,
+    ) -> Result<AuthPolicy, String> {
+        let roles: HashMap<_, _> = roles
+            .into_iter()
+            .map(|role| (role.name.clone(), Arc::new(role)))
+            .collect();
+        if let Some(role) = clients
+            .values()
+            .chain(&default_role)
+            .find(|role| !roles.contains_key(*role))
+        {
+            return Err(format!("role `{role}` is not defined"));
+        }
+        Ok(AuthPolicy {
+            clients,
+            roles,
+            default_role,
+        })
+    }
+
+    fn enabled(&self) -> bool {
+        !self.clients.is_empty() || self.default_role.is_some()
+    }
+
+    /// 找到客户端的角色, 按证书中名称的顺序匹配.
+    pub fn authorize(&self, identity: Option<&PeerIdentity>) -> Result<Arc<Role>, Status> {
+        if !self.enabled() {
+            return Ok(Arc::new(Role::unrestricted()));
+        }
+        let role = identity
+            .into_iter()
+            .flat_map(PeerIdentity::names)
+            .find_map(|name| self.clients.get(name))
+            .or(self.default_role.as_ref());
+        match role {
+            Some(role) => Ok(self.roles[role].clone()),
+            None => Err(Status::permission_denied(format!(
+                "client `{}` is not authorized",
+                identity.map_or("", PeerIdentity::name)
+            ))),
+        }
+    }
+}
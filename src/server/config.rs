@@ -0,0 +1,610 @@
+//! 服务端配置文件 (默认为配置目录下的 `server.toml`) 的加载, 校验和热重载.
+//!
+//! 命令行参数会覆盖配置文件中的对应项. 服务端收到 `SIGHUP` 或发现配置文件被修改时会重新加载配置,
+//! 新的执行策略只影响之后的请求, 已经存在的会话不受影响.
+
+use std::{
+    collections::HashMap,
+    fs::OpenOptions,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use serde::Deserialize;
+use tokio::fs;
+use tracing::{error, info, level_filters::LevelFilter, warn};
+use tracing_subscriber::{
+    Registry, layer::SubscriberExt as _, reload, util::SubscriberInitExt as _,
+};
+
+#[cfg(unix)]
+use crate::server::listener::UnixSocketOptions;
+use crate::{
+    ConfigError, DEFAULT_PORT, Error,
+    args::ServerArgs,
+    exec::ResourceLimits,
+    load_config,
+    server::{
+        EnvPolicy, Executor, ExecutorSettings, FileRoot, SessionEnv, SessionEnvSource,
+        audit::AuditLog,
+        auth::{AuthPolicy, Role},
+        listener::BindAddress,
+        policy::{Action, ExecPolicy, ExecRule},
+        resources::{self, CgroupRoot},
+    },
+};
+
+/// 检查配置文件是否被修改的间隔.
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+fn invalid(key: &'static str, message: impl ToString) -> ConfigError {
+    ConfigError::Invalid {
+        key,
+        message: message.to_string(),
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// 监听的地址, 为空时使用 `[::1]:DEFAULT_PORT`.
+    pub bind: Vec<BindAddress>,
+    /// 服务端证书和 CA 证书所在的目录, 为空时使用配置目录.
+    pub cert_dir: Option<PathBuf>,
+    pub log: LogConfig,
+    pub exec: ExecConfig,
+    pub files: FilesConfig,
+    pub limits: LimitsConfig,
+    pub auth: AuthConfig,
+    pub audit: AuditConfig,
+    pub shutdown: ShutdownConfig,
+    pub unix: UnixSocketConfig,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// `trace`, `debug`, `info`, `warn`, `error` 或 `off`, 为空时 debug 构建使用 `debug`, 否则使用 `info`.
+    pub level: Option<String>,
+    /// 追加写入日志的文件, 为空时输出到标准输出.
+    pub file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExecConfig {
+    /// 没有规则匹配时的动作.
+    pub default_action: Action,
+    /// 执行规则, 按顺序匹配, 见 [`ExecRule`].
+    pub rules: Vec<ExecRuleConfig>,
+    /// 已弃用, 相当于 `default_action = "deny"`, 并在 `rules` 末尾加一条允许这些程序的规则.
+    pub allowed_executables: Vec<String>,
+    /// 客户端没有指定工作目录时使用的目录, 必须是绝对路径.
+    pub default_cwd: Option<PathBuf>,
+    /// 不允许客户端设置或移除的环境变量, 与 `--protected-env` 合并.
+    pub protected_env: Vec<String>,
+    pub session_env: SessionEnvConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExecRuleConfig {
+    /// 拒绝时返回给客户端的规则名称, 为空时使用 `rules[序号]`.
+    pub name: Option<String>,
+    pub action: Action,
+    #[serde(default)]
+    pub executable: Vec<String>,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub current_dir: Vec<String>,
+}
+
+/// 桌面会话环境变量的来源, 写作 `"snapshot"`, `"systemd"` 或 `{ file = "..." }`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionEnvConfig {
+    #[default]
+    Snapshot,
+    Systemd,
+    File(PathBuf),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FilesConfig {
+    /// 文件传输可以访问的目录, 见 `--file-root`.
+    pub root: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// 同时存在的会话数上限.
+    pub max_sessions: Option<usize>,
+    /// 同时运行的子进程数上限.
+    pub max_running: Option<usize>,
+    /// 每个客户端 (按证书中的身份) 同时运行的子进程数上限.
+    pub max_running_per_client: Option<usize>,
+    /// 达到运行数上限时请求排队等待的最长时间 (秒), 为空时直接拒绝请求.
+    pub max_queue_wait: Option<u64>,
+    /// 子进程运行时间的上限 (秒).
+    pub max_timeout: Option<u64>,
+    /// 子进程没有输入输出的时间的上限 (秒).
+    pub max_idle_timeout: Option<u64>,
+    /// 子进程资源限制的上限 (仅 Linux), 客户端没有请求的项也会使用.
+    pub resources: ResourceLimits,
+    /// 服务端被委派的 cgroup v2 目录, 内存和 CPU 使用率的限制需要它.
+    pub cgroup: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// 用于验证客户端证书的 CA 证书, 为空时使用证书目录下的 CA 证书.
+    pub client_ca: Option<PathBuf>,
+    /// TLS 握手的超时时间 (秒).
+    pub handshake_timeout: u64,
+    /// 客户端证书中的身份 (subject CN 或 SAN) 到角色名称的映射, 为空时所有客户端都不受限制.
+    pub clients: HashMap<String, String>,
+    /// 身份没有匹配 `clients` 时使用的角色, 为空时拒绝这样的客户端.
+    pub default_role: Option<String>,
+    pub roles: HashMap<String, RoleConfig>,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            client_ca: None,
+            handshake_timeout: 1,
+            clients: HashMap::new(),
+            default_role: None,
+            roles: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    /// 审计日志文件, 为空时不记录审计日志.
+    pub file: Option<PathBuf>,
+    /// 日志文件超过该大小 (字节) 时轮转.
+    pub max_size: u64,
+    /// 轮转后保留的旧日志文件数量.
+    pub max_files: usize,
+    /// 是否同时记录被拒绝的请求.
+    pub log_denied: bool,
+    /// 是否记录客户端设置的环境变量的值, 默认只记录名称.
+    pub log_env_values: bool,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        AuditConfig {
+            file: None,
+            max_size: 10 * 1024 * 1024,
+            max_files: 5,
+            log_denied: false,
+            log_env_values: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig {
+    /// 服务端关闭时等待 `leak = false` 的子进程退出的时间 (秒), 之后终止它们.
+    pub grace_period: u64,
+    /// 保存关闭时仍在运行的 `leak = true` 的子进程的文件, 为空时使用配置目录下的文件.
+    pub leaked_file: Option<PathBuf>,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig {
+            grace_period: 10,
+            leaked_file: None,
+        }
+    }
+}
+
+/// `unix:` 监听地址的套接字文件设置.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UnixSocketConfig {
+    /// 套接字文件的权限位, 如 `0o660`.
+    pub mode: u32,
+    /// 套接字文件的所有者, 用户名或 UID, 为空时不修改.
+    pub owner: Option<String>,
+    /// 套接字文件的所属组, 组名或 GID, 为空时不修改.
+    pub group: Option<String>,
+    /// 是否只允许与服务端相同 UID 的进程连接.
+    pub same_uid: bool,
+}
+
+impl Default for UnixSocketConfig {
+    fn default() -> Self {
+        UnixSocketConfig {
+            mode: 0o600,
+            owner: None,
+            group: None,
+            same_uid: false,
+        }
+    }
+}
+
+/// 角色可以使用的功能, 见 [`Role`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoleConfig {
+    /// 可以执行的程序, 为空时不限制.
+    pub executables: Vec<String>,
+    /// 可以作为工作目录和文件传输路径的目录, 为空时不限制.
+    pub directories: Vec<String>,
+    /// 是否可以查看和终止其他客户端的会话.
+    pub sessions: bool,
+    /// 是否可以上传, 下载和同步文件.
+    pub files: bool,
+}
+
+impl LogConfig {
+    pub fn level_filter(&self) -> Result<LevelFilter, ConfigError> {
+        match &self.level {
+            Some(level) => level.parse().map_err(|e| invalid("log.level", e)),
+            None if cfg!(debug_assertions) => Ok(LevelFilter::DEBUG),
+            None => Ok(LevelFilter::INFO),
+        }
+    }
+}
+
+impl AuditConfig {
+    /// 打开审计日志文件, 没有配置文件时返回 [`None`].
+    pub fn audit_log(&self) -> Result<Option<AuditLog>, Error> {
+        let Some(path) = &self.file else {
+            return Ok(None);
+        };
+        let audit = AuditLog::builder()
+            .path(path)
+            .max_size(self.max_size)
+            .max_files(self.max_files)
+            .log_denied(self.log_denied)
+            .log_env_values(self.log_env_values)
+            .build()?;
+        Ok(Some(audit))
+    }
+}
+
+#[cfg(unix)]
+impl UnixSocketConfig {
+    pub fn options(&self) -> Result<UnixSocketOptions, ConfigError> {
+        if self.mode > 0o7777 {
+            return Err(invalid("unix.mode", "must be at most 0o7777"));
+        }
+        let owner = self
+            .owner
+            .as_deref()
+            .map(UnixSocketOptions::parse_owner)
+            .transpose()
+            .map_err(|e| invalid("unix.owner", e))?;
+        let group = self
+            .group
+            .as_deref()
+            .map(UnixSocketOptions::parse_group)
+            .transpose()
+            .map_err(|e| invalid("unix.group", e))?;
+        Ok(UnixSocketOptions {
+            mode: self.mode,
+            owner,
+            group,
+            same_uid: self.same_uid,
+        })
+    }
+}
+
+impl ServerConfig {
+    /// 读取配置文件, `required` 为 `false` 时文件不存在则使用默认配置.
+    pub async fn load(path: &Path, required: bool) -> Result<ServerConfig, ConfigError> {
+        load_config(path, required).await
+    }
+
+    /// 用命令行参数覆盖配置文件中的对应项.
+    #[must_use]
+    pub fn with_args(mut self, args: &ServerArgs) -> ServerConfig {
+        if !args.bind_address.is_empty() {
+            self.bind.clone_from(&args.bind_address);
+        } else if self.bind.is_empty() {
+            self.bind = vec![format!("[::1]:{DEFAULT_PORT}").parse().unwrap()];
+        }
+        if args.cert_dir.is_some() {
+            self.cert_dir.clone_from(&args.cert_dir);
+        }
+        self.exec
+            .protected_env
+            .extend(args.protected_env.iter().cloned());
+        if let Some(path) = &args.session_env_file {
+            self.exec.session_env = SessionEnvConfig::File(path.clone());
+        } else if args.session_env_systemd {
+            self.exec.session_env = SessionEnvConfig::Systemd;
+        }
+        if args.file_root.is_some() {
+            self.files.root.clone_from(&args.file_root);
+        }
+        self
+    }
+
+    /// 检查执行策略之外的配置项, 执行策略由 [`ServerConfig::executor_settings`] 检查.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.bind.is_empty() {
+            return Err(invalid("bind", "at least one address is required"));
+        }
+        if let Some((index, address)) = self
+            .bind
+            .iter()
+            .enumerate()
+            .find(|(index, address)| self.bind[..*index].contains(*address))
+        {
+            return Err(invalid(
+                "bind",
+                format!("address `{address}` is repeated at bind[{index}]"),
+            ));
+        }
+        #[cfg(unix)]
+        self.unix.options()?;
+        #[cfg(not(unix))]
+        if self
+            .bind
+            .iter()
+            .any(|it| matches!(it, BindAddress::Unix(_)))
+        {
+            return Err(invalid(
+                "bind",
+                "unix domain sockets are not supported on this platform",
+            ));
+        }
+        self.log.level_filter()?;
+        if self.auth.handshake_timeout == 0 {
+            return Err(invalid("auth.handshake_timeout", "must be positive"));
+        }
+        if self.audit.max_size == 0 {
+            return Err(invalid("audit.max_size", "must be positive"));
+        }
+        Ok(())
+    }
+
+    pub fn executor_settings(&self) -> Result<ExecutorSettings, ConfigError> {
+        let mut rules = Vec::new();
+        for (index, rule) in self.exec.rules.iter().enumerate() {
+            let rule = ExecRule::builder()
+                .name(
+                    rule.name
+                        .clone()
+                        .unwrap_or_else(|| format!("rules[{index}]")),
+                )
+                .action(rule.action)
+                .executable(rule.executable.clone())
+                .args(rule.args.clone())
+                .current_dir(rule.current_dir.clone())
+                .build()
+                .map_err(|e| invalid("exec.rules", e))?;
+            rules.push(rule);
+        }
+        let mut default_action = self.exec.default_action;
+        if !self.exec.allowed_executables.is_empty() {
+            warn!("exec.allowed_executables is deprecated, use exec.rules instead");
+            let rule = ExecRule::builder()
+                .name("allowed_executables")
+                .action(Action::Allow)
+                .executable(self.exec.allowed_executables.clone())
+                .build()
+                .map_err(|e| invalid("exec.allowed_executables", e))?;
+            rules.push(rule);
+            default_action = Action::Deny;
+        }
+        if let Some(dir) = &self.exec.default_cwd
+            && (dir.is_relative() || !dir.is_dir())
+        {
+            return Err(invalid(
+                "exec.default_cwd",
+                format!("{} is not an absolute directory path", dir.display()),
+            ));
+        }
+        if self.limits.max_sessions == Some(0) {
+            return Err(invalid("limits.max_sessions", "must be positive"));
+        }
+        if self.limits.max_running == Some(0) {
+            return Err(invalid("limits.max_running", "must be positive"));
+        }
+        if self.limits.max_running_per_client == Some(0) {
+            return Err(invalid("limits.max_running_per_client", "must be positive"));
+        }
+        if self.limits.max_queue_wait == Some(0) {
+            return Err(invalid("limits.max_queue_wait", "must be positive"));
+        }
+        if self.limits.max_timeout == Some(0) {
+            return Err(invalid("limits.max_timeout", "must be positive"));
+        }
+        if self.limits.max_idle_timeout == Some(0) {
+            return Err(invalid("limits.max_idle_timeout", "must be positive"));
+        }
+        let max_resources = self.limits.resources;
+        if !cfg!(target_os = "linux")
+            && (!resources::is_empty(&max_resources) || self.limits.cgroup.is_some())
+        {
+            return Err(invalid(
+                "limits.resources",
+                "resource limits are only supported on Linux",
+            ));
+        }
+        if max_resources.cpu_percent == Some(0) {
+            return Err(invalid("limits.resources", "cpu_percent must be positive"));
+        }
+        let cgroup = match &self.limits.cgroup {
+            Some(path) => {
+                Some(CgroupRoot::new(path.clone()).map_err(|e| invalid("limits.cgroup", e))?)
+            }
+            None if resources::needs_cgroup(&max_resources) => {
+                return Err(invalid(
+                    "limits.cgroup",
+                    "required by memory and cpu_percent in limits.resources",
+                ));
+            }
+            None => None,
+        };
+        let file_root =
+            FileRoot::new(self.files.root.clone()).map_err(|e| invalid("files.root", e))?;
+        let session_env_source = match &self.exec.session_env {
+            SessionEnvConfig::Snapshot => SessionEnvSource::Snapshot,
+            SessionEnvConfig::Systemd => SessionEnvSource::Systemd,
+            SessionEnvConfig::File(path) => SessionEnvSource::File(path.clone()),
+        };
+        let mut roles = Vec::new();
+        for (name, role) in &self.auth.roles {
+            let role = Role::builder()
+                .name(name)
+                .executables(role.executables.clone())
+                .directories(role.directories.clone())
+                .sessions(role.sessions)
+                .files(role.files)
+                .build()
+                .map_err(|e| invalid("auth.roles", e))?;
+            roles.push(role);
+        }
+        let auth = AuthPolicy::new(
+            self.auth.clients.clone(),
+            roles,
+            self.auth.default_role.clone(),
+        )
+        .map_err(|e| invalid("auth.clients", e))?;
+        Ok(ExecutorSettings::builder()
+            .env_policy(EnvPolicy::new(&self.exec.protected_env))
+            .session_env(SessionEnv::new(session_env_source))
+            .file_root(file_root)
+            .policy(ExecPolicy::new(rules, default_action))
+            .auth(auth)
+            .maybe_default_cwd(self.exec.default_cwd.clone())
+            .maybe_max_sessions(self.limits.max_sessions)
+            .maybe_max_running(self.limits.max_running)
+            .maybe_max_running_per_client(self.limits.max_running_per_client)
+            .maybe_max_queue_wait(self.limits.max_queue_wait.map(Duration::from_secs))
+            .maybe_max_timeout(self.limits.max_timeout.map(Duration::from_secs))
+            .maybe_max_idle_timeout(self.limits.max_idle_timeout.map(Duration::from_secs))
+            .max_resources(max_resources)
+            .maybe_cgroup(cgroup)
+            .build())
+    }
+
+    /// 加载配置文件, 合并命令行参数并检查所有配置项.
+    pub async fn resolve(
+        path: &Path,
+        required: bool,
+        args: &ServerArgs,
+    ) -> Result<(ServerConfig, ExecutorSettings), ConfigError> {
+        let config = ServerConfig::load(path, required).await?.with_args(args);
+        config.validate()?;
+        let settings = config.executor_settings()?;
+        Ok((config, settings))
+    }
+}
+
+pub type LogLevelHandle = reload::Handle<LevelFilter, Registry>;
+
+/// 初始化日志输出, 返回的 handle 用于重新加载配置时修改日志等级.
+pub fn init_logging(config: &LogConfig) -> Result<LogLevelHandle, Error> {
+    let (filter, handle) = reload::Layer::new(config.level_filter()?);
+    let registry = tracing_subscriber::registry().with(filter);
+    match &config.file {
+        Some(path) => {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            registry
+                .with(
+                    tracing_subscriber::fmt::layer()
+                        .with_ansi(false)
+                        .with_writer(Mutex::new(file)),
+                )
+                .init();
+        }
+        None => registry.with(tracing_subscriber::fmt::layer()).init(),
+    }
+    Ok(handle)
+}
+
+async fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).await.and_then(|it| it.modified()).ok()
+}
+
+/// 在收到 `SIGHUP` 或配置文件被修改时重新加载配置, 新配置有错误时继续使用旧配置.
+///
+/// 监听地址, 证书, TLS 设置, 日志文件, 审计日志和关闭设置的修改需要重启服务端才能生效,
+/// 每次重新加载时都与服务端启动时的配置 `running` 比较.
+pub async fn watch(
+    path: PathBuf,
+    required: bool,
+    args: ServerArgs,
+    running: ServerConfig,
+    executor: Executor,
+    log_level: LogLevelHandle,
+) {
+    #[cfg(unix)]
+    let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(it) => Some(it),
+        Err(e) => {
+            warn!("failed to listen for SIGHUP: {e}");
+            None
+        }
+    };
+    let mut last_modified = modified(&path).await;
+    let mut interval = tokio::time::interval(WATCH_INTERVAL);
+    loop {
+        #[cfg(unix)]
+        let hangup_received = async {
+            match &mut hangup {
+                Some(hangup) => hangup.recv().await,
+                None => std::future::pending().await,
+            }
+        };
+        #[cfg(not(unix))]
+        let hangup_received = std::future::pending::<Option<()>>();
+        tokio::select! {
+            _ = hangup_received => {
+                info!("received SIGHUP, reloading config");
+            }
+            _ = interval.tick() => {
+                let modified = modified(&path).await;
+                if modified == last_modified {
+                    continue;
+                }
+                last_modified = modified;
+                info!("{} changed, reloading config", path.display());
+            }
+        }
+        let (config, settings) = match ServerConfig::resolve(&path, required, &args).await {
+            Ok(it) => it,
+            Err(e) => {
+                error!("keep using the previous config: {e}");
+                continue;
+            }
+        };
+        executor.reload(settings);
+        if let Err(e) = log_level.modify(|level| {
+            *level = config.log.level_filter().expect("validated");
+        }) {
+            warn!("failed to change log level: {e}");
+        }
+        if config.bind != running.bind
+            || config.cert_dir != running.cert_dir
+            || config.auth.client_ca != running.auth.client_ca
+            || config.auth.handshake_timeout != running.auth.handshake_timeout
+            || config.log.file != running.log.file
+            || config.audit != running.audit
+            || config.shutdown != running.shutdown
+            || config.unix != running.unix
+        {
+            warn!(
+                "changes to bind, cert_dir, auth.client_ca, auth.handshake_timeout, log.file, [audit], [shutdown] and [unix] take effect after restart"
+            );
+        }
+        info!("config reloaded");
+    }
+}
@@ -0,0 +1,129 @@
+//! 子进程环境变量相关的服务端策略.
+
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    path::PathBuf,
+};
+
+use tokio::{fs, process};
+use tonic::Status;
+use tracing::warn;
+
+use crate::{exec::Command, parse_env_file};
+
+/// 在桌面会话中启动图形界面程序所依赖的环境变量.
+pub const SESSION_VARS: &[&str] = &[
+    "DISPLAY",
+    "WAYLAND_DISPLAY",
+    "XAUTHORITY",
+    "XDG_RUNTIME_DIR",
+    "DBUS_SESSION_BUS_ADDRESS",
+];
+
+/// 限制客户端对子进程环境变量的修改.
+#[derive(Debug, Clone, Default)]
+pub struct EnvPolicy {
+    /// 不允许客户端设置或移除的环境变量, 客户端清空环境时这些变量会保留服务端的值.
+    protected: HashSet<String>,
+}
+
+/// Windows 上环境变量名不区分大小写.
+fn normalize(name: &str) -> String {
+    if cfg!(windows) {
+        name.to_uppercase()
+    } else {
+        name.to_string()
+    }
+}
+
+impl EnvPolicy {
+    pub fn new(protected: impl IntoIterator<Item = impl AsRef<str>>) -> EnvPolicy {
+        EnvPolicy {
+            protected: protected
+                .into_iter()
+                .map(|name| normalize(name.as_ref()))
+                .collect(),
+        }
+    }
+
+    fn is_protected(&self, name: &str) -> bool {
+        self.protected.contains(&normalize(name))
+    }
+
+    /// 检查 [`Command`] 中的环境变量修改是否被允许, 并在客户端清空环境时补回受保护的变量.
+    pub fn apply(&self, command: &mut Command) -> Result<(), Status> {
+        if let Some(name) = command
+            .env
+            .keys()
+            .chain(&command.env_remove)
+            .find(|name| self.is_protected(name))
+        {
+            return Err(Status::permission_denied(format!(
+                "environment variable `{name}` is protected by server policy"
+            )));
+        }
+        if command.env_clear {
+            command
+                .env
+                .extend(env::vars().filter(|(name, _)| self.is_protected(name)));
+        }
+        Ok(())
+    }
+}
+
+/// 桌面会话环境变量的来源.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionEnvSource {
+    /// 只使用服务端启动时的快照.
+    Snapshot,
+    /// 每次执行时从 `KEY=VALUE` 格式的文件中重新读取.
+    File(PathBuf),
+    /// 每次执行时从 `systemctl --user show-environment` 的输出中重新读取.
+    Systemd,
+}
+
+/// 注入到每个子进程中的桌面会话环境变量, 见 [`SESSION_VARS`].
+///
+/// 即使客户端清空了环境变量, 子进程也能连接到桌面会话.
+#[derive(Debug, Clone)]
+pub struct SessionEnv {
+    snapshot: HashMap<String, String>,
+    source: SessionEnvSource,
+}
+
+fn session_vars(vars: impl IntoIterator<Item = (String, String)>) -> HashMap<String, String> {
+    vars.into_iter()
+        .filter(|(name, _)| SESSION_VARS.contains(&name.as_str()))
+        .collect()
+}
+
+impl SessionEnv {
+    /// 记录当前进程中的会话环境变量作为快照.
+    pub fn new(source: SessionEnvSource) -> SessionEnv {
+        SessionEnv {
+            snapshot: session_vars(env::vars()),
+            source,
+        }
+    }
+
+    /// 获取当前的会话环境变量, 从文件或 systemd 读取的值会覆盖快照中的值,
+    /// 读取失败时只使用快照.
+    pub async fn resolve(&self) -> HashMap<String, String> {
+        let loaded = match &self.source {
+            SessionEnvSource::Snapshot => return self.snapshot.clone(),
+            SessionEnvSource::File(path) => fs::read_to_string(path).await,
+            SessionEnvSource::Systemd => process::Command::new("systemctl")
+                .args(["--user", "show-environment"])
+                .output()
+                .await
+                .map(|output| String::from_utf8_lossy(&output.stdout).into_owned()),
+        };
+        let mut vars = self.snapshot.clone();
+        match loaded {
+            Ok(content) => vars.extend(session_vars(parse_env_file(&content))),
+            Err(e) => warn!("failed to load session environment: {e}"),
+        }
+        vars
+    }
+}
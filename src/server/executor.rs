@@ -1,206 +1,637 @@
 #![warn(clippy::all, clippy::pedantic)]
 
-use std::{path::PathBuf, process::Stdio, time::Duration};
+use std::{
+    collections::HashMap,
+    io,
+    path::PathBuf,
+    pin::pin,
+    process::{ExitStatus, Stdio},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
 
 use crate::exec::{
-    ExecuteRequestChunk, ProgramOutput, StderrChunk, StdoutChunk,
-    execute_request_chunk::RequestChunk, program_output::Payload,
+    Command as ExecCommand, ExitInfo, ProgramOutput, ResourceLimits, Signal, StderrChunk,
+    StdoutChunk, TerminalSize, TimeoutKind, execute_request_chunk::RequestChunk,
+    program_output::Payload,
 };
 use tokio::{
-    io::{AsyncReadExt as _, AsyncWriteExt as _, BufReader},
-    process::{Child, ChildStderr, ChildStdout, Command},
-    sync::mpsc::Sender,
-    task::JoinHandle,
+    io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _, BufReader},
+    process::{Child, Command},
+    sync::mpsc::Receiver,
+    task::{AbortHandle, JoinHandle},
+};
+use tonic::Status;
+use tracing::{debug, warn};
+
+use crate::server::ExecutorSettings;
+use crate::server::audit::{AuditEvent, AuditLog, CommandRecord, KillReason};
+use crate::server::auth::{Peer, Role};
+#[cfg(unix)]
+use crate::server::pty;
+use crate::server::resources::{self, Cgroup};
+use crate::server::session::{
+    Control, OutputSender, Session, SessionMeta, SessionRegistry, SessionReservation,
 };
-use tonic::{Status, Streaming};
-use tracing::debug;
+use crate::server::slots::SlotPermit;
+
+type BoxedStdin = Box<dyn AsyncWrite + Send + Unpin>;
+type BoxedReader = Box<dyn AsyncRead + Send + Unpin>;
+
+/// 启动后的子进程及其标准输入输出.
+struct Spawned {
+    child: Child,
+    stdin: BoxedStdin,
+    stdout: BoxedReader,
+    /// 伪终端模式下 stderr 与 stdout 合并, 为 [`None`].
+    stderr: Option<BoxedReader>,
+}
+
+/// 子进程最近一次输出或收到输入的时间, 用于空闲超时.
+type LastActivity = Arc<Mutex<Instant>>;
+
+/// 子进程退出后, 等待其输出结束的最长时间.
+///
+/// 子进程启动的后台进程 (`leak = true` 或者脱离了进程组) 可能一直持有输出管道,
+/// 超过这个时间后不再转发剩余的输出, 直接报告退出信息.
+const OUTPUT_DRAIN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// 将子进程的输出转发给会话, `wrap` 决定输出的类型, 转发的字节数累加到 `bytes_out`.
+fn spawn_output_transmitter(
+    session: Arc<Session>,
+    reader: BoxedReader,
+    wrap: fn(Vec<u8>) -> Payload,
+    last_activity: LastActivity,
+    bytes_out: Arc<AtomicU64>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut br = BufReader::new(reader);
+        let mut buf = vec![0u8; 1024];
+        while let Ok(read_len) = br.read(&mut buf).await {
+            if read_len == 0 {
+                break;
+            }
+            bytes_out.fetch_add(read_len as u64, Ordering::Relaxed);
+            *last_activity.lock().unwrap() = Instant::now();
+            session.publish(wrap(buf[..read_len].to_vec())).await;
+        }
+        debug!("output transmitter of session {} finished", session.id());
+    })
+}
+
+/// 在 `deadline` 时完成, 为 [`None`] 时永远不会完成.
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// 根据子进程的退出状态生成 [`ExitInfo`].
+fn exit_info(status: ExitStatus, killed_by_request: bool) -> ExitInfo {
+    #[cfg(unix)]
+    let (signal, core_dumped) = {
+        use std::os::unix::process::ExitStatusExt as _;
+        (status.signal(), status.core_dumped())
+    };
+    #[cfg(not(unix))]
+    let (signal, core_dumped) = (None, false);
+    ExitInfo {
+        code: status.code(),
+        signal,
+        core_dumped,
+        killed_by_request,
+        ..Default::default()
+    }
+}
+
+/// 解析信号名或信号编号, 信号名不区分大小写, 可以省略 `SIG` 前缀.
+#[cfg(unix)]
+fn parse_signal(name_or_number: &str) -> Option<nix::sys::signal::Signal> {
+    let name_or_number = name_or_number.trim();
+    if let Ok(number) = name_or_number.parse::<i32>() {
+        return nix::sys::signal::Signal::try_from(number).ok();
+    }
+    let name = name_or_number.to_ascii_uppercase();
+    if name.starts_with("SIG") {
+        name.parse().ok()
+    } else {
+        format!("SIG{name}").parse().ok()
+    }
+}
+
+/// 终止子进程所在的整个进程组, 包括子进程启动的后台进程.
+///
+/// 设置了 `grace_period` 时先发送 `SIGTERM`, 子进程在这段时间内没有退出再发送 `SIGKILL`.
+/// `pid` 需要在子进程被回收前获取, 回收后 [`Child::id`] 会返回 [`None`].
+#[cfg(unix)]
+async fn kill_tree(child: &mut Child, pid: Option<u32>, grace_period: Option<Duration>) {
+    use nix::{
+        sys::signal::{Signal as NixSignal, killpg},
+        unistd::Pid,
+    };
+
+    let Some(pid) = pid else {
+        debug!("kill sub process: {:?}", child.kill().await);
+        return;
+    };
+    // 子进程启动时成为了进程组组长, 进程组 ID 与其 pid 相同.
+    let group = Pid::from_raw(pid.cast_signed());
+    if let Some(grace_period) = grace_period
+        && killpg(group, NixSignal::SIGTERM).is_ok()
+    {
+        let exited = tokio::time::timeout(grace_period, child.wait()).await;
+        debug!("sub process exited after SIGTERM: {exited:?}");
+    }
+    // 子进程已经退出时, 进程组中可能还有其他进程.
+    if let Err(e) = killpg(group, NixSignal::SIGKILL) {
+        debug!("kill process group {group}: {e}");
+    }
+    child.wait().await.ok();
+}
+
+/// 非 Unix 平台没有进程组, 只能终止子进程本身.
+#[cfg(not(unix))]
+async fn kill_tree(child: &mut Child, _pid: Option<u32>, _grace_period: Option<Duration>) {
+    debug!("kill sub process: {:?}", child.kill().await);
+}
+
+/// 向子进程 (或其所在的进程组) 发送信号, 子进程已经退出时什么也不做.
+#[cfg(unix)]
+fn send_signal(child: &mut Child, signal: &Signal) -> Result<(), String> {
+    use nix::{
+        sys::signal::{kill, killpg},
+        unistd::{Pid, getpgid},
+    };
+
+    let sig = parse_signal(&signal.name_or_number)
+        .ok_or_else(|| format!("invalid signal: {}", signal.name_or_number))?;
+    let Some(pid) = child.id() else {
+        return Ok(());
+    };
+    let pid = Pid::from_raw(pid.cast_signed());
+    // 只有子进程是进程组组长时才发送给进程组, 避免把信号发给服务端自己.
+    let result = if signal.process_group && getpgid(Some(pid)) == Ok(pid) {
+        killpg(pid, sig)
+    } else {
+        kill(pid, sig)
+    };
+    result.map_err(|e| e.to_string())
+}
+
+/// 非 Unix 平台没有信号机制, 终止类的信号都会强制结束子进程, 其他信号不受支持.
+#[cfg(not(unix))]
+fn send_signal(child: &mut Child, signal: &Signal) -> Result<(), String> {
+    let name = signal.name_or_number.trim().to_ascii_uppercase();
+    match name.trim_start_matches("SIG") {
+        "HUP" | "1" | "INT" | "2" | "QUIT" | "3" | "KILL" | "9" | "TERM" | "15" => {
+            child.start_kill().map_err(|e| e.to_string())
+        }
+        _ => Err(format!("unsupported signal: {}", signal.name_or_number)),
+    }
+}
+
+/// 会话任务中的子进程, 以及生成退出信息和审计日志所需的状态.
+struct Running {
+    child: Child,
+    /// 需要在子进程被回收前获取, 见 [`kill_tree`].
+    pid: Option<u32>,
+    stdin: Option<BoxedStdin>,
+    killed_by_request: bool,
+    timeout_kind: Option<TimeoutKind>,
+    kill_reason: Option<KillReason>,
+    /// 写入子进程标准输入的字节数.
+    bytes_in: u64,
+}
+
+impl Running {
+    fn exit_info(&self, status: io::Result<ExitStatus>) -> ExitInfo {
+        let mut exit_info = match status {
+            Ok(status) => exit_info(status, self.killed_by_request),
+            Err(e) => {
+                warn!("failed to wait child: {e}");
+                ExitInfo {
+                    killed_by_request: self.killed_by_request,
+                    ..Default::default()
+                }
+            }
+        };
+        if let Some(timeout_kind) = self.timeout_kind {
+            exit_info.timed_out = true;
+            exit_info.set_timeout_kind(timeout_kind);
+        }
+        exit_info
+    }
+}
+
+/// 客户端请求的超时 (秒) 不能超过服务端的上限, 客户端没有请求时使用上限.
+fn limit_timeout(requested: Option<u32>, max: Option<Duration>) -> Option<Duration> {
+    let requested = requested.map(|secs| Duration::from_secs(secs.into()));
+    match (requested, max) {
+        (Some(requested), Some(max)) => Some(requested.min(max)),
+        (requested, max) => requested.or(max),
+    }
+}
 
 pub struct ProgramCaller {
+    /// 程序的真实路径.
     executable: PathBuf,
+    /// 子进程的 argv[0], 即在 `PATH` 中找到的, 解析符号链接之前的路径.
+    #[cfg(unix)]
+    arg0: PathBuf,
     current_dir: PathBuf,
     args: Vec<String>,
     leak: bool,
-    output_sender: Sender<Result<ProgramOutput, Status>>,
-    request_stream: Streaming<ExecuteRequestChunk>,
+    pty: bool,
+    /// 终止子进程时 `SIGTERM` 和 `SIGKILL` 之间的等待时间, 见 [`kill_tree`].
+    kill_grace_period: Option<Duration>,
+    /// 子进程运行时间的上限.
+    timeout: Option<Duration>,
+    /// 子进程没有输入输出的时间的上限.
+    idle_timeout: Option<Duration>,
+    last_activity: LastActivity,
+    /// 已经转发给会话的输出字节数.
+    bytes_out: Arc<AtomicU64>,
+    limits: ResourceLimits,
+    /// 子进程所在的 cgroup, 在会话任务结束时删除.
+    cgroup: Option<Cgroup>,
+    /// 桌面会话环境变量, 在清空环境之后, 客户端的修改之前设置.
+    session_env: HashMap<String, String>,
+    env: HashMap<String, String>,
+    env_remove: Vec<String>,
+    env_clear: bool,
+    /// 伪终端的初始窗口大小.
+    terminal_size: Option<TerminalSize>,
+    /// 伪终端模式下子进程所在终端的主设备, 用于调整窗口大小.
+    #[cfg(unix)]
+    pty_master: Option<pty::PtyMaster>,
+    /// 写入审计日志的执行请求内容.
+    record: CommandRecord,
+    /// 审计日志和发起执行的客户端, 在 [`ProgramCaller::call_program`] 中设置.
+    audit: Option<(Arc<AuditLog>, Peer)>,
+    /// 子进程占用的空位, 在会话任务结束时释放, 在 [`ProgramCaller::call_program`] 中设置.
+    permit: Option<SlotPermit>,
 }
 
 impl ProgramCaller {
-    fn spawn_stdout_transmitter(&self, stdout: ChildStdout) -> JoinHandle<()> {
-        let tx = self.output_sender.clone();
-        let handle = tokio::spawn(async move {
-            let mut br = BufReader::new(stdout);
-            let mut buf = vec![0u8; 1024];
-            while let Ok(read_len) = br.read(&mut buf).await {
-                if read_len == 0 {
-                    break;
-                }
-                if tx
-                    .send(Ok(ProgramOutput {
-                        payload: Some(Payload::StdoutChunk(StdoutChunk {
-                            data: buf[..read_len].to_vec(),
-                        })),
-                    }))
-                    .await
-                    .is_err()
-                {
-                    debug!("stdout closed");
-                    break;
-                }
+    /// 会话任务: 将控制消息中的输入写入 `stdin`, 处理其他控制消息,
+    /// 并在子进程结束, 且 `transmitters` 转发完所有输出 (最多再等待 [`OUTPUT_DRAIN_TIMEOUT`]) 后报告退出信息.
+    async fn run(
+        self,
+        child: Child,
+        stdin: BoxedStdin,
+        transmitters: Vec<JoinHandle<()>>,
+        session: Arc<Session>,
+        mut control_rx: Receiver<Control>,
+        sessions: Arc<SessionRegistry>,
+    ) {
+        let start_time = Instant::now();
+        let deadline = self.timeout.map(|timeout| start_time + timeout);
+        let mut running = Running {
+            pid: child.id(),
+            child,
+            stdin: Some(stdin),
+            killed_by_request: false,
+            timeout_kind: None,
+            kill_reason: None,
+            bytes_in: 0,
+        };
+        let mut control_open = true;
+        let mut status = None;
+        let mut drain_deadline = None;
+        let mut output_done = false;
+        let aborts: Vec<_> = transmitters.iter().map(JoinHandle::abort_handle).collect();
+        let mut output = pin!(async move {
+            for transmitter in transmitters {
+                transmitter.await.ok();
             }
         });
-        debug!("stdout transmitter spawned");
-        handle
-    }
-
-    fn spawn_stderr_transmitter(&self, stderr: ChildStderr) -> JoinHandle<()> {
-        let tx = self.output_sender.clone();
-        let handle = tokio::spawn(async move {
-            let mut br = BufReader::new(stderr);
-            let mut buf = vec![0u8; 1024];
-            while let Ok(read_len) = br.read(&mut buf).await {
-                if read_len == 0 {
-                    break;
+        while status.is_none() || !output_done {
+            let timing = status.is_none() && running.timeout_kind.is_none();
+            tokio::select! {
+                result = running.child.wait(), if status.is_none() => {
+                    debug!("sub process exited: {result:?}");
+                    status = Some(result);
+                    drain_deadline = Some(Instant::now() + OUTPUT_DRAIN_TIMEOUT);
+                    if !self.leak {
+                        // 子进程自己退出后, 它启动的后台进程也不能留下, 否则输出不会结束.
+                        kill_tree(&mut running.child, running.pid, None).await;
+                    }
                 }
-                if tx
-                    .send(Ok(ProgramOutput {
-                        payload: Some(Payload::StderrChunk(StderrChunk {
-                            data: buf[..read_len].to_vec(),
-                        })),
-                    }))
-                    .await
-                    .is_err()
-                {
-                    break;
+                () = &mut output, if !output_done => output_done = true,
+                () = sleep_until(drain_deadline), if !output_done => {
+                    debug!("output of session {} is still open, stop waiting", session.id());
+                    aborts.iter().for_each(AbortHandle::abort);
+                    output_done = true;
+                }
+                () = sleep_until(deadline), if timing => {
+                    debug!("session {} timed out", session.id());
+                    self.kill(&mut running, KillReason::Timeout).await;
+                    running.timeout_kind = Some(TimeoutKind::Deadline);
+                }
+                () = sleep_until(self.idle_deadline()), if timing => {
+                    // 等待期间可能有新的输入输出, 此时重新计算截止时间.
+                    if self.idle_deadline().is_some_and(|it| it <= Instant::now()) {
+                        debug!("session {} idle timed out", session.id());
+                        self.kill(&mut running, KillReason::IdleTimeout).await;
+                        running.timeout_kind = Some(TimeoutKind::Idle);
+                    }
+                }
+                control = control_rx.recv(), if control_open => match control {
+                    Some(Control::Request(request_chunk)) => {
+                        self.handle_request(request_chunk, &mut running).await;
+                    }
+                    Some(Control::Disconnected) => {
+                        self.kill(&mut running, KillReason::Disconnect).await;
+                    }
+                    Some(Control::Shutdown) => {
+                        self.kill(&mut running, KillReason::Shutdown).await;
+                    }
+                    None => control_open = false,
                 }
             }
+        }
+
+        let mut exit_info = running.exit_info(status.expect("loop ends after the child exited"));
+        exit_info.limit_exceeded = self.limit_exceeded(&exit_info);
+        self.write_audit(&AuditEvent::Exit {
+            session_id: Some(session.id().to_string()),
+            pid: running.pid,
+            command: self.record.clone(),
+            duration_ms: start_time.elapsed().as_millis(),
+            exit: (&exit_info).into(),
+            bytes_in: running.bytes_in,
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            kill_reason: running.kill_reason,
         });
+        if session.finish(exit_info).await {
+            sessions.remove(session.id());
+        } else {
+            debug!("session {} exited while detached", session.id());
+        }
+    }
 
-        debug!("stderr transmitter spawned");
-        handle
-    }
-
-    /// [`child`] 的 [`ChildStdin`] 不能为 [`None`], 否则 panic.
-    async fn transmit_stdin(&mut self, mut child: Child) -> Result<Child, Status> {
-        let mut stdin = child.stdin.take().unwrap();
-        while let Ok(request_chunk) = tokio::select! {
-           msg = self.request_stream.message() => msg,
-           () = tokio::time::sleep(Duration::from_secs_f32(1.0)) => Ok(None),
-        } {
-            // debug!("nihc"); // todo 解决疯狂循环(间隔很短, 没有预想的 1 秒)的问题.
-            let Some(ExecuteRequestChunk {
-                request_chunk: Some(request_chunk),
-            }) = request_chunk
-            else {
-                // 每隔一段时间检查一下子进程是否已经退出.
-                match child.try_wait() {
-                    Ok(Some(_)) => {
-                        // 进程结束的信息留给外面的函数报告.
-                        break;
-                    }
-                    Err(e) => {
-                        // 无法获取子进程信息.
-                        return Err(Status::internal(e.to_string()));
-                    }
-                    _ => continue,
+    /// 处理客户端通过会话发送的请求.
+    async fn handle_request(&self, request_chunk: RequestChunk, running: &mut Running) {
+        match request_chunk {
+            RequestChunk::StdinEof(_) => {
+                debug!("stdin EOF");
+                // 伪终端的主设备还用于输出和调整窗口大小, 不能关闭, 改为写入 VEOF (`^D`).
+                if self.pty
+                    && let Some(stdin) = &mut running.stdin
+                    && let Err(e) = stdin.write_all(b"\x04").await
+                {
+                    warn!("failed to write EOF to pty: {e}");
                 }
-            };
-            match request_chunk {
-                RequestChunk::StdinChunk(stdin_chunk) => {
-                    if stdin_chunk.data.is_empty() {
-                        debug!("stdin EOF");
-                        break;
-                    }
+                // 只关闭标准输入, 继续处理控制消息并等待子进程自己退出.
+                running.stdin = None;
+            }
+            RequestChunk::StdinChunk(stdin_chunk) => {
+                if let Some(stdin) = &mut running.stdin {
+                    *self.last_activity.lock().unwrap() = Instant::now();
                     match stdin.write_all(&stdin_chunk.data).await {
-                        Ok(()) => (),
-                        Err(e) => {
-                            return Err(Status::internal(e.to_string()));
-                        }
+                        Ok(()) => running.bytes_in += stdin_chunk.data.len() as u64,
+                        Err(e) => warn!("failed to write stdin: {e}"),
                     }
                 }
-                RequestChunk::Kill(_) => {
-                    child.kill().await.ok();
-                    match child.wait().await {
-                        Ok(status) => {
-                            self.output_sender
-                                .send(Ok(ProgramOutput {
-                                    payload: Some(Payload::ExitStatus(status.code().unwrap_or(-1))),
-                                }))
-                                .await
-                                .ok();
-                        }
-                        Err(e) => {
-                            return Err(Status::internal(e.to_string()));
-                        }
-                    }
-                    break;
+            }
+            RequestChunk::Kill(_) => {
+                self.kill(running, KillReason::Request).await;
+                running.killed_by_request = true;
+            }
+            RequestChunk::TerminalSize(size) => self.resize_terminal(&size),
+            RequestChunk::Signal(signal) => {
+                if let Err(e) = send_signal(&mut running.child, &signal) {
+                    warn!("failed to send signal: {e}");
                 }
-                RequestChunk::Command(_) => {}
             }
+            RequestChunk::Command(_) | RequestChunk::Attach(_) => {}
         }
-        Ok(child)
     }
 
-    /// 根据字段中的启动信息来启动进程, 如果发生错误,
-    /// 那么错误 [`Status`] 会通过返回值提供, 不会在 [`Sender`] 中发送.
-    ///
-    /// 当连接在程序执行完毕前终止时, 如果 [`ProgramCaller::leak`] 属性设置为 false,
-    /// 那么程序会被 [`Child::kill`] 命令杀死, 否则不会, 需要手动将子程序杀死.
-    ///
-    /// 当连接未被关闭时, 此方法可被调用多次.
-    ///
-    /// # Returns
-    /// 当执行正常时, 返回子程序对象; 当出现错误时, 返回 [`Status`] 错误信息.
-    pub async fn call_program(&mut self) -> Result<Child, Status> {
-        let mut child = match Command::new(&self.executable)
-            .args(&self.args)
-            .current_dir(&self.current_dir)
+    /// 终止子进程所在的进程组, 并记录终止的原因.
+    async fn kill(&self, running: &mut Running, reason: KillReason) {
+        kill_tree(&mut running.child, running.pid, self.kill_grace_period).await;
+        running.kill_reason = Some(reason);
+    }
+
+    /// 子进程因为超出资源限制而被终止时, 返回超出的限制.
+    fn limit_exceeded(&self, exit_info: &ExitInfo) -> Option<String> {
+        #[cfg(unix)]
+        if self.limits.cpu_time.is_some() && exit_info.signal == Some(libc::SIGXCPU) {
+            return Some("cpu_time".into());
+        }
+        if exit_info.spawn_error.is_none() && self.cgroup.as_ref().is_some_and(Cgroup::oom_killed) {
+            return Some("memory".into());
+        }
+        None
+    }
+
+    /// 空闲超时的截止时间, 没有设置空闲超时时为 [`None`].
+    fn idle_deadline(&self) -> Option<Instant> {
+        self.idle_timeout
+            .map(|timeout| *self.last_activity.lock().unwrap() + timeout)
+    }
+
+    /// 设置了审计日志时写入一条记录.
+    fn write_audit(&self, event: &AuditEvent) {
+        if let Some((audit, peer)) = &self.audit {
+            audit.record(peer, event);
+        }
+    }
+
+    /// 将客户端的终端窗口大小应用到子进程所在的伪终端上, 非伪终端模式下忽略.
+    #[cfg(unix)]
+    fn resize_terminal(&self, size: &TerminalSize) {
+        if let Some(master) = &self.pty_master
+            && let Err(e) = master.set_size(size)
+        {
+            debug!("failed to resize terminal: {e}");
+        }
+    }
+
+    #[cfg(not(unix))]
+    #[allow(clippy::unused_self)]
+    fn resize_terminal(&self, _size: &TerminalSize) {}
+
+    /// 以管道连接子进程的标准输入输出.
+    fn spawn_piped(mut command: Command) -> io::Result<Spawned> {
+        // 子进程成为新进程组的组长, 以便终止时连同其后代进程一起终止.
+        #[cfg(unix)]
+        command.process_group(0);
+        let mut child = command
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
             .stdin(Stdio::piped())
-            .spawn()
-        {
-            Ok(child) => child,
-            Err(e) => {
-                return Err(Status::unknown(e.to_string()));
-            }
-        };
-
-        debug!("child spawn");
+            .spawn()?;
+        Ok(Spawned {
+            stdin: Box::new(child.stdin.take().unwrap()),
+            stdout: Box::new(child.stdout.take().unwrap()),
+            stderr: Some(Box::new(child.stderr.take().unwrap())),
+            child,
+        })
+    }
 
-        self.spawn_stderr_transmitter(child.stderr.take().unwrap());
-        self.spawn_stdout_transmitter(child.stdout.take().unwrap());
-        let mut child = self.transmit_stdin(child).await?;
-        if !self.leak {
-            debug!("kill sub process: {:?}", child.kill().await);
+    /// 在伪终端中启动子进程, 终端的输出都会作为 stdout 转发.
+    #[cfg(unix)]
+    fn spawn_in_pty(&mut self, mut command: Command) -> io::Result<Spawned> {
+        let master = pty::attach_pty(&mut command, self.terminal_size.as_ref())?;
+        let inherits_term = !self.env_clear && std::env::var_os("TERM").is_some();
+        if !inherits_term && !self.env.contains_key("TERM") {
+            command.env("TERM", "xterm-256color");
         }
+        let child = command.spawn()?;
+        // 丢弃 command 以关闭父进程中持有的从设备, 否则子进程退出后读取主设备不会结束.
+        drop(command);
+        self.pty_master = Some(master.try_clone()?);
+        Ok(Spawned {
+            child,
+            stdout: Box::new(master.try_clone()?),
+            stdin: Box::new(master),
+            stderr: None,
+        })
+    }
+
+    #[cfg(not(unix))]
+    #[allow(clippy::unused_self)]
+    fn spawn_in_pty(&mut self, _command: Command) -> io::Result<Spawned> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "pty mode is not supported on this platform",
+        ))
+    }
 
-        if let Ok(Some(es)) = child.try_wait() {
-            debug!("sub process exited: {:?}", es.code());
-            self.output_sender
-                .send(Ok(ProgramOutput {
-                    payload: Some(Payload::ExitStatus(es.code().unwrap_or(-1))),
+    /// 根据字段中的启动信息来启动进程, 并为其创建会话, `tx` 会被附加到会话上,
+    /// `peer` 是发起执行的客户端, 设置了 `audit` 时子进程的启动和退出会写入审计日志,
+    /// `permit` 是子进程占用的空位, 子进程结束后释放.
+    ///
+    /// 子进程启动失败时, 失败原因会通过 [`ExitInfo::spawn_error`] 发送给客户端, 此时返回 [`None`].
+    ///
+    /// 当连接在程序执行完毕前终止时, 如果 [`ProgramCaller::leak`] 属性设置为 false,
+    /// 那么程序会被 [`Child::kill`] 命令杀死, 否则不会, 会话会保留在 [`SessionRegistry`] 中等待重新连接.
+    ///
+    /// # Returns
+    /// 子进程所在的会话.
+    pub async fn call_program(
+        mut self,
+        sessions: &Arc<SessionRegistry>,
+        tx: &OutputSender,
+        peer: &Peer,
+        audit: Option<Arc<AuditLog>>,
+        permit: SlotPermit,
+        reservation: SessionReservation,
+    ) -> Option<Arc<Session>> {
+        self.audit = audit.map(|audit| (audit, peer.clone()));
+        self.permit = Some(permit);
+        let mut command = Command::new(&self.executable);
+        // 多调用程序 (如 busybox) 根据 argv[0] 决定行为, 保留符号链接的路径.
+        #[cfg(unix)]
+        command.arg0(&self.arg0);
+        command.args(&self.args).current_dir(&self.current_dir);
+        if self.env_clear {
+            command.env_clear();
+        }
+        command.envs(&self.session_env);
+        for name in &self.env_remove {
+            command.env_remove(name);
+        }
+        command.envs(&self.env);
+        #[cfg(target_os = "linux")]
+        resources::apply(&mut command, &self.limits, self.cgroup.as_ref());
+        let spawned = if self.pty {
+            self.spawn_in_pty(command)
+        } else {
+            Self::spawn_piped(command)
+        };
+        let spawned = match spawned {
+            Ok(spawned) => spawned,
+            Err(e) => {
+                debug!("failed to spawn child: {e}");
+                let exit_info = ExitInfo {
+                    spawn_error: Some(e.to_string()),
+                    ..Default::default()
+                };
+                self.write_audit(&AuditEvent::Exit {
+                    session_id: None,
+                    pid: None,
+                    command: self.record.clone(),
+                    duration_ms: 0,
+                    exit: (&exit_info).into(),
+                    bytes_in: 0,
+                    bytes_out: 0,
+                    kill_reason: None,
+                });
+                tx.send(Ok(ProgramOutput {
+                    payload: Some(Payload::ExitInfo(exit_info)),
                 }))
                 .await
                 .ok();
-        } else {
-            debug!("sub process leaked.");
+                return None;
+            }
+        };
+        let (session, control_rx) = sessions.create(
+            SessionMeta {
+                pid: spawned.child.id(),
+                executable: self.executable.to_string_lossy().into_owned(),
+                args: self.args.clone(),
+                current_dir: self.current_dir.to_string_lossy().into_owned(),
+                peer_address: peer.address.clone(),
+                owner: peer.identity.clone(),
+                leak: self.leak,
+                pty: self.pty,
+            },
+            reservation,
+        );
+        debug!("child spawn, session {}", session.id());
+        self.write_audit(&AuditEvent::Start {
+            session_id: session.id().to_string(),
+            pid: spawned.child.id(),
+            command: self.record.clone(),
+        });
+        // 新建的会话不会被其他连接占用.
+        session.attach(tx.clone()).await.ok();
+
+        *self.last_activity.lock().unwrap() = Instant::now();
+        let mut transmitters = vec![spawn_output_transmitter(
+            session.clone(),
+            spawned.stdout,
+            |data| Payload::StdoutChunk(StdoutChunk { data }),
+            self.last_activity.clone(),
+            self.bytes_out.clone(),
+        )];
+        if let Some(stderr) = spawned.stderr {
+            transmitters.push(spawn_output_transmitter(
+                session.clone(),
+                stderr,
+                |data| Payload::StderrChunk(StderrChunk { data }),
+                self.last_activity.clone(),
+                self.bytes_out.clone(),
+            ));
         }
-        Ok(child)
+        tokio::spawn(self.run(
+            spawned.child,
+            spawned.stdin,
+            transmitters,
+            session.clone(),
+            control_rx,
+            sessions.clone(),
+        ));
+        Some(session)
     }
 
-    /// 从输入请求流中解析进程启动信息, 如果发生了错误, 返回 [`Status`] 错误信息, 不会向 tx 中发送 [`Err`].
-    pub async fn parse(
-        mut request: Streaming<ExecuteRequestChunk>,
-        tx: Sender<Result<ProgramOutput, Status>>,
+    /// 从 [`ExecCommand`] 中解析进程启动信息, 如果发生了错误, 返回 [`Status`] 错误信息.
+    pub fn parse(
+        mut command: ExecCommand,
+        settings: &ExecutorSettings,
+        role: &Role,
+        session_env: HashMap<String, String>,
     ) -> Result<ProgramCaller, Status> {
-        let Ok(Some(ExecuteRequestChunk {
-            request_chunk: Some(RequestChunk::Command(command)),
-        })) = request.message().await
-        else {
-            return Err(Status::invalid_argument(
-                "can not get command in first chunk",
-            ));
-        };
+        // 在补回受保护的环境变量之前记录, 审计日志中只有客户端的修改.
+        let mut record = CommandRecord::from(&command);
+        settings.env_policy.apply(&mut command)?;
         let Ok(executable) = which::which(PathBuf::from(command.executable)) else {
             return Err(Status::not_found("executable not found"));
         };
@@ -209,7 +640,22 @@ impl ProgramCaller {
                 "relative executable path is not supported",
             ));
         }
-        let current_dir = match command.current_dir {
+        // 按真实路径匹配策略和角色, 避免通过符号链接或 `..` 绕过规则, 之后也执行这个路径.
+        let Ok(real_executable) = std::fs::canonicalize(&executable) else {
+            return Err(Status::not_found("executable not found"));
+        };
+        if command.pty && !cfg!(unix) {
+            return Err(Status::unimplemented(
+                "pty mode is not supported on this platform",
+            ));
+        }
+        let current_dir = command.current_dir.or_else(|| {
+            settings
+                .default_cwd
+                .as_ref()
+                .map(|dir| dir.to_string_lossy().into_owned())
+        });
+        let current_dir = match current_dir {
             Some(it) => it,
             None => {
                 if let Some(dir) = executable.parent() {
@@ -219,13 +665,64 @@ impl ProgramCaller {
                 }
             }
         };
+        // 按真实路径匹配工作目录, 避免通过符号链接绕过策略.
+        let real_dir =
+            std::fs::canonicalize(&current_dir).unwrap_or_else(|_| current_dir.clone().into());
+        settings
+            .policy
+            .check(&real_executable, &command.args, &real_dir)
+            .map_err(|e| {
+                Status::permission_denied(format!("executing `{}`: {e}", real_executable.display()))
+            })?;
+        role.check_exec(&real_executable, &real_dir)?;
+        let limits = resources::limit(command.limits.as_deref(), &settings.max_resources);
+        if !cfg!(target_os = "linux") && !resources::is_empty(&limits) {
+            return Err(Status::unimplemented(
+                "resource limits are only supported on Linux",
+            ));
+        }
+        let cgroup = if resources::needs_cgroup(&limits) {
+            let Some(cgroup_root) = &settings.cgroup else {
+                return Err(Status::failed_precondition(
+                    "memory and cpu_percent limits require a cgroup configured on the server",
+                ));
+            };
+            let cgroup = cgroup_root
+                .create(&limits)
+                .map_err(|e| Status::internal(format!("failed to create cgroup: {e}")))?;
+            Some(cgroup)
+        } else {
+            None
+        };
+        record.executable = real_executable.to_string_lossy().into_owned();
+        record.current_dir = Some(current_dir.clone());
         Ok(ProgramCaller {
             current_dir: current_dir.into(),
             leak: command.leak,
+            pty: command.pty,
+            terminal_size: command.terminal_size,
+            kill_grace_period: command
+                .kill_grace_period
+                .map(|secs| Duration::from_secs(secs.into())),
+            timeout: limit_timeout(command.timeout, settings.max_timeout),
+            idle_timeout: limit_timeout(command.idle_timeout, settings.max_idle_timeout),
+            last_activity: Arc::new(Mutex::new(Instant::now())),
+            bytes_out: Arc::new(AtomicU64::new(0)),
+            limits,
+            cgroup,
+            session_env,
+            env: command.env,
+            env_remove: command.env_remove,
+            env_clear: command.env_clear,
+            #[cfg(unix)]
+            pty_master: None,
+            record,
+            audit: None,
+            permit: None,
             args: command.args,
-            output_sender: tx,
-            request_stream: request,
-            executable,
+            executable: real_executable,
+            #[cfg(unix)]
+            arg0: executable,
         })
     }
 }
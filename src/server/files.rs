@@ -0,0 +1,141 @@
+//! 文件传输相关的服务端策略.
+
+use std::{
+    io,
+    path::{Component, Path, PathBuf},
+};
+
+use tokio::fs;
+use tonic::Status;
+
+use crate::{
+    exec::ManifestEntry,
+    transfer::{file_sha256, io_status},
+};
+
+/// 限制文件传输 (上传, 下载和同步) 可以访问的服务端路径.
+#[derive(Debug, Clone, Default)]
+pub struct FileRoot {
+    /// 为 [`None`] 时允许访问任意绝对路径.
+    root: Option<PathBuf>,
+}
+
+/// 不访问文件系统, 只在字面上处理路径中的 `.` 和 `..`.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+impl FileRoot {
+    pub fn new(root: Option<PathBuf>) -> io::Result<FileRoot> {
+        Ok(FileRoot {
+            root: root.map(std::fs::canonicalize).transpose()?,
+        })
+    }
+
+    /// 检查客户端请求的服务端路径, 设置了根目录时相对路径相对于根目录.
+    ///
+    /// 路径中已经存在的部分会被解析为真实路径, 避免通过符号链接访问根目录之外的文件.
+    pub async fn resolve(&self, path: impl AsRef<Path>) -> Result<PathBuf, Status> {
+        let path = path.as_ref();
+        let Some(root) = &self.root else {
+            if path.is_relative() {
+                return Err(Status::invalid_argument(
+                    "relative file path is not supported",
+                ));
+            }
+            return Ok(path.to_path_buf());
+        };
+        let normalized = normalize(&root.join(path));
+        let mut existing = normalized.as_path();
+        let mut missing = Vec::new();
+        let resolved = loop {
+            match fs::canonicalize(existing).await {
+                Ok(resolved) => break resolved,
+                Err(_) => {
+                    let (Some(parent), Some(name)) = (existing.parent(), existing.file_name())
+                    else {
+                        break existing.to_path_buf();
+                    };
+                    missing.push(name);
+                    existing = parent;
+                }
+            }
+        };
+        let resolved = missing
+            .iter()
+            .rev()
+            .fold(resolved, |it, name| it.join(name));
+        if !resolved.starts_with(root) {
+            return Err(Status::permission_denied(format!(
+                "{}: outside the server file root",
+                path.display()
+            )));
+        }
+        Ok(resolved)
+    }
+}
+
+/// 将清单中以 `/` 分隔的相对路径转换为本地路径, 拒绝绝对路径和 `..`.
+fn manifest_path(path: &str) -> Result<PathBuf, Status> {
+    let mut relative = PathBuf::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                return Err(Status::invalid_argument(format!(
+                    "`{path}`: parent directory is not allowed in manifest"
+                )));
+            }
+            part => relative.push(part),
+        }
+    }
+    if relative.as_os_str().is_empty() || relative.is_absolute() || relative.has_root() {
+        return Err(Status::invalid_argument(format!(
+            "`{path}`: invalid manifest path"
+        )));
+    }
+    Ok(relative)
+}
+
+/// 对比客户端的文件清单和服务端 `dir` 目录下的文件, 返回需要上传的文件, 不修改文件系统.
+pub async fn changed_files(
+    file_root: &FileRoot,
+    dir: &Path,
+    entries: Vec<ManifestEntry>,
+) -> Result<Vec<String>, Status> {
+    let mut changed = Vec::new();
+    for entry in entries {
+        let path = file_root
+            .resolve(dir.join(manifest_path(&entry.path)?))
+            .await?;
+        let unchanged = match fs::metadata(&path).await {
+            Ok(metadata) if metadata.is_dir() => {
+                return Err(Status::invalid_argument(format!(
+                    "{}: is a directory",
+                    path.display()
+                )));
+            }
+            // 大小相同时才需要计算校验和.
+            Ok(metadata) if metadata.len() == entry.size => {
+                file_sha256(&path).await.map_err(|e| io_status(&e, &path))? == entry.sha256
+            }
+            Ok(_) => false,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => false,
+            Err(e) => return Err(io_status(&e, &path)),
+        };
+        if !unchanged {
+            changed.push(entry.path);
+        }
+    }
+    Ok(changed)
+}
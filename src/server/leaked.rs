@@ -0,0 +1,76 @@
+//! 服务端关闭时仍在运行的 `leak = true` 的子进程的记录.
+//!
+//! 服务端关闭后这些子进程继续运行, 但不再属于任何会话. 记录以 JSON 格式保存在文件中,
+//! 服务端下次启动时读取, 其中仍在运行的进程会在再次关闭时与新的记录一起保存.
+
+use std::{io, path::Path};
+
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::exec::SessionInfo;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LeakedProcess {
+    pub session_id: String,
+    pub pid: u32,
+    pub executable: String,
+    pub args: Vec<String>,
+    pub current_dir: String,
+    /// 启动时间, Unix 时间戳 (秒).
+    pub start_time: u64,
+    /// 启动会话的客户端证书中的身份.
+    pub identity: String,
+    pub peer_address: String,
+}
+
+impl LeakedProcess {
+    /// 子进程已经结束的会话没有记录.
+    pub fn from_info(info: SessionInfo) -> Option<LeakedProcess> {
+        Some(LeakedProcess {
+            pid: info.pid?,
+            session_id: info.session_id,
+            executable: info.executable,
+            args: info.args,
+            current_dir: info.current_dir,
+            start_time: info.start_time,
+            identity: info.identity,
+            peer_address: info.peer_address,
+        })
+    }
+
+    /// 进程是否还在运行, 进程号可能已经被其他进程重用.
+    #[cfg(unix)]
+    pub fn is_running(&self) -> bool {
+        use nix::{errno::Errno, sys::signal::kill, unistd::Pid};
+
+        kill(Pid::from_raw(self.pid.cast_signed()), None) != Err(Errno::ESRCH)
+    }
+
+    /// 无法检查时视为还在运行.
+    #[cfg(not(unix))]
+    pub fn is_running(&self) -> bool {
+        true
+    }
+}
+
+/// 读取记录, 文件不存在时没有记录.
+pub async fn load(path: &Path) -> io::Result<Vec<LeakedProcess>> {
+    match fs::read(path).await {
+        Ok(content) => serde_json::from_slice(&content).map_err(io::Error::other),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// 保存记录, 没有记录时删除文件.
+pub async fn save(path: &Path, processes: &[LeakedProcess]) -> io::Result<()> {
+    if processes.is_empty() {
+        return match fs::remove_file(path).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        };
+    }
+    let content = serde_json::to_vec_pretty(processes).expect("records are serializable");
+    fs::write(path, content).await
+}
@@ -0,0 +1,333 @@
+//! 服务端的监听地址: TCP 地址或 Unix 域套接字.
+//!
+//! 同一台机器上的任何用户都可以连接 TCP 回环地址, Unix 域套接字则可以通过文件权限限制可以连接的用户,
+//! 还可以通过 `SO_PEERCRED` 只允许与服务端相同 UID 的进程连接.
+//!
+//! 服务端由 systemd socket activation 启动时, 使用 systemd 传入的监听套接字 (`LISTEN_FDS`), 而不是自己监听.
+
+use std::{fmt, net::SocketAddr, path::PathBuf, str::FromStr};
+
+use serde::{Deserialize, Deserializer, de};
+use tonic::transport::server::TcpIncoming;
+
+/// 写作 `127.0.0.1:30521`, `[::1]:30521` 或 `unix:/path/to/rex.sock`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum BindAddress {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for BindAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err("unix socket path is empty".into());
+            }
+            return Ok(BindAddress::Unix(path.into()));
+        }
+        s.parse()
+            .map(BindAddress::Tcp)
+            .map_err(|e| format!("invalid bind address `{s}`: {e}"))
+    }
+}
+
+impl fmt::Display for BindAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BindAddress::Tcp(addr) => write!(f, "{addr}"),
+            BindAddress::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for BindAddress {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+/// 已经在监听的套接字.
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpIncoming),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listener::Tcp(incoming) => match incoming.local_addr() {
+                Ok(addr) => write!(f, "{addr}"),
+                Err(_) => write!(f, "<unknown tcp address>"),
+            },
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let addr = listener.local_addr().ok();
+                match addr.as_ref().and_then(|it| it.as_pathname()) {
+                    Some(path) => write!(f, "unix:{}", path.display()),
+                    None => write!(f, "<unnamed unix socket>"),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+pub use unix::{UnixSocketOptions, bind_unix, bind_unix_listener, listen_fds, unix_incoming};
+
+/// 只有 Unix 上的 systemd 会传入监听套接字.
+#[cfg(not(unix))]
+pub fn listen_fds() -> std::io::Result<Vec<Listener>> {
+    Ok(Vec::new())
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::{
+        env,
+        fs::{self, Permissions},
+        io,
+        os::{
+            fd::{AsRawFd as _, FromRawFd as _, OwnedFd, RawFd},
+            unix::fs::{FileTypeExt as _, PermissionsExt as _},
+        },
+        path::Path,
+        process,
+        sync::atomic::{AtomicBool, Ordering},
+    };
+
+    use nix::{
+        fcntl::{FcntlArg, FdFlag, fcntl},
+        sys::socket::{
+            AddressFamily, SockType, SockaddrLike as _, SockaddrStorage, getsockname, getsockopt,
+            sockopt,
+        },
+        sys::stat::{self, Mode},
+        unistd::{Gid, Group, Uid, User, chown, getuid},
+    };
+    use tokio::net::{TcpListener, UnixListener, UnixStream};
+    use tokio_stream::{Stream, StreamExt as _, wrappers::UnixListenerStream};
+    use tonic::transport::server::TcpIncoming;
+    use tracing::warn;
+
+    use super::Listener;
+
+    /// systemd 传入的第一个文件描述符.
+    const LISTEN_FDS_START: RawFd = 3;
+
+    /// systemd 传入的文件描述符是否已经被取得所有权.
+    static LISTEN_FDS_TAKEN: AtomicBool = AtomicBool::new(false);
+
+    /// Unix 域套接字文件的权限和可以连接的进程.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct UnixSocketOptions {
+        /// 套接字文件的权限位.
+        pub mode: u32,
+        pub owner: Option<Uid>,
+        pub group: Option<Gid>,
+        /// 是否只允许与服务端相同 UID 的进程连接.
+        pub same_uid: bool,
+    }
+
+    impl Default for UnixSocketOptions {
+        fn default() -> Self {
+            UnixSocketOptions {
+                mode: 0o600,
+                owner: None,
+                group: None,
+                same_uid: false,
+            }
+        }
+    }
+
+    impl UnixSocketOptions {
+        /// 解析用户名或 UID.
+        pub fn parse_owner(owner: &str) -> io::Result<Uid> {
+            if let Ok(uid) = owner.parse() {
+                return Ok(Uid::from_raw(uid));
+            }
+            User::from_name(owner)?
+                .map(|user| user.uid)
+                .ok_or_else(|| io::Error::other(format!("user `{owner}` does not exist")))
+        }
+
+        /// 解析组名或 GID.
+        pub fn parse_group(group: &str) -> io::Result<Gid> {
+            if let Ok(gid) = group.parse() {
+                return Ok(Gid::from_raw(gid));
+            }
+            Group::from_name(group)?
+                .map(|group| group.gid)
+                .ok_or_else(|| io::Error::other(format!("group `{group}` does not exist")))
+        }
+    }
+
+    /// 删除之前的服务端没有清理的套接字文件, 仍然有服务端在监听时返回错误.
+    fn remove_stale(path: &Path) -> io::Result<()> {
+        let Ok(metadata) = fs::symlink_metadata(path) else {
+            return Ok(());
+        };
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ));
+        }
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{} is in use by another server", path.display()),
+            ));
+        }
+        fs::remove_file(path)
+    }
+
+    /// 在 `path` 上监听并设置套接字文件的权限.
+    pub fn bind_unix_listener(
+        path: &Path,
+        options: &UnixSocketOptions,
+    ) -> io::Result<UnixListener> {
+        remove_stale(path)?;
+        // 套接字文件创建后到修改权限前, 其他用户不能连接.
+        // umask 是整个进程共享的, 期间其他线程创建的文件权限也会更严格, 但不会更宽松.
+        let umask = stat::umask(Mode::from_bits_truncate(0o177));
+        let listener = UnixListener::bind(path);
+        stat::umask(umask);
+        let listener = listener?;
+        fs::set_permissions(path, Permissions::from_mode(options.mode))?;
+        if options.owner.is_some() || options.group.is_some() {
+            chown(path, options.owner, options.group)?;
+        }
+        Ok(listener)
+    }
+
+    /// 在 `path` 上监听并设置套接字文件的权限, 返回接受的连接.
+    pub fn bind_unix(
+        path: &Path,
+        options: &UnixSocketOptions,
+    ) -> io::Result<impl Stream<Item = io::Result<UnixStream>> + use<>> {
+        Ok(unix_incoming(
+            bind_unix_listener(path, options)?,
+            options.same_uid,
+        ))
+    }
+
+    /// `listener` 接受的连接, `same_uid` 为 `true` 时丢弃与服务端 UID 不同的进程的连接.
+    pub fn unix_incoming(
+        listener: UnixListener,
+        same_uid: bool,
+    ) -> impl Stream<Item = io::Result<UnixStream>> + use<> {
+        let server_uid = same_uid.then(getuid);
+        UnixListenerStream::new(listener).filter(move |stream| {
+            let (Some(server_uid), Ok(stream)) = (server_uid, stream) else {
+                return true;
+            };
+            match stream.peer_cred() {
+                Ok(cred) if cred.uid() == server_uid.as_raw() => true,
+                Ok(cred) => {
+                    warn!("rejected connection from uid {}", cred.uid());
+                    false
+                }
+                Err(e) => {
+                    warn!("rejected connection without peer credentials: {e}");
+                    false
+                }
+            }
+        })
+    }
+
+    /// 将已经在监听的流套接字 `fd` 转换为 [`Listener`], 并设置 `FD_CLOEXEC` 以免被子进程继承.
+    fn from_fd(fd: OwnedFd) -> io::Result<Listener> {
+        fcntl(&fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+        if getsockopt(&fd, sockopt::SockType)? != SockType::Stream
+            || !getsockopt(&fd, sockopt::AcceptConn)?
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "file descriptor {} is not a listening stream socket",
+                    fd.as_raw_fd()
+                ),
+            ));
+        }
+        match getsockname::<SockaddrStorage>(fd.as_raw_fd())?.family() {
+            Some(AddressFamily::Inet | AddressFamily::Inet6) => {
+                let listener = std::net::TcpListener::from(fd);
+                listener.set_nonblocking(true)?;
+                Ok(Listener::Tcp(TcpIncoming::from(TcpListener::from_std(
+                    listener,
+                )?)))
+            }
+            Some(AddressFamily::Unix) => {
+                let listener = std::os::unix::net::UnixListener::from(fd);
+                listener.set_nonblocking(true)?;
+                Ok(Listener::Unix(UnixListener::from_std(listener)?))
+            }
+            family => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unsupported socket family {family:?}"),
+            )),
+        }
+    }
+
+    /// systemd socket activation 传入的监听套接字, 没有传入或不是传给当前进程时为空.
+    ///
+    /// 文件描述符只会被取得一次所有权, 之后的调用返回空.
+    /// 相关的环境变量会被删除, 以免子进程认为这些文件描述符是传给它的.
+    /// 必须在创建子进程之前调用.
+    pub fn listen_fds() -> io::Result<Vec<Listener>> {
+        let pid = env::var("LISTEN_PID").ok();
+        let fds = env::var("LISTEN_FDS").ok();
+        for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+            // SAFETY: 只在启动服务端时调用, 此时还没有其他线程读写环境变量.
+            unsafe { env::remove_var(name) };
+        }
+        if pid.and_then(|it| it.parse().ok()) != Some(process::id()) {
+            return Ok(Vec::new());
+        }
+        let count: RawFd = fds
+            .and_then(|it| it.parse().ok())
+            .ok_or_else(|| io::Error::other("LISTEN_FDS is not a number"))?;
+        if LISTEN_FDS_TAKEN.swap(true, Ordering::SeqCst) {
+            return Ok(Vec::new());
+        }
+        (LISTEN_FDS_START..LISTEN_FDS_START + count)
+            .map(|fd| {
+                // SAFETY: systemd 将这些文件描述符传给了当前进程, 由上面的标志保证只取得一次所有权.
+                from_fd(unsafe { OwnedFd::from_raw_fd(fd) })
+            })
+            .collect()
+    }
+
+    #[cfg(test)]
+    mod test {
+        use std::os::fd::OwnedFd;
+
+        use super::{Listener, from_fd};
+
+        #[tokio::test]
+        async fn listener_from_fd() {
+            let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = tcp.local_addr().unwrap();
+            let listener = from_fd(OwnedFd::from(tcp)).unwrap();
+            assert!(matches!(listener, Listener::Tcp(_)));
+            assert_eq!(listener.to_string(), addr.to_string());
+
+            let path = std::env::temp_dir().join(format!("rex-fd-{}.sock", std::process::id()));
+            std::fs::remove_file(&path).ok();
+            let unix = std::os::unix::net::UnixListener::bind(&path).unwrap();
+            let listener = from_fd(OwnedFd::from(unix)).unwrap();
+            assert_eq!(listener.to_string(), format!("unix:{}", path.display()));
+            std::fs::remove_file(&path).unwrap();
+
+            // 没有在监听的套接字.
+            let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            assert!(from_fd(OwnedFd::from(udp)).is_err());
+        }
+    }
+}
@@ -0,0 +1,277 @@
+//! 限制客户端可以执行的程序, 参数和工作目录的服务端策略.
+//!
+//! 规则按顺序匹配, 第一条匹配的规则决定允许还是拒绝, 没有规则匹配时使用默认动作.
+//! 程序和工作目录都按解析所有符号链接和 `..` 之后的真实路径匹配.
+
+use std::path::Path;
+
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    #[default]
+    Allow,
+    Deny,
+}
+
+/// 一条执行规则, 没有设置的条件匹配任意值, 所有条件都满足时规则才匹配.
+#[derive(Debug, Clone)]
+pub struct ExecRule {
+    name: String,
+    action: Action,
+    /// 匹配程序的真实路径.
+    executable: Option<GlobSet>,
+    /// 逐个匹配参数: 允许规则要求每个参数都匹配, 拒绝规则只要有一个参数匹配.
+    args: Option<GlobSet>,
+    /// 匹配工作目录.
+    current_dir: Option<GlobSet>,
+}
+
+/// 路径中的 `*` 和 `?` 不匹配 `/`, 需要跨目录时使用 `**`.
+pub(super) fn path_glob_set(patterns: &[String]) -> Result<Option<GlobSet>, globset::Error> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(GlobBuilder::new(pattern).literal_separator(true).build()?);
+    }
+    builder.build().map(Some)
+}
+
+fn glob_set(patterns: &[String]) -> Result<Option<GlobSet>, globset::Error> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern)?);
+    }
+    builder.build().map(Some)
+}
+
+#[bon::bon]
+impl ExecRule {
+    #[builder]
+    pub fn new(
+        #[builder(into)] name: String,
+        action: Action,
+        #[builder(default)] executable: Vec<String>,
+        #[builder(default)] args: Vec<String>,
+        #[builder(default)] current_dir: Vec<String>,
+    ) -> Result<ExecRule, globset::Error> {
+        Ok(ExecRule {
+            name,
+            action,
+            executable: path_glob_set(&executable)?,
+            args: glob_set(&args)?,
+            current_dir: path_glob_set(&current_dir)?,
+        })
+    }
+
+    fn matches(&self, executable: &Path, args: &[String], current_dir: &Path) -> bool {
+        let args_match = self.args.as_ref().is_none_or(|it| match self.action {
+            // 允许规则列出可以使用的参数.
+            Action::Allow => args.iter().all(|arg| it.is_match(arg)),
+            // 拒绝规则列出禁止使用的参数.
+            Action::Deny => args.iter().any(|arg| it.is_match(arg)),
+        });
+        self.executable
+            .as_ref()
+            .is_none_or(|it| it.is_match(executable))
+            && args_match
+            && self
+                .current_dir
+                .as_ref()
+                .is_none_or(|it| it.is_match(current_dir))
+    }
+}
+
+/// 请求被策略拒绝, 包含匹配的规则名称.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum Denied {
+    #[error("denied by rule `{0}`")]
+    Rule(String),
+    #[error("no rule allows it and the default action is deny")]
+    Default,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ExecPolicy {
+    rules: Vec<ExecRule>,
+    default_action: Action,
+}
+
+impl ExecPolicy {
+    pub fn new(rules: Vec<ExecRule>, default_action: Action) -> ExecPolicy {
+        ExecPolicy {
+            rules,
+            default_action,
+        }
+    }
+
+    /// 检查是否允许在 `current_dir` 中以 `args` 执行 `executable`.
+    pub fn check(
+        &self,
+        executable: &Path,
+        args: &[String],
+        current_dir: &Path,
+    ) -> Result<(), Denied> {
+        match self
+            .rules
+            .iter()
+            .find(|rule| rule.matches(executable, args, current_dir))
+        {
+            Some(ExecRule {
+                action: Action::Allow,
+                ..
+            }) => Ok(()),
+            Some(rule) => Err(Denied::Rule(rule.name.clone())),
+            None if self.default_action == Action::Allow => Ok(()),
+            None => Err(Denied::Default),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::{Action, Denied, ExecPolicy, ExecRule};
+
+    fn check(
+        policy: &ExecPolicy,
+        executable: &str,
+        args: &[&str],
+        cwd: &str,
+    ) -> Result<(), Denied> {
+        let args: Vec<String> = args.iter().map(ToString::to_string).collect();
+        policy.check(Path::new(executable), &args, Path::new(cwd))
+    }
+
+    #[test]
+    fn default_action() {
+        let policy = ExecPolicy::default();
+        assert_eq!(check(&policy, "/usr/bin/rm", &["-rf", "/"], "/"), Ok(()));
+        let policy = ExecPolicy::new(vec![], Action::Deny);
+        assert_eq!(
+            check(&policy, "/usr/bin/ls", &[], "/"),
+            Err(Denied::Default)
+        );
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let policy = ExecPolicy::new(
+            vec![
+                ExecRule::builder()
+                    .name("no-root-rm")
+                    .action(Action::Deny)
+                    .executable(vec!["/usr/bin/rm".into()])
+                    .args(vec!["/".into(), "--no-preserve-root".into()])
+                    .build()
+                    .unwrap(),
+                ExecRule::builder()
+                    .name("coreutils")
+                    .action(Action::Allow)
+                    .executable(vec!["/usr/bin/*".into()])
+                    .build()
+                    .unwrap(),
+            ],
+            Action::Deny,
+        );
+        assert_eq!(
+            check(&policy, "/usr/bin/rm", &["-rf", "/tmp/x"], "/"),
+            Ok(())
+        );
+        assert_eq!(
+            check(&policy, "/usr/bin/rm", &["-v", "-rf", "/"], "/"),
+            Err(Denied::Rule("no-root-rm".into()))
+        );
+        // 参数逐个匹配, 不受参数的顺序和拆分方式影响.
+        assert_eq!(
+            check(&policy, "/usr/bin/rm", &["/", "-r", "-f"], "/"),
+            Err(Denied::Rule("no-root-rm".into()))
+        );
+        // `*` 不匹配路径分隔符.
+        assert_eq!(
+            check(&policy, "/usr/bin/sub/tool", &[], "/"),
+            Err(Denied::Default)
+        );
+        assert_eq!(check(&policy, "/opt/tool", &[], "/"), Err(Denied::Default));
+    }
+
+    #[test]
+    fn allowed_args() {
+        let policy = ExecPolicy::new(
+            vec![
+                ExecRule::builder()
+                    .name("git-read-only")
+                    .action(Action::Allow)
+                    .executable(vec!["/usr/bin/git".into()])
+                    .args(vec!["status".into(), "log".into(), "--oneline".into()])
+                    .build()
+                    .unwrap(),
+            ],
+            Action::Deny,
+        );
+        assert_eq!(check(&policy, "/usr/bin/git", &["status"], "/"), Ok(()));
+        assert_eq!(
+            check(&policy, "/usr/bin/git", &["log", "--oneline"], "/"),
+            Ok(())
+        );
+        // 每个参数都要被允许, 不能把其他参数拼接在允许的参数后面.
+        assert_eq!(
+            check(&policy, "/usr/bin/git", &["status", "push"], "/"),
+            Err(Denied::Default)
+        );
+        assert_eq!(
+            check(&policy, "/usr/bin/git", &["log --oneline"], "/"),
+            Err(Denied::Default)
+        );
+    }
+
+    #[test]
+    fn current_dir_rule() {
+        let policy = ExecPolicy::new(
+            vec![
+                ExecRule::builder()
+                    .name("projects")
+                    .action(Action::Allow)
+                    .current_dir(vec![
+                        "/home/me/projects".into(),
+                        "/home/me/projects/**".into(),
+                    ])
+                    .build()
+                    .unwrap(),
+            ],
+            Action::Deny,
+        );
+        assert_eq!(
+            check(&policy, "/usr/bin/make", &[], "/home/me/projects"),
+            Ok(())
+        );
+        assert_eq!(
+            check(&policy, "/usr/bin/make", &[], "/home/me/projects/rex/src"),
+            Ok(())
+        );
+        assert_eq!(
+            check(&policy, "/usr/bin/make", &[], "/home/me"),
+            Err(Denied::Default)
+        );
+    }
+
+    #[test]
+    fn invalid_pattern() {
+        assert!(
+            ExecRule::builder()
+                .name("broken")
+                .action(Action::Allow)
+                .executable(vec!["[".into()])
+                .build()
+                .is_err()
+        );
+    }
+}
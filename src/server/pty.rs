@@ -0,0 +1,141 @@
+//! 伪终端 (PTY) 支持, 仅在 Unix 平台可用.
+
+use std::{
+    fs::File,
+    io::{self, Read as _, Write as _},
+    os::fd::{AsRawFd as _, OwnedFd},
+    pin::Pin,
+    process::Stdio,
+    task::{Context, Poll, ready},
+};
+
+use nix::{
+    fcntl::{FcntlArg, FdFlag, OFlag, fcntl},
+    pty::openpty,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf, unix::AsyncFd},
+    process::Command,
+};
+
+use crate::exec::TerminalSize;
+
+nix::ioctl_write_ptr_bad!(tiocswinsz, libc::TIOCSWINSZ, libc::winsize);
+
+fn winsize(size: &TerminalSize) -> libc::winsize {
+    let clamp = |v: u32| u16::try_from(v).unwrap_or(u16::MAX);
+    libc::winsize {
+        ws_row: clamp(size.rows),
+        ws_col: clamp(size.cols),
+        ws_xpixel: clamp(size.xpixel),
+        ws_ypixel: clamp(size.ypixel),
+    }
+}
+
+/// 伪终端主设备的一端, 可以进行异步读写.
+///
+/// 从设备 (slave) 的所有持有者关闭后, 读取会返回 EOF.
+pub struct PtyMaster {
+    fd: AsyncFd<File>,
+}
+
+impl PtyMaster {
+    fn new(fd: OwnedFd) -> io::Result<PtyMaster> {
+        let flags = OFlag::from_bits_truncate(fcntl(&fd, FcntlArg::F_GETFL)?);
+        fcntl(&fd, FcntlArg::F_SETFL(flags | OFlag::O_NONBLOCK))?;
+        fcntl(&fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+        Ok(PtyMaster {
+            fd: AsyncFd::new(File::from(fd))?,
+        })
+    }
+
+    /// 设置终端窗口大小, 内核会向终端的前台进程组发送 `SIGWINCH`.
+    pub fn set_size(&self, size: &TerminalSize) -> io::Result<()> {
+        let winsize = winsize(size);
+        // SAFETY: fd 在 self 存活期间有效, winsize 指针指向栈上的有效结构体.
+        unsafe { tiocswinsz(self.fd.get_ref().as_raw_fd(), &raw const winsize) }?;
+        Ok(())
+    }
+
+    /// 复制一份主设备句柄, 用于将读写分别交给不同的任务.
+    pub fn try_clone(&self) -> io::Result<PtyMaster> {
+        PtyMaster::new(self.fd.get_ref().try_clone()?.into())
+    }
+}
+
+impl AsyncRead for PtyMaster {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            let mut guard = ready!(self.fd.poll_read_ready(cx))?;
+            let unfilled = buf.initialize_unfilled();
+            match guard.try_io(|inner| inner.get_ref().read(unfilled)) {
+                Ok(Ok(len)) => {
+                    buf.advance(len);
+                    return Poll::Ready(Ok(()));
+                }
+                // Linux 下从设备全部关闭后读取主设备会得到 EIO, 视为 EOF.
+                Ok(Err(e)) if e.raw_os_error() == Some(libc::EIO) => return Poll::Ready(Ok(())),
+                Ok(Err(e)) => return Poll::Ready(Err(e)),
+                Err(_would_block) => {}
+            }
+        }
+    }
+}
+
+impl AsyncWrite for PtyMaster {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            let mut guard = ready!(self.fd.poll_write_ready(cx))?;
+            match guard.try_io(|inner| inner.get_ref().write(buf)) {
+                Ok(result) => return Poll::Ready(result),
+                Err(_would_block) => {}
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// 创建一个伪终端, 并将 [`Command`] 的标准输入输出都设置为其从设备,
+/// 子进程会在新的会话中启动, 并以该伪终端为控制终端.
+///
+/// 伪终端的窗口大小为 `size`, 使启动时就读取窗口大小的程序 (vim, less 等) 得到正确的大小.
+///
+/// 返回的 [`PtyMaster`] 用于和子进程交互, [`Command`] 启动子进程后应当尽快被丢弃,
+/// 否则其持有的从设备不会关闭, 子进程退出后读取主设备也不会结束.
+pub fn attach_pty(command: &mut Command, size: Option<&TerminalSize>) -> io::Result<PtyMaster> {
+    let pty = openpty(size.map(winsize).as_ref(), None)?;
+    let master = PtyMaster::new(pty.master)?;
+    command
+        .stdin(Stdio::from(pty.slave.try_clone()?))
+        .stdout(Stdio::from(pty.slave.try_clone()?))
+        .stderr(Stdio::from(pty.slave));
+    // SAFETY: 闭包中只调用了 async-signal-safe 的系统调用.
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setsid() == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            #[allow(clippy::cast_lossless, clippy::useless_conversion)]
+            if libc::ioctl(0, libc::TIOCSCTTY.into(), 0) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    Ok(master)
+}
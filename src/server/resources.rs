@@ -0,0 +1,207 @@
+//! 子进程的资源限制, 仅支持 Linux.
+//!
+//! `setrlimit` 在子进程执行程序前设置. 内存和 CPU 使用率的限制需要服务端配置一个被委派的 cgroup v2 子树,
+//! 服务端在其中为每个子进程创建一个 cgroup, 子进程在执行程序前把自己移入该 cgroup.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io,
+    path::PathBuf,
+};
+
+use tracing::debug;
+
+use crate::exec::ResourceLimits;
+
+/// cgroup `cpu.max` 中的周期 (微秒).
+const CPU_PERIOD: u64 = 100_000;
+
+fn min(requested: Option<u64>, max: Option<u64>) -> Option<u64> {
+    match (requested, max) {
+        (Some(requested), Some(max)) => Some(requested.min(max)),
+        (requested, max) => requested.or(max),
+    }
+}
+
+/// 客户端请求的每一项限制都不能超过服务端的上限, 客户端没有请求的项使用上限.
+pub fn limit(requested: Option<&ResourceLimits>, max: &ResourceLimits) -> ResourceLimits {
+    let requested = requested.copied().unwrap_or_default();
+    ResourceLimits {
+        cpu_time: min(requested.cpu_time, max.cpu_time),
+        address_space: min(requested.address_space, max.address_space),
+        open_files: min(requested.open_files, max.open_files),
+        processes: min(requested.processes, max.processes),
+        core_size: min(requested.core_size, max.core_size),
+        memory: min(requested.memory, max.memory),
+        cpu_percent: min(requested.cpu_percent, max.cpu_percent),
+    }
+}
+
+pub fn is_empty(limits: &ResourceLimits) -> bool {
+    *limits == ResourceLimits::default()
+}
+
+/// 是否有需要 cgroup 才能实现的限制.
+pub fn needs_cgroup(limits: &ResourceLimits) -> bool {
+    limits.memory.is_some() || limits.cpu_percent.is_some()
+}
+
+/// 每个周期中可以使用的 CPU 时间 (微秒), `percent` 由客户端指定, 可能非常大.
+fn cpu_quota(percent: u64) -> u64 {
+    (percent.saturating_mul(CPU_PERIOD) / 100).max(1000)
+}
+
+/// 服务端被委派的 cgroup v2 子树, 子进程的 cgroup 创建在其下.
+///
+/// 该 cgroup 自己不能包含进程, 服务端需要有权限写入它和服务端所在 cgroup 的共同祖先的 `cgroup.procs`.
+#[derive(Debug, Clone)]
+pub struct CgroupRoot {
+    path: PathBuf,
+}
+
+impl CgroupRoot {
+    /// 检查 `path` 是可以使用 memory 和 cpu 控制器的 cgroup v2 目录, 并为子 cgroup 启用这两个控制器.
+    pub fn new(path: PathBuf) -> io::Result<CgroupRoot> {
+        let controllers = fs::read_to_string(path.join("cgroup.controllers"))?;
+        for controller in ["memory", "cpu"] {
+            if !controllers.split_whitespace().any(|it| it == controller) {
+                return Err(io::Error::other(format!(
+                    "controller `{controller}` is not available in {}",
+                    path.display()
+                )));
+            }
+        }
+        fs::write(path.join("cgroup.subtree_control"), "+memory +cpu")?;
+        Ok(CgroupRoot { path })
+    }
+
+    /// 创建一个应用了 `limits` 中内存和 CPU 使用率限制的 cgroup.
+    pub fn create(&self, limits: &ResourceLimits) -> io::Result<Cgroup> {
+        let name = format!("rex-{:016x}", rand::random::<u64>());
+        let path = self.path.join(name);
+        fs::create_dir(&path)?;
+        // 之后的步骤失败时, 丢弃 cgroup 会删除刚创建的目录.
+        let cgroup = Cgroup {
+            procs: OpenOptions::new()
+                .write(true)
+                .open(path.join("cgroup.procs"))?,
+            path,
+        };
+        if let Some(memory) = limits.memory {
+            cgroup.write("memory.max", &memory.to_string())?;
+            // 没有启用 swap 时不存在该文件.
+            cgroup.write("memory.swap.max", "0").ok();
+        }
+        if let Some(percent) = limits.cpu_percent {
+            cgroup.write("cpu.max", &format!("{} {CPU_PERIOD}", cpu_quota(percent)))?;
+        }
+        Ok(cgroup)
+    }
+}
+
+/// 一个子进程所在的 cgroup, 被丢弃时删除.
+#[derive(Debug)]
+pub struct Cgroup {
+    path: PathBuf,
+    /// 在子进程中写入 `0` 以将子进程移入该 cgroup.
+    procs: File,
+}
+
+impl Cgroup {
+    fn write(&self, file: &str, content: &str) -> io::Result<()> {
+        fs::write(self.path.join(file), content)
+    }
+
+    /// cgroup 中是否有进程因为超出内存限制而被杀死.
+    pub fn oom_killed(&self) -> bool {
+        let Ok(events) = fs::read_to_string(self.path.join("memory.events")) else {
+            return false;
+        };
+        events.lines().any(|line| {
+            line.strip_prefix("oom_kill ")
+                .and_then(|count| count.trim().parse::<u64>().ok())
+                .is_some_and(|count| count > 0)
+        })
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        // 泄露的后台进程还在其中时无法删除.
+        if let Err(e) = fs::remove_dir(&self.path) {
+            debug!("failed to remove cgroup {}: {e}", self.path.display());
+        }
+    }
+}
+
+/// 设置软限制和硬限制, 不超过当前的硬限制.
+///
+/// CPU 时间的硬限制比软限制多一秒, 使子进程先收到可以识别的 `SIGXCPU`.
+#[cfg(target_os = "linux")]
+fn set_rlimit(resource: nix::sys::resource::Resource, limit: u64) -> nix::Result<()> {
+    use nix::sys::resource::{Resource, getrlimit, setrlimit};
+
+    let (_, max) = getrlimit(resource)?;
+    let soft = limit.min(max);
+    let hard = if resource == Resource::RLIMIT_CPU {
+        soft.saturating_add(1).min(max)
+    } else {
+        soft
+    };
+    setrlimit(resource, soft, hard)
+}
+
+/// 在子进程执行程序前设置资源限制, 并将其移入 `cgroup`.
+#[cfg(target_os = "linux")]
+pub fn apply(
+    command: &mut tokio::process::Command,
+    limits: &ResourceLimits,
+    cgroup: Option<&Cgroup>,
+) {
+    use std::os::fd::AsRawFd as _;
+
+    use nix::sys::resource::Resource;
+
+    if is_empty(limits) {
+        return;
+    }
+    let rlimits = [
+        (Resource::RLIMIT_CPU, limits.cpu_time),
+        (Resource::RLIMIT_AS, limits.address_space),
+        (Resource::RLIMIT_NOFILE, limits.open_files),
+        (Resource::RLIMIT_NPROC, limits.processes),
+        (Resource::RLIMIT_CORE, limits.core_size),
+    ];
+    let procs = cgroup.map(|it| it.procs.as_raw_fd());
+    // SAFETY: 闭包在 fork 之后, exec 之前运行, 只进行了系统调用, 没有分配内存或获取锁.
+    unsafe {
+        command.pre_exec(move || {
+            if let Some(procs) = procs
+                && libc::write(procs, b"0".as_ptr().cast(), 1) < 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+            for (resource, limit) in rlimits {
+                if let Some(limit) = limit {
+                    set_rlimit(resource, limit)?;
+                }
+            }
+            Ok(())
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CPU_PERIOD, cpu_quota};
+
+    #[test]
+    fn cpu_quota_bounds() {
+        assert_eq!(cpu_quota(50), CPU_PERIOD / 2);
+        assert_eq!(cpu_quota(200), CPU_PERIOD * 2);
+        // 过小的配额会被提高到 cgroup 允许的最小值.
+        assert_eq!(cpu_quota(0), 1000);
+        // 过大的值不会溢出.
+        assert_eq!(cpu_quota(u64::MAX), u64::MAX / 100);
+    }
+}
@@ -0,0 +1,460 @@
+//! 执行会话: 每次执行都对应一个会话, 使子进程的生命周期与客户端连接解耦.
+//!
+//! `leak = true` 的会话在连接断开后仍然保留在 [`SessionRegistry`] 中,
+//! 期间的输出保存在有界的缓冲区里, 之后可以通过 `Attach` 重新连接.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tonic::{Status, Streaming};
+use tracing::debug;
+
+use crate::exec::{
+    ExecuteRequestChunk, ExitInfo, ProgramOutput, SessionInfo, SessionStarted,
+    SessionState as ExecSessionState, StdinEof, execute_request_chunk::RequestChunk,
+    program_output::Payload,
+};
+use crate::server::auth::PeerIdentity;
+
+/// 每个会话保留的最近输出的字节数.
+pub const OUTPUT_BUFFER_SIZE: usize = 64 * 1024;
+/// 已经结束但结果没有被取回的会话保留的时间.
+pub const FINISHED_SESSION_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// 最多保留的已经结束但结果没有被取回的会话数, 超出时删除最早结束的会话.
+pub const MAX_FINISHED_SESSIONS: usize = 256;
+/// 重新连接时发送缓冲区中的输出的最长时间, 期间持有会话的锁, 不能被不接收输出的客户端一直占用.
+const REPLAY_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub type OutputSender = Sender<Result<ProgramOutput, Status>>;
+
+/// 发送给持有子进程的会话任务的控制消息.
+#[derive(Debug)]
+pub enum Control {
+    /// 客户端发送的请求.
+    Request(RequestChunk),
+    /// `leak = false` 的会话的客户端连接断开.
+    Disconnected,
+    /// 服务端正在关闭, 等待时间结束后子进程仍未退出.
+    Shutdown,
+}
+
+/// 会话中子进程的启动信息.
+#[derive(Debug, Clone)]
+pub struct SessionMeta {
+    pub pid: Option<u32>,
+    pub executable: String,
+    pub args: Vec<String>,
+    pub current_dir: String,
+    /// 启动会话的客户端地址.
+    pub peer_address: String,
+    /// 启动会话的客户端证书中的身份.
+    pub owner: Option<PeerIdentity>,
+    pub leak: bool,
+    pub pty: bool,
+}
+
+/// 所有尚未结束, 或者已经结束但结果还没有被客户端取回的会话.
+#[derive(Default)]
+pub struct SessionRegistry {
+    sessions: Mutex<HashMap<String, Arc<Session>>>,
+    /// 已经通过 [`SessionRegistry::reserve`] 预留, 但还没有创建的会话数, 只在持有 `sessions` 的锁时增加.
+    reserved: Arc<AtomicUsize>,
+}
+
+/// 为即将创建的会话预留的名额, 创建会话或者被丢弃时归还.
+pub struct SessionReservation {
+    reserved: Arc<AtomicUsize>,
+}
+
+impl Drop for SessionReservation {
+    fn drop(&mut self) {
+        self.reserved.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// 删除超过 [`FINISHED_SESSION_TTL`] 的已经结束的会话, 并使其数量不超过 [`MAX_FINISHED_SESSIONS`].
+fn evict_finished(sessions: &mut HashMap<String, Arc<Session>>) {
+    let now = Instant::now();
+    let mut finished: Vec<_> = sessions
+        .values()
+        .filter_map(|session| Some(((*session.finished_at.lock().unwrap())?, session.id.clone())))
+        .collect();
+    finished.sort();
+    let excess = finished.len().saturating_sub(MAX_FINISHED_SESSIONS);
+    for (index, (finished_at, id)) in finished.into_iter().enumerate() {
+        if index < excess || now.duration_since(finished_at) > FINISHED_SESSION_TTL {
+            debug!("evicting finished session {id}");
+            sessions.remove(&id);
+        }
+    }
+}
+
+impl SessionRegistry {
+    /// 为一个新的会话预留名额, 会话 (包括已经预留的) 数量达到 `max_sessions` 时返回错误.
+    ///
+    /// 子进程启动之前预留, 使并发的请求不会一起超过上限.
+    pub fn reserve(&self, max_sessions: Option<usize>) -> Result<SessionReservation, Status> {
+        let mut sessions = self.sessions.lock().unwrap();
+        evict_finished(&mut sessions);
+        if let Some(max_sessions) = max_sessions
+            && sessions.len() + self.reserved.load(Ordering::SeqCst) >= max_sessions
+        {
+            return Err(Status::resource_exhausted(format!(
+                "too many sessions, the server allows at most {max_sessions}"
+            )));
+        }
+        self.reserved.fetch_add(1, Ordering::SeqCst);
+        Ok(SessionReservation {
+            reserved: self.reserved.clone(),
+        })
+    }
+
+    /// 使用预留的名额创建并登记一个新的会话, 返回会话和会话任务接收控制消息的一端.
+    ///
+    /// 同时删除结果长时间没有被取回的会话.
+    pub fn create(
+        &self,
+        meta: SessionMeta,
+        reservation: SessionReservation,
+    ) -> (Arc<Session>, Receiver<Control>) {
+        let (control, control_rx) = mpsc::channel(10);
+        let mut sessions = self.sessions.lock().unwrap();
+        evict_finished(&mut sessions);
+        let id = loop {
+            let id = format!("{:016x}", rand::random::<u64>());
+            if !sessions.contains_key(&id) {
+                break id;
+            }
+        };
+        let session = Arc::new(Session {
+            id: id.clone(),
+            meta,
+            start_time: SystemTime::now(),
+            finished_at: Mutex::new(None),
+            control,
+            state: tokio::sync::Mutex::default(),
+        });
+        sessions.insert(id, session.clone());
+        drop(reservation);
+        (session, control_rx)
+    }
+
+    pub fn get(&self, id: &str) -> Option<Arc<Session>> {
+        self.sessions.lock().unwrap().get(id).cloned()
+    }
+
+    pub fn remove(&self, id: &str) {
+        self.sessions.lock().unwrap().remove(id);
+    }
+
+    /// 所有会话, 按启动时间排序.
+    pub fn list(&self) -> Vec<Arc<Session>> {
+        let mut sessions: Vec<_> = self.sessions.lock().unwrap().values().cloned().collect();
+        sessions.sort_by_key(|session| session.start_time);
+        sessions
+    }
+}
+
+#[derive(Default)]
+struct SessionState {
+    /// 最近的 stdout 和 stderr 输出.
+    output: VecDeque<ProgramOutput>,
+    output_len: usize,
+    /// 当前连接的客户端.
+    attached: Option<OutputSender>,
+    exit_info: Option<ExitInfo>,
+}
+
+pub struct Session {
+    id: String,
+    meta: SessionMeta,
+    start_time: SystemTime,
+    /// 子进程结束的时间, 用于删除结果没有被取回的会话.
+    finished_at: Mutex<Option<Instant>>,
+    control: Sender<Control>,
+    state: tokio::sync::Mutex<SessionState>,
+}
+
+impl Session {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn owner(&self) -> Option<&PeerIdentity> {
+        self.meta.owner.as_ref()
+    }
+
+    pub fn leak(&self) -> bool {
+        self.meta.leak
+    }
+
+    fn started_message(&self) -> ProgramOutput {
+        ProgramOutput {
+            payload: Some(Payload::SessionStarted(SessionStarted {
+                session_id: self.id.clone(),
+                pty: self.meta.pty,
+            })),
+        }
+    }
+
+    /// 会话的当前信息.
+    pub async fn info(&self) -> SessionInfo {
+        let state = self.state.lock().await;
+        let session_state = if state.exit_info.is_some() {
+            ExecSessionState::Exited
+        } else if state.attached.as_ref().is_some_and(|it| !it.is_closed()) {
+            ExecSessionState::Running
+        } else {
+            ExecSessionState::Detached
+        };
+        SessionInfo {
+            session_id: self.id.clone(),
+            pid: self.meta.pid.filter(|_| state.exit_info.is_none()),
+            executable: self.meta.executable.clone(),
+            args: self.meta.args.clone(),
+            current_dir: self.meta.current_dir.clone(),
+            start_time: self
+                .start_time
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            peer_address: self.meta.peer_address.clone(),
+            state: session_state.into(),
+            leak: self.meta.leak,
+            pty: self.meta.pty,
+            exit_info: state.exit_info.clone(),
+            identity: self
+                .meta
+                .owner
+                .as_ref()
+                .map_or("", PeerIdentity::name)
+                .to_string(),
+        }
+    }
+
+    pub async fn exited(&self) -> bool {
+        self.state.lock().await.exit_info.is_some()
+    }
+
+    /// 将请求转交给持有子进程的会话任务, 不经过客户端连接.
+    pub async fn control(&self, request_chunk: RequestChunk) -> Result<(), Status> {
+        self.control
+            .send(Control::Request(request_chunk))
+            .await
+            .map_err(|_| Status::failed_precondition("session no longer accepts requests"))
+    }
+
+    /// 将客户端连接附加到会话上, 先发送 [`SessionStarted`] 和缓冲区中的输出,
+    /// 如果子进程已经结束, 还会发送退出信息.
+    ///
+    /// # Returns
+    /// 子进程是否已经结束, 结束时不需要再处理该连接的请求.
+    pub async fn attach(&self, tx: OutputSender) -> Result<bool, Status> {
+        let mut state = self.state.lock().await;
+        if state.attached.as_ref().is_some_and(|it| !it.is_closed()) {
+            return Err(Status::failed_precondition(
+                "session is attached to another client",
+            ));
+        }
+        let replay = async {
+            tx.send(Ok(self.started_message())).await.ok();
+            for output in &state.output {
+                tx.send(Ok(output.clone())).await.ok();
+            }
+            if let Some(exit_info) = &state.exit_info {
+                tx.send(Ok(ProgramOutput {
+                    payload: Some(Payload::ExitInfo(exit_info.clone())),
+                }))
+                .await
+                .ok();
+            }
+        };
+        if tokio::time::timeout(REPLAY_TIMEOUT, replay).await.is_err() {
+            return Err(Status::deadline_exceeded("client is not receiving output"));
+        }
+        if state.exit_info.is_some() {
+            return Ok(true);
+        }
+        state.attached = Some(tx);
+        Ok(false)
+    }
+
+    /// 在服务端关闭时终止子进程.
+    pub async fn shutdown(&self) {
+        self.control.send(Control::Shutdown).await.ok();
+    }
+
+    /// 只发送给当前连接的客户端, 不保存在缓冲区中.
+    ///
+    /// 通知是尽力而为的, 客户端没有及时接收输出时直接丢弃, 不等待.
+    pub async fn notify(&self, payload: Payload) {
+        let state = self.state.lock().await;
+        if let Some(tx) = &state.attached {
+            tx.try_send(Ok(ProgramOutput {
+                payload: Some(payload),
+            }))
+            .ok();
+        }
+    }
+
+    pub async fn detach(&self) {
+        self.state.lock().await.attached = None;
+    }
+
+    /// 保存子进程的输出, 并发送给当前连接的客户端.
+    ///
+    /// 发送时不持有会话的锁, 客户端不接收输出时只会阻塞子进程的输出, 不会阻塞关闭服务端等其他操作.
+    pub async fn publish(&self, payload: Payload) {
+        let output = ProgramOutput {
+            payload: Some(payload),
+        };
+        let tx = {
+            let mut state = self.state.lock().await;
+            let len = output_len(&output);
+            state.output.push_back(output.clone());
+            state.output_len += len;
+            while state.output_len > OUTPUT_BUFFER_SIZE && state.output.len() > 1 {
+                let front = state.output.pop_front().unwrap();
+                state.output_len -= output_len(&front);
+            }
+            state.attached.clone()
+        };
+        if let Some(tx) = tx
+            && tx.send(Ok(output)).await.is_err()
+        {
+            debug!("session {} detached", self.id);
+            let mut state = self.state.lock().await;
+            if state
+                .attached
+                .as_ref()
+                .is_some_and(|it| it.same_channel(&tx))
+            {
+                state.attached = None;
+            }
+        }
+    }
+
+    /// 子进程结束时调用, 发送退出信息并断开当前连接.
+    ///
+    /// # Returns
+    /// 退出信息是否已经送达客户端, 没有送达时会话需要继续保留, 等待客户端重新连接.
+    pub async fn finish(&self, exit_info: ExitInfo) -> bool {
+        let tx = {
+            let mut state = self.state.lock().await;
+            state.exit_info = Some(exit_info.clone());
+            state.attached.take()
+        };
+        *self.finished_at.lock().unwrap() = Some(Instant::now());
+        match tx {
+            Some(tx) => tx
+                .send(Ok(ProgramOutput {
+                    payload: Some(Payload::ExitInfo(exit_info)),
+                }))
+                .await
+                .is_ok(),
+            None => false,
+        }
+    }
+
+    /// 处理一个客户端连接的请求流, 直到会话结束或连接断开.
+    ///
+    /// 连接断开时, `leak = false` 的会话的子进程会被杀死, 否则会话只是与该连接分离.
+    pub async fn serve(&self, mut request: Streaming<ExecuteRequestChunk>, tx: OutputSender) {
+        let mut request_closed = false;
+        loop {
+            tokio::select! {
+                msg = request.message(), if !request_closed => match msg {
+                    Ok(Some(ExecuteRequestChunk {
+                        request_chunk: Some(chunk),
+                    })) => {
+                        if self.control.send(Control::Request(chunk)).await.is_err() {
+                            return;
+                        }
+                    }
+                    Ok(Some(_)) => {}
+                    // 客户端不再发送请求, 但仍在接收输出, 之后也不会再有输入.
+                    // `leak = true` 的会话可能被重新连接并继续输入, 需要客户端显式发送 StdinEof.
+                    Ok(None) => {
+                        request_closed = true;
+                        let eof = Control::Request(RequestChunk::StdinEof(StdinEof {}));
+                        if !self.meta.leak && self.control.send(eof).await.is_err() {
+                            return;
+                        }
+                    }
+                    Err(_) => break,
+                },
+                () = tx.closed() => break,
+                () = self.control.closed() => return,
+            }
+        }
+        debug!("client of session {} disconnected", self.id);
+        if self.meta.leak {
+            self.detach().await;
+        } else {
+            self.control.send(Control::Disconnected).await.ok();
+        }
+    }
+}
+
+fn output_len(output: &ProgramOutput) -> usize {
+    match &output.payload {
+        Some(Payload::StdoutChunk(chunk)) => chunk.data.len(),
+        Some(Payload::StderrChunk(chunk)) => chunk.data.len(),
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{MAX_FINISHED_SESSIONS, SessionMeta, SessionRegistry};
+    use crate::exec::ExitInfo;
+
+    fn meta() -> SessionMeta {
+        SessionMeta {
+            pid: None,
+            executable: "/bin/true".into(),
+            args: Vec::new(),
+            current_dir: "/".into(),
+            peer_address: String::new(),
+            owner: None,
+            leak: true,
+            pty: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn evict_finished_sessions() {
+        let registry = SessionRegistry::default();
+        let (first, _control) = registry.create(meta(), registry.reserve(None).unwrap());
+        assert_eq!(first.id().len(), 16);
+        first.finish(ExitInfo::default()).await;
+        for _ in 0..MAX_FINISHED_SESSIONS {
+            let (session, _control) = registry.create(meta(), registry.reserve(None).unwrap());
+            session.finish(ExitInfo::default()).await;
+        }
+        let (running, _control) = registry.create(meta(), registry.reserve(None).unwrap());
+        // 最早结束的会话被删除, 正在运行的会话不受影响.
+        assert_eq!(registry.list().len(), MAX_FINISHED_SESSIONS + 1);
+        assert!(registry.get(first.id()).is_none());
+        assert!(registry.get(running.id()).is_some());
+    }
+
+    #[test]
+    fn reserve_sessions() {
+        let registry = SessionRegistry::default();
+        let reservation = registry.reserve(Some(2)).unwrap();
+        let (_session, _control) = registry.create(meta(), registry.reserve(Some(2)).unwrap());
+        // 预留的名额和已经创建的会话都计入上限.
+        let status = registry.reserve(Some(2)).err().unwrap();
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+        drop(reservation);
+        assert!(registry.reserve(Some(2)).is_ok());
+    }
+}
@@ -0,0 +1,243 @@
+//! 限制同时运行的子进程数, 全局和每个客户端 (按证书中的身份) 分别计数.
+//!
+//! 没有空位时请求可以排队等待, 空位出现时队列中第一个不超过限制的请求获得空位.
+//! 排队期间请求的位置通过 [`Queued`] 发送给客户端.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use tokio::sync::{oneshot, watch};
+use tonic::Status;
+use tracing::debug;
+
+use crate::exec::{ProgramOutput, Queued, program_output::Payload};
+use crate::server::session::OutputSender;
+
+struct Waiter {
+    id: u64,
+    client: String,
+    grant: oneshot::Sender<SlotPermit>,
+}
+
+#[derive(Default)]
+struct State {
+    max_running: Option<usize>,
+    max_running_per_client: Option<usize>,
+    running: usize,
+    /// 每个客户端正在运行的子进程数, 没有运行的客户端不在其中.
+    per_client: HashMap<String, usize>,
+    queue: VecDeque<Waiter>,
+    next_id: u64,
+}
+
+impl State {
+    /// 没有空位时返回拒绝的原因.
+    fn check(&self, client: &str) -> Result<(), String> {
+        if let Some(max) = self.max_running
+            && self.running >= max
+        {
+            return Err(format!(
+                "too many running processes, the server allows at most {max}"
+            ));
+        }
+        if let Some(max) = self.max_running_per_client
+            && self.per_client.get(client).copied().unwrap_or_default() >= max
+        {
+            return Err(format!(
+                "too many running processes for client `{client}`, the server allows at most {max}"
+            ));
+        }
+        Ok(())
+    }
+
+    fn take(&mut self, client: &str) {
+        self.running += 1;
+        *self.per_client.entry(client.to_string()).or_default() += 1;
+    }
+
+    fn release(&mut self, client: &str) {
+        self.running -= 1;
+        if let Some(count) = self.per_client.get_mut(client) {
+            *count -= 1;
+            if *count == 0 {
+                self.per_client.remove(client);
+            }
+        }
+    }
+
+    /// 请求在队列中的位置 (从 1 开始) 和队列的长度.
+    fn position(&self, id: u64) -> Option<Queued> {
+        let index = self.queue.iter().position(|waiter| waiter.id == id)?;
+        Some(Queued {
+            position: u32::try_from(index + 1).unwrap_or(u32::MAX),
+            length: u32::try_from(self.queue.len()).unwrap_or(u32::MAX),
+        })
+    }
+}
+
+/// 子进程运行所占用的空位, 丢弃时释放.
+pub struct SlotPermit {
+    slots: Arc<Slots>,
+    client: String,
+}
+
+impl Drop for SlotPermit {
+    fn drop(&mut self) {
+        // 在同一个临界区内把空位交给排队的请求, 否则新的请求可能在两次加锁之间抢走空位.
+        let mut state = self.slots.state.lock().unwrap();
+        state.release(&self.client);
+        self.slots.dispatch(state);
+    }
+}
+
+/// 排队中的请求, 放弃等待时从队列中移除.
+struct QueueEntry<'a> {
+    slots: &'a Arc<Slots>,
+    id: u64,
+}
+
+impl Drop for QueueEntry<'_> {
+    fn drop(&mut self) {
+        let mut state = self.slots.state.lock().unwrap();
+        if let Some(index) = state.queue.iter().position(|waiter| waiter.id == self.id) {
+            state.queue.remove(index);
+            drop(state);
+            self.slots.changed.send_modify(|version| *version += 1);
+        }
+    }
+}
+
+pub struct Slots {
+    state: Mutex<State>,
+    /// 队列每次变化时递增, 用于通知排队的请求更新位置.
+    changed: watch::Sender<u64>,
+}
+
+impl Default for Slots {
+    fn default() -> Self {
+        Slots {
+            state: Mutex::default(),
+            changed: watch::Sender::new(0),
+        }
+    }
+}
+
+impl Slots {
+    /// 修改上限, 已经运行的子进程不受影响, 上限提高时排队的请求会获得空位.
+    pub fn set_limits(
+        self: &Arc<Self>,
+        max_running: Option<usize>,
+        max_running_per_client: Option<usize>,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        state.max_running = max_running;
+        state.max_running_per_client = max_running_per_client;
+        self.dispatch(state);
+    }
+
+    /// 按顺序将空位分配给队列中不超过限制的请求, 之后释放 `state` 的锁.
+    fn dispatch(self: &Arc<Self>, mut state: MutexGuard<'_, State>) {
+        let mut abandoned = Vec::new();
+        let mut changed = false;
+        let mut index = 0;
+        while index < state.queue.len() {
+            if state.check(&state.queue[index].client).is_err() {
+                index += 1;
+                continue;
+            }
+            let waiter = state.queue.remove(index).expect("index is in bounds");
+            changed = true;
+            state.take(&waiter.client);
+            let permit = SlotPermit {
+                slots: self.clone(),
+                client: waiter.client,
+            };
+            if let Err(permit) = waiter.grant.send(permit) {
+                abandoned.push(permit);
+            }
+        }
+        drop(state);
+        if changed {
+            self.changed.send_modify(|version| *version += 1);
+        }
+        // 已经放弃等待的请求的空位需要在释放锁之后丢弃, 丢弃时会再次分配.
+        drop(abandoned);
+    }
+
+    /// 为 `client` 获取一个空位.
+    ///
+    /// `max_wait` 为 [`None`] 时没有空位立即返回 `ResourceExhausted`,
+    /// 否则排队等待, 期间通过 `tx` 向客户端发送排队位置, 超过 `max_wait` 仍然没有空位时返回 `ResourceExhausted`.
+    pub async fn acquire(
+        self: &Arc<Self>,
+        client: &str,
+        max_wait: Option<Duration>,
+        tx: &OutputSender,
+    ) -> Result<SlotPermit, Status> {
+        let (grant, mut granted) = oneshot::channel();
+        let id = {
+            let mut state = self.state.lock().unwrap();
+            // 队列中的请求都已经超过了限制, 新的请求不需要排在它们后面.
+            let reason = match state.check(client) {
+                Ok(()) => {
+                    state.take(client);
+                    return Ok(SlotPermit {
+                        slots: self.clone(),
+                        client: client.to_string(),
+                    });
+                }
+                Err(reason) => reason,
+            };
+            if max_wait.is_none() {
+                return Err(Status::resource_exhausted(reason));
+            }
+            let id = state.next_id;
+            state.next_id += 1;
+            state.queue.push_back(Waiter {
+                id,
+                client: client.to_string(),
+                grant,
+            });
+            id
+        };
+        let _entry = QueueEntry { slots: self, id };
+        self.changed.send_modify(|version| *version += 1);
+        let mut changed = self.changed.subscribe();
+        changed.mark_changed();
+        let deadline = tokio::time::sleep(max_wait.unwrap_or_default());
+        tokio::pin!(deadline);
+        let mut last_position = None;
+        loop {
+            tokio::select! {
+                permit = &mut granted => {
+                    return permit.map_err(|_| Status::internal("slot queue is closed"));
+                }
+                _ = changed.changed() => {
+                    let position = self.state.lock().unwrap().position(id);
+                    if position.is_some() && position != last_position {
+                        debug!("waiting for slot {position:?}");
+                        let output = ProgramOutput {
+                            payload: position.map(Payload::Queued),
+                        };
+                        if tx.send(Ok(output)).await.is_err() {
+                            return Err(Status::cancelled("client disconnected"));
+                        }
+                        last_position = position;
+                    }
+                }
+                () = &mut deadline => {
+                    return Err(Status::resource_exhausted(format!(
+                        "no slot became free within {}s",
+                        max_wait.unwrap_or_default().as_secs()
+                    )));
+                }
+                () = tx.closed() => {
+                    return Err(Status::cancelled("client disconnected"));
+                }
+            }
+        }
+    }
+}
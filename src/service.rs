@@ -0,0 +1,421 @@
+//! 设置服务端开机自启: 生成以当前 `rex` 可执行文件启动服务端的 systemd 用户单元或 XDG autostart 条目.
+//!
+//! systemd 用户单元随图形会话启动和停止, 也可以同时生成 socket 单元, 由第一个连接启动服务端.
+//! 使用默认目录时, systemd 用户单元会通过 `systemctl --user` 启用并立即启动, 指定目录时只生成文件.
+
+use std::{
+    env,
+    ffi::OsString,
+    fs, io,
+    path::{Path, PathBuf, absolute},
+    process,
+};
+
+use crate::{
+    Error,
+    args::{
+        InstallServiceArgs, ServerArgs, ServiceArgs, ServiceKind, ServiceStatusArgs,
+        UninstallServiceArgs,
+    },
+    server::listener::BindAddress,
+};
+
+pub const SYSTEMD_UNIT: &str = "rex.service";
+pub const SYSTEMD_SOCKET: &str = "rex.socket";
+pub const AUTOSTART_ENTRY: &str = "rex.desktop";
+
+/// 不需要加引号的字符.
+fn is_plain(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "/._-:,=@+[]".contains(c)
+}
+
+/// 按 systemd 单元文件的规则转义一个参数, `%` 和 `$` 会被 systemd 展开.
+fn systemd_quote(arg: &str) -> String {
+    let arg = arg.replace('%', "%%").replace('$', "$$");
+    if !arg.is_empty() && arg.chars().all(|c| is_plain(c) || c == '%' || c == '$') {
+        return arg;
+    }
+    let escaped = arg
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n");
+    format!("\"{escaped}\"")
+}
+
+/// 按 Desktop Entry 规范中 `Exec` 的规则转义一个参数, 不包括键值文件本身的转义.
+fn desktop_quote(arg: &str) -> String {
+    let arg = arg.replace('%', "%%");
+    if !arg.is_empty() && arg.chars().all(|c| is_plain(c) || c == '%') {
+        return arg;
+    }
+    let mut quoted = String::from("\"");
+    for c in arg.chars() {
+        if matches!(c, '"' | '`' | '$' | '\\') {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+impl ServiceKind {
+    pub fn file_name(self) -> &'static str {
+        match self {
+            ServiceKind::Systemd => SYSTEMD_UNIT,
+            ServiceKind::Autostart => AUTOSTART_ENTRY,
+        }
+    }
+
+    /// systemd 或桌面环境查找该文件的目录.
+    pub fn default_dir(self) -> Result<PathBuf, Error> {
+        let config = match env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => {
+                let home = env::var(if cfg!(windows) { "USERPROFILE" } else { "HOME" })?;
+                PathBuf::from(home).join(".config")
+            }
+        };
+        Ok(match self {
+            ServiceKind::Systemd => config.join("systemd").join("user"),
+            ServiceKind::Autostart => config.join("autostart"),
+        })
+    }
+
+    /// 文件中启动服务端的命令行所在的键.
+    fn exec_key(self) -> &'static str {
+        match self {
+            ServiceKind::Systemd => "ExecStart=",
+            ServiceKind::Autostart => "Exec=",
+        }
+    }
+
+    /// 转义后的命令行.
+    fn command_line(self, program: &Path) -> Result<String, Error> {
+        let program = program.to_str().ok_or_else(|| {
+            Error::InvalidArgs(format!("{} is not valid UTF-8", program.display()))
+        })?;
+        Ok(match self {
+            ServiceKind::Systemd => systemd_quote(program),
+            // Exec 的值本身也是需要转义反斜杠的字符串.
+            ServiceKind::Autostart => desktop_quote(program).replace('\\', "\\\\"),
+        })
+    }
+
+    /// 以 `program server <args>` 启动服务端的文件内容.
+    pub fn render(self, program: &Path, args: &[OsString]) -> Result<String, Error> {
+        let mut command = self.command_line(program)?;
+        command.push_str(" server");
+        for arg in args {
+            let arg = arg.to_str().ok_or_else(|| {
+                Error::InvalidArgs(format!("{} is not valid UTF-8", arg.display()))
+            })?;
+            command.push(' ');
+            command.push_str(&match self {
+                ServiceKind::Systemd => systemd_quote(arg),
+                ServiceKind::Autostart => desktop_quote(arg).replace('\\', "\\\\"),
+            });
+        }
+        Ok(match self {
+            // 服务端需要图形会话的环境变量, 登录桌面后才启动, 退出桌面时停止.
+            // 服务端自己在关闭时终止子进程, `leak = true` 的子进程需要在服务端退出后继续运行.
+            ServiceKind::Systemd => format!(
+                "# Generated by `rex install-service`.\n\
+                 [Unit]\n\
+                 Description=rex server\n\
+                 PartOf=graphical-session.target\n\
+                 After=graphical-session.target\n\
+                 \n\
+                 [Service]\n\
+                 ExecStart={command}\n\
+                 Restart=on-failure\n\
+                 KillMode=process\n\
+                 \n\
+                 [Install]\n\
+                 WantedBy=graphical-session.target\n"
+            ),
+            ServiceKind::Autostart => format!(
+                "# Generated by `rex install-service`.\n\
+                 [Desktop Entry]\n\
+                 Type=Application\n\
+                 Name=rex server\n\
+                 Exec={command}\n\
+                 Terminal=false\n\
+                 NoDisplay=true\n\
+                 X-GNOME-Autostart-enabled=true\n"
+            ),
+        })
+    }
+}
+
+/// 在 `bind` 中的地址上监听, 并在第一个连接时启动 `rex.service` 的 socket 单元.
+///
+/// 套接字文件的权限由 socket 单元设置, 与服务端的默认值一样只允许所有者连接.
+pub fn render_socket(bind: &[BindAddress]) -> Result<String, Error> {
+    if bind.is_empty() {
+        return Err(Error::InvalidArgs(
+            "the socket unit needs at least one `--bind` address".into(),
+        ));
+    }
+    let mut listen = String::new();
+    for bind_address in bind {
+        let address = match bind_address {
+            BindAddress::Tcp(addr) => addr.to_string(),
+            BindAddress::Unix(path) => path
+                .to_str()
+                .ok_or_else(|| {
+                    Error::InvalidArgs(format!("{} is not valid UTF-8", path.display()))
+                })?
+                .to_string(),
+        };
+        listen.push_str("ListenStream=");
+        listen.push_str(&address.replace('%', "%%"));
+        listen.push('\n');
+    }
+    Ok(format!(
+        "# Generated by `rex install-service`.\n\
+         [Unit]\n\
+         Description=rex server socket\n\
+         PartOf=graphical-session.target\n\
+         After=graphical-session.target\n\
+         \n\
+         [Socket]\n\
+         {listen}\
+         SocketMode=0600\n\
+         \n\
+         [Install]\n\
+         WantedBy=graphical-session.target\n"
+    ))
+}
+
+/// 服务端由 systemd 或桌面环境以其他工作目录启动, 参数中的相对路径需要转换为绝对路径.
+fn absolute_paths(server: &ServerArgs) -> io::Result<ServerArgs> {
+    let mut server = server.clone();
+    for path in [
+        &mut server.config,
+        &mut server.cert_dir,
+        &mut server.session_env_file,
+        &mut server.file_root,
+    ]
+    .into_iter()
+    .flatten()
+    {
+        *path = absolute(&*path)?;
+    }
+    for bind_address in &mut server.bind_address {
+        if let BindAddress::Unix(path) = bind_address {
+            *path = absolute(&*path)?;
+        }
+    }
+    Ok(server)
+}
+
+/// 在 `dir` 中写入以 `program` 和 `server` 参数启动服务端的文件, 返回文件路径.
+pub fn install(
+    kind: ServiceKind,
+    dir: &Path,
+    program: &Path,
+    server: &ServerArgs,
+) -> Result<PathBuf, Error> {
+    let content = kind.render(program, &absolute_paths(server)?.to_args())?;
+    fs::create_dir_all(dir)?;
+    let path = dir.join(kind.file_name());
+    fs::write(&path, content)?;
+    Ok(path)
+}
+
+/// 在 `dir` 中写入监听 `server` 的 `--bind` 地址的 socket 单元, 返回文件路径.
+pub fn install_socket(dir: &Path, server: &ServerArgs) -> Result<PathBuf, Error> {
+    let content = render_socket(&absolute_paths(server)?.bind_address)?;
+    fs::create_dir_all(dir)?;
+    let path = dir.join(SYSTEMD_SOCKET);
+    fs::write(&path, content)?;
+    Ok(path)
+}
+
+/// 删除文件, 返回文件是否存在.
+fn remove(path: &Path) -> Result<bool, Error> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// 删除 `dir` 中的文件, systemd 用户单元还会删除 socket 单元, 返回文件是否存在.
+pub fn uninstall(kind: ServiceKind, dir: &Path) -> Result<bool, Error> {
+    if kind == ServiceKind::Systemd {
+        remove(&dir.join(SYSTEMD_SOCKET))?;
+    }
+    remove(&dir.join(kind.file_name()))
+}
+
+/// 已经安装的文件.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstalledService {
+    pub kind: ServiceKind,
+    pub path: PathBuf,
+    /// 文件中启动服务端的命令行 (转义后), 文件被修改过时可能没有.
+    pub command: Option<String>,
+}
+
+impl InstalledService {
+    /// 是否以 `program` 启动服务端, 可执行文件被移动或更新到其他位置后需要重新安装.
+    pub fn starts(&self, program: &Path) -> bool {
+        let (Some(command), Ok(program)) = (&self.command, self.kind.command_line(program)) else {
+            return false;
+        };
+        command
+            .strip_prefix(&program)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with(' '))
+    }
+}
+
+/// 读取 `dir` 中已经安装的文件, 没有安装时返回 [`None`].
+pub fn status(kind: ServiceKind, dir: &Path) -> Result<Option<InstalledService>, Error> {
+    let path = dir.join(kind.file_name());
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let command = content
+        .lines()
+        .find_map(|line| line.strip_prefix(kind.exec_key()))
+        .map(str::to_string);
+    Ok(Some(InstalledService {
+        kind,
+        path,
+        command,
+    }))
+}
+
+/// 使用默认目录的 systemd 用户单元时才需要调用 `systemctl`.
+fn uses_systemctl(service: &ServiceArgs) -> bool {
+    service.kind == ServiceKind::Systemd && service.dir.is_none()
+}
+
+fn service_dir(service: &ServiceArgs) -> Result<PathBuf, Error> {
+    match &service.dir {
+        Some(dir) => Ok(dir.clone()),
+        None => service.kind.default_dir(),
+    }
+}
+
+fn systemctl(args: &[&str]) -> Result<(), Error> {
+    let status = process::Command::new("systemctl")
+        .arg("--user")
+        .args(args)
+        .status()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(Error::InvalidArgs(format!(
+            "`systemctl --user {}` failed: {status}",
+            args.join(" ")
+        )))
+    }
+}
+
+pub fn install_service_main(args: InstallServiceArgs) -> Result<(), Error> {
+    if args.socket && args.service.kind != ServiceKind::Systemd {
+        return Err(Error::InvalidArgs(
+            "`--socket` is only supported by `--kind systemd`".into(),
+        ));
+    }
+    let dir = service_dir(&args.service)?;
+    let path = install(args.service.kind, &dir, &env::current_exe()?, &args.server)?;
+    println!("installed {}", path.display());
+    let manage = uses_systemctl(&args.service);
+    let socket = dir.join(SYSTEMD_SOCKET);
+    if args.socket {
+        println!(
+            "installed {}",
+            install_socket(&dir, &args.server)?.display()
+        );
+    } else if args.service.kind == ServiceKind::Systemd && socket.exists() {
+        // 之前安装的 socket 单元不再使用.
+        if manage {
+            systemctl(&["disable", "--now", SYSTEMD_SOCKET])?;
+        }
+        remove(&socket)?;
+        println!("removed {}", socket.display());
+    }
+    if manage {
+        systemctl(&["daemon-reload"])?;
+        if args.socket {
+            // 服务端改为由第一个连接启动, 已经在运行的服务端会占用监听地址.
+            systemctl(&["disable", "--now", SYSTEMD_UNIT])?;
+            systemctl(&["enable", "--now", SYSTEMD_SOCKET])?;
+        } else {
+            systemctl(&["enable", "--now", SYSTEMD_UNIT])?;
+        }
+    }
+    Ok(())
+}
+
+pub fn uninstall_service_main(args: UninstallServiceArgs) -> Result<(), Error> {
+    let dir = service_dir(&args.service)?;
+    let kind = args.service.kind;
+    let manage = uses_systemctl(&args.service);
+    if manage {
+        for unit in [SYSTEMD_SOCKET, SYSTEMD_UNIT] {
+            if dir.join(unit).exists() {
+                systemctl(&["disable", "--now", unit])?;
+            }
+        }
+    }
+    if uninstall(kind, &dir)? {
+        println!("removed {}", dir.join(kind.file_name()).display());
+    } else {
+        println!("{} is not installed", dir.join(kind.file_name()).display());
+    }
+    if manage {
+        systemctl(&["daemon-reload"])?;
+    }
+    Ok(())
+}
+
+pub fn service_status_main(args: ServiceStatusArgs) -> Result<(), Error> {
+    let dir = service_dir(&args.service)?;
+    let kind = args.service.kind;
+    let Some(installed) = status(kind, &dir)? else {
+        println!("{} is not installed", dir.join(kind.file_name()).display());
+        return Ok(());
+    };
+    println!("installed: {}", installed.path.display());
+    let socket = dir.join(SYSTEMD_SOCKET);
+    let socket = (kind == ServiceKind::Systemd && socket.exists()).then_some(socket);
+    if let Some(socket) = &socket {
+        println!("socket: {}", socket.display());
+    }
+    if let Some(command) = &installed.command {
+        println!("command: {command}");
+    }
+    let program = env::current_exe()?;
+    if !installed.starts(&program) {
+        println!(
+            "warning: it does not start {}, run `rex install-service` again to update it",
+            program.display()
+        );
+    }
+    if uses_systemctl(&args.service) {
+        let units = if socket.is_some() {
+            &[SYSTEMD_UNIT, SYSTEMD_SOCKET][..]
+        } else {
+            &[SYSTEMD_UNIT][..]
+        };
+        for unit in units {
+            for query in ["is-enabled", "is-active"] {
+                let output = process::Command::new("systemctl")
+                    .args(["--user", query, unit])
+                    .output()?;
+                println!(
+                    "{unit} {query}: {}",
+                    String::from_utf8_lossy(&output.stdout).trim()
+                );
+            }
+        }
+    }
+    Ok(())
+}
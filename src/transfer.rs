@@ -0,0 +1,221 @@
+//! 文件传输: 上传和下载共用的发送和接收逻辑.
+//!
+//! 一次传输依次发送 [`FileHeader`], 若干数据 chunk 和全部数据的 SHA-256,
+//! 接收方先写入同目录下的临时文件, 校验通过后才替换目标文件.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use sha2::{Digest as _, Sha256};
+use tokio::{
+    fs::{self, File},
+    io::{AsyncReadExt as _, AsyncWriteExt as _},
+    sync::mpsc,
+};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Status, Streaming};
+
+use crate::exec::{FileChunk, FileHeader, file_chunk::Chunk};
+
+/// 每个数据 chunk 的最大字节数.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// 将文件操作的错误转换为 [`Status`], 错误信息中带上出错的路径.
+pub fn io_status(e: &io::Error, path: &Path) -> Status {
+    let message = format!("{}: {e}", path.display());
+    match e.kind() {
+        io::ErrorKind::NotFound => Status::not_found(message),
+        io::ErrorKind::PermissionDenied => Status::permission_denied(message),
+        io::ErrorKind::IsADirectory => Status::invalid_argument(message),
+        _ => Status::internal(message),
+    }
+}
+
+fn chunk(chunk: Chunk) -> FileChunk {
+    FileChunk { chunk: Some(chunk) }
+}
+
+/// 打开要发送的文件, 返回的 [`FileHeader`] 中的路径为空, 由调用方填写.
+pub async fn open_file(path: &Path) -> Result<(File, FileHeader), Status> {
+    let file = File::open(path).await.map_err(|e| io_status(&e, path))?;
+    let metadata = file.metadata().await.map_err(|e| io_status(&e, path))?;
+    if metadata.is_dir() {
+        return Err(Status::invalid_argument(format!(
+            "{}: is a directory",
+            path.display()
+        )));
+    }
+    #[cfg(unix)]
+    let mode = {
+        use std::os::unix::fs::PermissionsExt as _;
+        Some(metadata.permissions().mode() & 0o7777)
+    };
+    #[cfg(not(unix))]
+    let mode = None;
+    let mtime = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .and_then(|since_epoch| i64::try_from(since_epoch.as_secs()).ok());
+    Ok((
+        file,
+        FileHeader {
+            path: String::new(),
+            size: metadata.len(),
+            mode,
+            mtime,
+        },
+    ))
+}
+
+/// 在后台任务中读取文件, 依次发送 `header`, 数据和校验和.
+pub fn stream_file(
+    mut file: File,
+    header: FileHeader,
+) -> ReceiverStream<Result<FileChunk, Status>> {
+    let (tx, rx) = mpsc::channel(4);
+    tokio::spawn(async move {
+        let path = PathBuf::from(&header.path);
+        if tx.send(Ok(chunk(Chunk::Header(header)))).await.is_err() {
+            return;
+        }
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0u8; CHUNK_SIZE];
+        loop {
+            let len = match file.read(&mut buf).await {
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(e) => {
+                    tx.send(Err(io_status(&e, &path))).await.ok();
+                    return;
+                }
+            };
+            hasher.update(&buf[..len]);
+            if tx
+                .send(Ok(chunk(Chunk::Data(buf[..len].to_vec()))))
+                .await
+                .is_err()
+            {
+                return;
+            }
+        }
+        tx.send(Ok(chunk(Chunk::Sha256(format!("{:x}", hasher.finalize())))))
+            .await
+            .ok();
+    });
+    ReceiverStream::new(rx)
+}
+
+/// 计算文件内容的 SHA-256 (十六进制小写).
+pub async fn file_sha256(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    loop {
+        let len = file.read(&mut buf).await?;
+        if len == 0 {
+            break;
+        }
+        hasher.update(&buf[..len]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// 与目标文件在同一目录下的临时文件, 保证最后的重命名不会跨文件系统.
+fn temp_path(path: &Path) -> Result<PathBuf, Status> {
+    let Some(name) = path.file_name() else {
+        return Err(Status::invalid_argument(format!(
+            "{}: not a file path",
+            path.display()
+        )));
+    };
+    let suffix = rand::random::<u64>();
+    Ok(path.with_file_name(format!(".{}.{suffix:016x}.rex-tmp", name.to_string_lossy())))
+}
+
+/// 将发送方文件的修改时间和权限应用到接收的文件上.
+async fn apply_metadata(file: File, header: &FileHeader, path: &Path) -> Result<(), Status> {
+    let file = file.into_std().await;
+    if let Some(mtime) = header.mtime.and_then(|mtime| u64::try_from(mtime).ok()) {
+        file.set_modified(UNIX_EPOCH + Duration::from_secs(mtime))
+            .map_err(|e| io_status(&e, path))?;
+    }
+    #[cfg(unix)]
+    if let Some(mode) = header.mode {
+        use std::{fs::Permissions, os::unix::fs::PermissionsExt as _};
+        // 不保留 setuid, setgid 和 sticky 位.
+        file.set_permissions(Permissions::from_mode(mode & 0o777))
+            .map_err(|e| io_status(&e, path))?;
+    }
+    Ok(())
+}
+
+/// 接收一个文件, `destination` 根据 [`FileHeader`] 决定写入的路径.
+///
+/// 数据先写入临时文件, 大小和校验和都与发送方一致时才替换目标文件, 否则删除临时文件.
+///
+/// # Returns
+/// 写入的路径和字节数.
+pub async fn receive_file(
+    stream: &mut Streaming<FileChunk>,
+    destination: impl AsyncFnOnce(&FileHeader) -> Result<PathBuf, Status>,
+) -> Result<(PathBuf, u64), Status> {
+    let Some(FileChunk {
+        chunk: Some(Chunk::Header(header)),
+    }) = stream.message().await?
+    else {
+        return Err(Status::invalid_argument(
+            "first chunk must be a file header",
+        ));
+    };
+    let path = destination(&header).await?;
+    let temp_path = temp_path(&path)?;
+    let mut file = File::create(&temp_path)
+        .await
+        .map_err(|e| io_status(&e, &path))?;
+    let result = async {
+        let mut hasher = Sha256::new();
+        let mut size = 0u64;
+        let sha256 = loop {
+            match stream.message().await? {
+                Some(FileChunk {
+                    chunk: Some(Chunk::Data(data)),
+                }) => {
+                    hasher.update(&data);
+                    size += data.len() as u64;
+                    file.write_all(&data)
+                        .await
+                        .map_err(|e| io_status(&e, &path))?;
+                }
+                Some(FileChunk {
+                    chunk: Some(Chunk::Sha256(sha256)),
+                }) => break sha256,
+                Some(_) => return Err(Status::invalid_argument("unexpected file chunk")),
+                None => return Err(Status::data_loss("file transfer ended before checksum")),
+            }
+        };
+        if size != header.size {
+            return Err(Status::data_loss(format!(
+                "expected {} bytes, received {size}",
+                header.size
+            )));
+        }
+        if sha256 != format!("{:x}", hasher.finalize()) {
+            return Err(Status::data_loss("checksum mismatch"));
+        }
+        file.flush().await.map_err(|e| io_status(&e, &path))?;
+        apply_metadata(file, &header, &path).await?;
+        fs::rename(&temp_path, &path)
+            .await
+            .map_err(|e| io_status(&e, &path))?;
+        Ok(size)
+    }
+    .await;
+    if result.is_err() {
+        fs::remove_file(&temp_path).await.ok();
+    }
+    result.map(|size| (path, size))
+}
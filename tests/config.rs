@@ -0,0 +1,281 @@
+use std::env;
+use std::path::PathBuf;
+
+use clap::Parser;
+use exec_with_local_desktop::ConfigError;
+use exec_with_local_desktop::args::ServerArgs;
+use exec_with_local_desktop::client::{ClientConfig, Profile};
+use exec_with_local_desktop::server::config::{ServerConfig, SessionEnvConfig};
+use exec_with_local_desktop::server::listener::BindAddress;
+use rand::Rng;
+
+/// 将配置写入临时文件后加载.
+async fn load(content: &str) -> Result<ServerConfig, ConfigError> {
+    let path = env::temp_dir().join(format!(
+        "rex-config-{:08x}.toml",
+        rand::rng().random::<u32>()
+    ));
+    std::fs::write(&path, content).unwrap();
+    let result = ServerConfig::load(&path, true).await;
+    std::fs::remove_file(&path).unwrap();
+    result
+}
+
+#[tokio::test]
+async fn parse_config() {
+    let config = load(
+        r#"
+        bind = ["127.0.0.1:9000", "[::1]:9000", "unix:/run/rex/rex.sock"]
+        cert_dir = "/etc/rex"
+
+        [log]
+        level = "warn"
+        file = "/tmp/rex.log"
+
+        [exec]
+        default_cwd = "/tmp"
+        default_action = "deny"
+        protected_env = ["PATH"]
+        session_env = { file = "/run/session.env" }
+
+        [[exec.rules]]
+        action = "allow"
+        executable = ["/usr/bin/*"]
+
+        [limits]
+        max_sessions = 4
+        max_running = 2
+        max_queue_wait = 30
+        max_timeout = 3600
+
+        [limits.resources]
+        open_files = 1024
+        core_size = 0
+
+        [auth]
+        handshake_timeout = 5
+
+        [audit]
+        file = "/tmp/rex-audit.log"
+        log_denied = true
+
+        [shutdown]
+        grace_period = 30
+
+        [unix]
+        mode = 0o660
+        owner = "0"
+        same_uid = true
+        "#,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        config.bind[2],
+        BindAddress::Unix("/run/rex/rex.sock".into())
+    );
+    assert_eq!(config.cert_dir, Some(PathBuf::from("/etc/rex")));
+    assert_eq!(config.log.level.as_deref(), Some("warn"));
+    assert_eq!(
+        config.exec.session_env,
+        SessionEnvConfig::File("/run/session.env".into())
+    );
+    assert_eq!(config.limits.max_sessions, Some(4));
+    assert_eq!(config.limits.max_running, Some(2));
+    assert_eq!(config.limits.max_running_per_client, None);
+    assert_eq!(config.limits.max_queue_wait, Some(30));
+    assert_eq!(config.limits.max_timeout, Some(3600));
+    assert_eq!(config.limits.resources.open_files, Some(1024));
+    assert_eq!(config.limits.resources.memory, None);
+    assert_eq!(config.auth.handshake_timeout, 5);
+    assert_eq!(config.auth.client_ca, None);
+    assert_eq!(config.audit.file, Some(PathBuf::from("/tmp/rex-audit.log")));
+    assert_eq!(config.audit.max_files, 5);
+    assert!(config.audit.log_denied);
+    assert_eq!(config.shutdown.grace_period, 30);
+    assert_eq!(config.shutdown.leaked_file, None);
+    assert_eq!(config.unix.mode, 0o660);
+    assert!(config.unix.same_uid);
+    config.validate().unwrap();
+    config.executor_settings().unwrap();
+}
+
+#[tokio::test]
+async fn missing_config() {
+    let path = env::temp_dir().join("rex-config-missing.toml");
+    assert_eq!(
+        ServerConfig::load(&path, false).await.unwrap(),
+        ServerConfig::default()
+    );
+    assert!(matches!(
+        ServerConfig::load(&path, true).await,
+        Err(ConfigError::Read { .. })
+    ));
+}
+
+#[tokio::test]
+async fn invalid_config() {
+    // 规则必须指定动作.
+    assert!(matches!(
+        load("[[exec.rules]]\nexecutable = [\"/usr/bin/*\"]").await,
+        Err(ConfigError::Parse { .. })
+    ));
+    assert!(matches!(
+        load("[limits.resources]\nstack = 1").await,
+        Err(ConfigError::Parse { .. })
+    ));
+    assert!(matches!(
+        load("bind = [\"localhost\"]").await,
+        Err(ConfigError::Parse { .. })
+    ));
+    assert!(matches!(
+        load("bind = [\"unix:\"]").await,
+        Err(ConfigError::Parse { .. })
+    ));
+
+    let invalid_key = |config: ServerConfig| match config
+        .validate()
+        .and_then(|()| config.executor_settings().map(drop))
+    {
+        Err(ConfigError::Invalid { key, .. }) => key,
+        result => panic!("config should be invalid: {result:?}"),
+    };
+    let args = ServerArgs::parse_from(["s"]);
+    for (content, key) in [
+        ("[log]\nlevel = \"loud\"", "log.level"),
+        (
+            "[[exec.rules]]\naction = \"deny\"\nexecutable = [\"[\"]",
+            "exec.rules",
+        ),
+        (
+            "[exec]\nallowed_executables = [\"[\"]",
+            "exec.allowed_executables",
+        ),
+        ("[exec]\ndefault_cwd = \"tmp\"", "exec.default_cwd"),
+        ("[limits]\nmax_sessions = 0", "limits.max_sessions"),
+        ("[limits]\nmax_idle_timeout = 0", "limits.max_idle_timeout"),
+        (
+            "[limits]\nmax_running_per_client = 0",
+            "limits.max_running_per_client",
+        ),
+        ("[limits.resources]\nmemory = 1073741824", "limits.cgroup"),
+        ("[limits]\ncgroup = \"/nonexistent\"", "limits.cgroup"),
+        ("[auth]\nhandshake_timeout = 0", "auth.handshake_timeout"),
+        ("[audit]\nmax_size = 0", "audit.max_size"),
+        ("[unix]\nowner = \"rex-no-such-user\"", "unix.owner"),
+        ("bind = [\"[::1]:9000\", \"[::1]:9000\"]", "bind"),
+    ] {
+        let config = load(content).await.unwrap().with_args(&args);
+        assert_eq!(invalid_key(config), key, "{content}");
+    }
+}
+
+#[tokio::test]
+async fn override_with_args() {
+    let config = load(
+        r#"
+        bind = ["127.0.0.1:9000"]
+        [exec]
+        protected_env = ["PATH"]
+        session_env = "systemd"
+        "#,
+    )
+    .await
+    .unwrap();
+
+    let merged =
+        config
+            .clone()
+            .with_args(&ServerArgs::parse_from(["s", "--protected-env", "HOME"]));
+    assert_eq!(merged.bind, ["127.0.0.1:9000".parse().unwrap()]);
+    assert_eq!(merged.exec.protected_env, ["PATH", "HOME"]);
+    assert_eq!(merged.exec.session_env, SessionEnvConfig::Systemd);
+
+    let merged = config.with_args(&ServerArgs::parse_from([
+        "s",
+        "-b",
+        "127.0.0.1:9001",
+        "-b",
+        "unix:/tmp/rex.sock",
+        "--session-env-file",
+        "/tmp/session.env",
+    ]));
+    assert_eq!(
+        merged.bind,
+        [
+            BindAddress::Tcp("127.0.0.1:9001".parse().unwrap()),
+            BindAddress::Unix("/tmp/rex.sock".into())
+        ]
+    );
+    assert_eq!(
+        merged.exec.session_env,
+        SessionEnvConfig::File("/tmp/session.env".into())
+    );
+
+    // 配置文件和命令行都没有指定时使用默认地址.
+    let merged = ServerConfig::default().with_args(&ServerArgs::parse_from(["s"]));
+    assert_eq!(merged.bind.len(), 1);
+}
+
+#[tokio::test]
+async fn client_profiles() {
+    let path = env::temp_dir().join(format!(
+        "rex-client-{:08x}.toml",
+        rand::rng().random::<u32>()
+    ));
+    std::fs::write(
+        &path,
+        r#"
+        default_profile = "laptop"
+
+        [profiles.laptop]
+        address = "https://[::1]:30521"
+
+        [profiles.work-pc]
+        address = "https://192.168.1.20:30521"
+        cert_dir = "/home/me/.config/rex/work-pc"
+        domain = "work-pc.lan"
+        current_dir = "/home/me/project"
+        env = { DISPLAY = ":0" }
+        leak = true
+        "#,
+    )
+    .unwrap();
+    let config = ClientConfig::load(&path, true).await.unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let profile = config.profile(Some("work-pc")).unwrap();
+    assert_eq!(profile.address(None), "https://192.168.1.20:30521");
+    assert_eq!(
+        profile.address(Some("https://[::1]:9000".into())),
+        "https://[::1]:9000"
+    );
+    assert_eq!(
+        profile.cert_dir(None).unwrap(),
+        PathBuf::from("/home/me/.config/rex/work-pc")
+    );
+    assert_eq!(
+        profile.cert_dir(Some("/tmp/certs".into())).unwrap(),
+        PathBuf::from("/tmp/certs")
+    );
+    assert_eq!(profile.domain(), "work-pc.lan");
+    assert_eq!(profile.env["DISPLAY"], ":0");
+    assert_eq!(profile.leak, Some(true));
+
+    // 没有指定名称时使用 `default_profile`.
+    let profile = config.profile(None).unwrap();
+    assert_eq!(profile.address(None), "https://[::1]:30521");
+    assert_eq!(profile.domain(), "localhost");
+
+    assert!(matches!(
+        config.profile(Some("missing")),
+        Err(ConfigError::Invalid {
+            key: "profiles",
+            ..
+        })
+    ));
+    assert_eq!(
+        ClientConfig::default().profile(None).unwrap(),
+        Profile::default()
+    );
+}
@@ -1,12 +1,27 @@
-use exec_with_local_desktop::client::{ExecuteOptions, ExecutorClient};
+use exec_with_local_desktop::Error;
+use exec_with_local_desktop::client::{ExecuteOptions, ExecutorClient, SyncFilter};
+use exec_with_local_desktop::exec::execute_client::ExecuteClient;
+use exec_with_local_desktop::exec::execute_request_chunk::RequestChunk;
 use exec_with_local_desktop::exec::execute_server::ExecuteServer;
-use exec_with_local_desktop::server::Executor;
+use exec_with_local_desktop::exec::file_chunk::Chunk;
+use exec_with_local_desktop::exec::program_output::Payload;
+use exec_with_local_desktop::exec::{
+    Attach, Command, ExecuteRequestChunk, FileChunk, FileHeader, KillCommand, ManifestEntry,
+    SessionState, Signal, StdinChunk, StdinEof, SyncRequest, TerminalSize,
+};
+use exec_with_local_desktop::server::{
+    EnvPolicy, Executor, ExecutorSettings, FileRoot, SessionEnv, SessionEnvSource, audit::AuditLog,
+    config::ServerConfig,
+};
 use rand::Rng;
+use std::collections::HashMap;
 use std::env;
 use std::path::Path;
 use std::thread;
 use std::time::Duration;
+use tokio_stream::wrappers::ReceiverStream;
 use tonic::transport::Server;
+use tonic::transport::server::TcpIncoming;
 use tracing::info;
 
 fn random_filename() -> String {
@@ -19,6 +34,18 @@ fn random_filename() -> String {
         .collect()
 }
 
+/// 在当前运行时中启动一个无 tls 的服务端, 返回客户端连接用的地址.
+fn spawn_server(executor: Executor) -> String {
+    let incoming = TcpIncoming::bind("[::1]:0".parse().unwrap()).unwrap();
+    let addr = incoming.local_addr().unwrap();
+    tokio::spawn(
+        Server::builder()
+            .add_service(ExecuteServer::new(executor))
+            .serve_with_incoming(incoming),
+    );
+    format!("grpc://{addr}")
+}
+
 #[test]
 fn test_random_filename() {
     let mut f = random_filename();
@@ -44,7 +71,7 @@ fn no_leak() {
             // 15 秒后退出.
             tokio::time::timeout(Duration::from_secs(15), async move {
                 Server::builder()
-                    .add_service(ExecuteServer::new(Executor))
+                    .add_service(ExecuteServer::new(Executor::default()))
                     .serve(ADDR.parse().unwrap())
                     .await
                     .unwrap();
@@ -108,3 +135,1383 @@ fn no_leak() {
     s_join.join().unwrap();
     c_join.join().unwrap();
 }
+
+/// 测试伪终端模式下子进程的标准输入输出是终端.
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn pty() {
+    let mut client = ExecutorClient::connect(spawn_server(Executor::default()))
+        .await
+        .unwrap();
+    let output = client
+        .execute(
+            ExecuteOptions::builder()
+                .executable("bash".into())
+                .current_dir(None)
+                .args(vec![
+                    "-c".into(),
+                    "test -t 0 && test -t 1 && echo tty".into(),
+                ])
+                .leak(false)
+                .pty(true)
+                .build(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(output.code, 0);
+    assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "tty");
+}
+
+/// 测试客户端发送的终端窗口大小会应用到伪终端上.
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn pty_resize() {
+    let mut client = ExecuteClient::connect(spawn_server(Executor::default()))
+        .await
+        .unwrap();
+    let requests = [
+        RequestChunk::Command(Command {
+            executable: "bash".into(),
+            args: vec!["-c".into(), "sleep 0.5 && stty size".into()],
+            pty: true,
+            ..Default::default()
+        }),
+        RequestChunk::TerminalSize(TerminalSize {
+            rows: 33,
+            cols: 101,
+            xpixel: 0,
+            ypixel: 0,
+        }),
+    ]
+    .map(|chunk| ExecuteRequestChunk {
+        request_chunk: Some(chunk),
+    });
+    let mut stream = client
+        .execute(tokio_stream::iter(requests))
+        .await
+        .unwrap()
+        .into_inner();
+    let mut stdout = Vec::new();
+    while let Some(msg) = stream.message().await.unwrap() {
+        if let Some(Payload::StdoutChunk(mut chunk)) = msg.payload {
+            stdout.append(&mut chunk.data);
+        }
+    }
+    assert_eq!(String::from_utf8_lossy(&stdout).trim(), "33 101");
+
+    // 初始窗口大小在子进程启动前设置.
+    let requests = [ExecuteRequestChunk {
+        request_chunk: Some(RequestChunk::Command(Command {
+            executable: "stty".into(),
+            args: vec!["size".into()],
+            pty: true,
+            terminal_size: Some(TerminalSize {
+                rows: 24,
+                cols: 80,
+                xpixel: 0,
+                ypixel: 0,
+            }),
+            ..Default::default()
+        })),
+    }];
+    let mut stream = client
+        .execute(tokio_stream::iter(requests))
+        .await
+        .unwrap()
+        .into_inner();
+    let mut stdout = Vec::new();
+    while let Some(msg) = stream.message().await.unwrap() {
+        if let Some(Payload::StdoutChunk(mut chunk)) = msg.payload {
+            stdout.append(&mut chunk.data);
+        }
+    }
+    assert_eq!(String::from_utf8_lossy(&stdout).trim(), "24 80");
+}
+
+/// 测试信号能够转发给子进程.
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn forward_signal() {
+    let mut client = ExecuteClient::connect(spawn_server(Executor::default()))
+        .await
+        .unwrap();
+    let (tx, rx) = tokio::sync::mpsc::channel(10);
+    tx.send(ExecuteRequestChunk {
+        request_chunk: Some(RequestChunk::Command(Command {
+            executable: "bash".into(),
+            args: vec![
+                "-c".into(),
+                "trap 'echo got; exit 3' USR1; echo ready; sleep 5 >/dev/null 2>&1 & wait".into(),
+            ],
+            ..Default::default()
+        })),
+    })
+    .await
+    .unwrap();
+    let mut stream = client
+        .execute(ReceiverStream::new(rx))
+        .await
+        .unwrap()
+        .into_inner();
+    let mut stdout = Vec::new();
+    let mut code = None;
+    while let Some(msg) = stream.message().await.unwrap() {
+        match msg.payload {
+            Some(Payload::StdoutChunk(mut chunk)) => {
+                stdout.append(&mut chunk.data);
+                if stdout == b"ready\n" {
+                    tx.send(ExecuteRequestChunk {
+                        request_chunk: Some(RequestChunk::Signal(Signal {
+                            name_or_number: "usr1".into(),
+                            process_group: false,
+                        })),
+                    })
+                    .await
+                    .unwrap();
+                }
+            }
+            Some(Payload::ExitInfo(info)) => code = info.code,
+            _ => {}
+        }
+    }
+    assert_eq!(code, Some(3));
+    assert_eq!(String::from_utf8_lossy(&stdout), "ready\ngot\n");
+}
+
+/// 测试被信号终止的子进程会报告信号编号, 并转换为 shell 约定的退出码.
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn exit_by_signal() {
+    let mut client = ExecutorClient::connect(spawn_server(Executor::default()))
+        .await
+        .unwrap();
+    let output = client
+        .execute(
+            ExecuteOptions::builder()
+                .executable("bash".into())
+                .current_dir(None)
+                .args(vec!["-c".into(), "kill -TERM $$".into()])
+                .leak(false)
+                .build(),
+        )
+        .await
+        .unwrap();
+    let exit_info = output.exit_info.unwrap();
+    assert_eq!(exit_info.code, None);
+    assert_eq!(exit_info.signal, Some(15));
+    assert!(!exit_info.killed_by_request);
+    assert_eq!(output.code, 143);
+}
+
+/// 测试客户端能够清空并设置子进程的环境变量, 且受保护的变量会被保留.
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn environment() {
+    let mut client = ExecutorClient::connect(spawn_server(Executor::new(
+        ExecutorSettings::builder()
+            .env_policy(EnvPolicy::new(["PATH"]))
+            .build(),
+    )))
+    .await
+    .unwrap();
+    let output = client
+        .execute(
+            ExecuteOptions::builder()
+                .executable("bash".into())
+                .current_dir(None)
+                .args(vec![
+                    "-c".into(),
+                    "echo \"$REX_TEST:$HOME:${PATH:+path}\"".into(),
+                ])
+                .leak(false)
+                .env(HashMap::from([("REX_TEST".into(), "hello".into())]))
+                .env_clear(true)
+                .build(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout).trim(),
+        "hello::path"
+    );
+
+    let err = client
+        .execute(
+            ExecuteOptions::builder()
+                .executable("bash".into())
+                .current_dir(None)
+                .args(vec![])
+                .leak(false)
+                .env_remove(vec!["PATH".into()])
+                .build(),
+        )
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        exec_with_local_desktop::Error::TonicStatus(status)
+            if status.code() == tonic::Code::PermissionDenied
+    ));
+}
+
+/// 测试从文件读取的桌面会话环境变量会注入到清空了环境的子进程中.
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn session_environment() {
+    let session_file = env::temp_dir().join(random_filename());
+    std::fs::write(
+        &session_file,
+        "DISPLAY=:42\nexport WAYLAND_DISPLAY='wayland-9'\nOTHER=1\n",
+    )
+    .unwrap();
+    let executor = Executor::new(
+        ExecutorSettings::builder()
+            .session_env(SessionEnv::new(SessionEnvSource::File(
+                session_file.clone(),
+            )))
+            .build(),
+    );
+    let mut client = ExecutorClient::connect(spawn_server(executor))
+        .await
+        .unwrap();
+    let output = client
+        .execute(
+            ExecuteOptions::builder()
+                .executable("bash".into())
+                .current_dir(None)
+                .args(vec![
+                    "-c".into(),
+                    "echo \"$DISPLAY:$WAYLAND_DISPLAY:$OTHER\"".into(),
+                ])
+                .leak(false)
+                .env_clear(true)
+                .build(),
+        )
+        .await
+        .unwrap();
+    std::fs::remove_file(session_file).unwrap();
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout).trim(),
+        ":42:wayland-9:"
+    );
+}
+
+/// 测试 leak 的会话在连接断开后能够重新连接, 并收到断开期间的输出.
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn attach_leaked_session() {
+    let addr = spawn_server(Executor::default());
+    let mut client = ExecuteClient::connect(addr.clone()).await.unwrap();
+    let (tx, rx) = tokio::sync::mpsc::channel(10);
+    tx.send(ExecuteRequestChunk {
+        request_chunk: Some(RequestChunk::Command(Command {
+            executable: "bash".into(),
+            args: vec![
+                "-c".into(),
+                "echo one; read line; echo \"got $line\"".into(),
+            ],
+            leak: true,
+            ..Default::default()
+        })),
+    })
+    .await
+    .unwrap();
+    let mut stream = client
+        .execute(ReceiverStream::new(rx))
+        .await
+        .unwrap()
+        .into_inner();
+    let Some(Payload::SessionStarted(started)) = stream.message().await.unwrap().unwrap().payload
+    else {
+        panic!("first message should be SessionStarted");
+    };
+    let Some(Payload::StdoutChunk(chunk)) = stream.message().await.unwrap().unwrap().payload else {
+        panic!("expected stdout");
+    };
+    assert_eq!(chunk.data, b"one\n");
+    // 断开连接.
+    drop(stream);
+    drop(tx);
+    drop(client);
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let mut client = ExecuteClient::connect(addr).await.unwrap();
+    let requests = [
+        RequestChunk::Attach(Attach {
+            session_id: started.session_id.clone(),
+        }),
+        RequestChunk::StdinChunk(StdinChunk {
+            data: b"two\n".to_vec(),
+        }),
+    ]
+    .map(|chunk| ExecuteRequestChunk {
+        request_chunk: Some(chunk),
+    });
+    let mut stream = client
+        .execute(tokio_stream::iter(requests))
+        .await
+        .unwrap()
+        .into_inner();
+    let mut stdout = Vec::new();
+    let mut code = None;
+    while let Some(msg) = stream.message().await.unwrap() {
+        match msg.payload {
+            Some(Payload::SessionStarted(attached)) => {
+                assert_eq!(attached.session_id, started.session_id);
+            }
+            Some(Payload::StdoutChunk(mut chunk)) => stdout.append(&mut chunk.data),
+            Some(Payload::ExitInfo(info)) => code = info.code,
+            _ => {}
+        }
+    }
+    assert_eq!(String::from_utf8_lossy(&stdout), "one\ngot two\n");
+    assert_eq!(code, Some(0));
+
+    // 结束的会话被取回后不再保留.
+    let status = client
+        .execute(tokio_stream::once(ExecuteRequestChunk {
+            request_chunk: Some(RequestChunk::Attach(Attach {
+                session_id: started.session_id,
+            })),
+        }))
+        .await
+        .unwrap()
+        .into_inner()
+        .message()
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::NotFound);
+}
+
+/// 测试通过会话管理接口列出, 查看和终止会话.
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn manage_sessions() {
+    let addr = spawn_server(Executor::default());
+    let mut client = ExecuteClient::connect(addr.clone()).await.unwrap();
+    let mut stream = client
+        .execute(tokio_stream::once(ExecuteRequestChunk {
+            request_chunk: Some(RequestChunk::Command(Command {
+                executable: "sleep".into(),
+                args: vec!["30".into()],
+                current_dir: Some("/tmp".into()),
+                leak: true,
+                ..Default::default()
+            })),
+        }))
+        .await
+        .unwrap()
+        .into_inner();
+    let Some(Payload::SessionStarted(started)) = stream.message().await.unwrap().unwrap().payload
+    else {
+        panic!("first message should be SessionStarted");
+    };
+    drop(stream);
+    drop(client);
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let mut client = ExecutorClient::connect(addr).await.unwrap();
+    let sessions = client.list_sessions().await.unwrap();
+    let info = sessions
+        .iter()
+        .find(|info| info.session_id == started.session_id)
+        .expect("leaked session should be listed");
+    assert_eq!(info.state(), SessionState::Detached);
+    assert!(info.pid.is_some());
+    assert!(info.executable.ends_with("sleep"));
+    assert_eq!(info.args, ["30"]);
+    assert_eq!(info.current_dir, "/tmp");
+    assert!(info.leak);
+
+    client
+        .terminate_session(started.session_id.clone(), None)
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let info = client
+        .get_session(started.session_id.clone())
+        .await
+        .unwrap();
+    assert_eq!(info.state(), SessionState::Exited);
+    assert_eq!(info.pid, None);
+    assert!(info.exit_info.unwrap().killed_by_request);
+
+    // 终止已经结束的会话会将其移除.
+    client
+        .terminate_session(started.session_id.clone(), None)
+        .await
+        .unwrap();
+    let Err(Error::TonicStatus(status)) = client.get_session(started.session_id).await else {
+        panic!("removed session should not be found");
+    };
+    assert_eq!(status.code(), tonic::Code::NotFound);
+}
+
+/// 进程是否还在运行, 已经成为僵尸进程的也视为不在运行.
+#[cfg(target_os = "linux")]
+fn process_alive(pid: u32) -> bool {
+    std::fs::read_to_string(format!("/proc/{pid}/stat")).is_ok_and(|stat| {
+        stat.rsplit_once(") ")
+            .is_some_and(|(_, rest)| !rest.starts_with('Z'))
+    })
+}
+
+/// 测试 `leak = false` 时, 连接断开后子进程启动的后台进程也会被终止.
+#[cfg(target_os = "linux")]
+#[tokio::test(flavor = "multi_thread")]
+async fn no_leak_process_tree() {
+    let addr = spawn_server(Executor::default());
+    let mut client = ExecuteClient::connect(addr).await.unwrap();
+    let (tx, rx) = tokio::sync::mpsc::channel(10);
+    tx.send(ExecuteRequestChunk {
+        request_chunk: Some(RequestChunk::Command(Command {
+            executable: "bash".into(),
+            args: vec!["-c".into(), "sleep 100 > /dev/null & echo $!; wait".into()],
+            ..Default::default()
+        })),
+    })
+    .await
+    .unwrap();
+    let mut stream = client
+        .execute(ReceiverStream::new(rx))
+        .await
+        .unwrap()
+        .into_inner();
+    let pid = loop {
+        if let Some(Payload::StdoutChunk(chunk)) = stream.message().await.unwrap().unwrap().payload
+        {
+            break String::from_utf8_lossy(&chunk.data)
+                .trim()
+                .parse::<u32>()
+                .unwrap();
+        }
+    };
+    assert!(process_alive(pid));
+    drop(stream);
+    drop(tx);
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert!(!process_alive(pid), "background process {pid} leaked");
+}
+
+/// 测试设置了 `kill_grace_period` 时, 子进程先收到 `SIGTERM`, 有机会自行退出.
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn kill_with_grace_period() {
+    let addr = spawn_server(Executor::default());
+    let mut client = ExecuteClient::connect(addr).await.unwrap();
+    let (tx, rx) = tokio::sync::mpsc::channel(10);
+    tx.send(ExecuteRequestChunk {
+        request_chunk: Some(RequestChunk::Command(Command {
+            executable: "bash".into(),
+            args: vec![
+                "-c".into(),
+                "trap 'exit 3' TERM; echo ready; while true; do sleep 0.1; done".into(),
+            ],
+            kill_grace_period: Some(5),
+            ..Default::default()
+        })),
+    })
+    .await
+    .unwrap();
+    let mut stream = client
+        .execute(ReceiverStream::new(rx))
+        .await
+        .unwrap()
+        .into_inner();
+    loop {
+        if let Some(Payload::StdoutChunk(_)) = stream.message().await.unwrap().unwrap().payload {
+            break;
+        }
+    }
+    tx.send(ExecuteRequestChunk {
+        request_chunk: Some(RequestChunk::Kill(KillCommand {})),
+    })
+    .await
+    .unwrap();
+    let mut exit_info = None;
+    while let Some(msg) = stream.message().await.unwrap() {
+        if let Some(Payload::ExitInfo(info)) = msg.payload {
+            exit_info = Some(info);
+        }
+    }
+    let exit_info = exit_info.unwrap();
+    assert!(exit_info.killed_by_request);
+    assert_eq!(exit_info.code, Some(3));
+}
+
+/// 测试子进程退出时, 所有输出都在退出信息之前送达.
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn output_before_exit_info() {
+    let mut client = ExecutorClient::connect(spawn_server(Executor::default()))
+        .await
+        .unwrap();
+    let output = client
+        .execute(
+            ExecuteOptions::builder()
+                .executable("bash".into())
+                .current_dir(None)
+                .args(vec![
+                    "-c".into(),
+                    "head -c 300000 /dev/zero; head -c 200000 /dev/zero >&2".into(),
+                ])
+                .leak(false)
+                .build(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(output.stdout.len(), 300_000);
+    assert_eq!(output.stderr.len(), 200_000);
+    assert_eq!(output.code, 0);
+}
+
+/// 测试子进程退出后, 仍持有输出管道的后台进程不会让退出信息一直无法送达.
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn background_process_holds_output() {
+    let mut client = ExecutorClient::connect(spawn_server(Executor::default()))
+        .await
+        .unwrap();
+    let output = tokio::time::timeout(
+        Duration::from_secs(10),
+        client.execute(
+            ExecuteOptions::builder()
+                .executable("bash".into())
+                .current_dir(None)
+                .args(vec!["-c".into(), "sleep 30 & echo $!".into()])
+                .leak(true)
+                .build(),
+        ),
+    )
+    .await
+    .expect("exit info should not wait for the background process")
+    .unwrap();
+    assert_eq!(output.code, 0);
+    let pid = String::from_utf8(output.stdout).unwrap();
+    nix::sys::signal::kill(
+        nix::unistd::Pid::from_raw(pid.trim().parse().unwrap()),
+        nix::sys::signal::Signal::SIGKILL,
+    )
+    .ok();
+}
+
+/// 测试关闭标准输入后, 子进程能自然退出并输出全部结果, 之后的控制消息仍然会被处理.
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn stdin_eof() {
+    let addr = spawn_server(Executor::default());
+    let mut client = ExecuteClient::connect(addr).await.unwrap();
+    let (tx, rx) = tokio::sync::mpsc::channel(10);
+    let requests = [
+        RequestChunk::Command(Command {
+            executable: "bash".into(),
+            args: vec!["-c".into(), "sort; echo sorted; sleep 100".into()],
+            ..Default::default()
+        }),
+        RequestChunk::StdinChunk(StdinChunk {
+            data: b"b\na\n".to_vec(),
+        }),
+        RequestChunk::StdinEof(StdinEof {}),
+    ];
+    for chunk in requests {
+        tx.send(ExecuteRequestChunk {
+            request_chunk: Some(chunk),
+        })
+        .await
+        .unwrap();
+    }
+    let mut stream = client
+        .execute(ReceiverStream::new(rx))
+        .await
+        .unwrap()
+        .into_inner();
+    let mut stdout = Vec::new();
+    while !stdout.ends_with(b"sorted\n") {
+        if let Some(Payload::StdoutChunk(mut chunk)) =
+            stream.message().await.unwrap().unwrap().payload
+        {
+            stdout.append(&mut chunk.data);
+        }
+    }
+    assert_eq!(String::from_utf8_lossy(&stdout), "a\nb\nsorted\n");
+    // 关闭标准输入后仍然可以杀死子进程.
+    tx.send(ExecuteRequestChunk {
+        request_chunk: Some(RequestChunk::Kill(KillCommand {})),
+    })
+    .await
+    .unwrap();
+    let mut exit_info = None;
+    while let Some(msg) = stream.message().await.unwrap() {
+        if let Some(Payload::ExitInfo(info)) = msg.payload {
+            exit_info = Some(info);
+        }
+    }
+    assert!(exit_info.unwrap().killed_by_request);
+}
+
+/// 测试请求流结束时关闭子进程的标准输入, 伪终端模式下的 [`StdinEof`] 使子进程读到文件结束.
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn stdin_eof_closes_input() {
+    let addr = spawn_server(Executor::default());
+    let mut client = ExecutorClient::connect(addr.clone()).await.unwrap();
+    let output = tokio::time::timeout(
+        Duration::from_secs(10),
+        client.execute(
+            ExecuteOptions::builder()
+                .executable("cat".into())
+                .current_dir(None)
+                .args(vec![])
+                .leak(false)
+                .build(),
+        ),
+    )
+    .await
+    .expect("cat should exit after the request stream ends")
+    .unwrap();
+    assert_eq!(output.code, 0);
+
+    let mut client = ExecuteClient::connect(addr).await.unwrap();
+    let (tx, rx) = tokio::sync::mpsc::channel(10);
+    let requests = [
+        RequestChunk::Command(Command {
+            executable: "cat".into(),
+            pty: true,
+            ..Default::default()
+        }),
+        RequestChunk::StdinChunk(StdinChunk {
+            data: b"data\n".to_vec(),
+        }),
+        RequestChunk::StdinEof(StdinEof {}),
+    ];
+    for chunk in requests {
+        tx.send(ExecuteRequestChunk {
+            request_chunk: Some(chunk),
+        })
+        .await
+        .unwrap();
+    }
+    let mut stream = client
+        .execute(ReceiverStream::new(rx))
+        .await
+        .unwrap()
+        .into_inner();
+    let exit_info = tokio::time::timeout(Duration::from_secs(10), async {
+        while let Some(msg) = stream.message().await.unwrap() {
+            if let Some(Payload::ExitInfo(info)) = msg.payload {
+                return info;
+            }
+        }
+        panic!("no exit info");
+    })
+    .await
+    .expect("cat in a pty should exit after stdin EOF");
+    assert_eq!(exit_info.code, Some(0));
+}
+
+/// 测试上传和下载文件, 内容, 权限和修改时间都会保留.
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn upload_and_download() {
+    use std::os::unix::fs::PermissionsExt as _;
+    use std::time::UNIX_EPOCH;
+
+    let dir = env::temp_dir().join(random_filename());
+    std::fs::create_dir(&dir).unwrap();
+    let local = dir.join("local.sh");
+    let content: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+    std::fs::write(&local, &content).unwrap();
+    std::fs::set_permissions(&local, std::fs::Permissions::from_mode(0o750)).unwrap();
+    let mtime = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+    std::fs::File::options()
+        .write(true)
+        .open(&local)
+        .unwrap()
+        .set_modified(mtime)
+        .unwrap();
+
+    let mut client = ExecutorClient::connect(spawn_server(Executor::default()))
+        .await
+        .unwrap();
+    let remote = dir.join("remote.sh");
+    let size = client
+        .upload(&local, remote.to_string_lossy().into())
+        .await
+        .unwrap();
+    assert_eq!(size, content.len() as u64);
+    let downloaded = dir.join("downloaded.sh");
+    client
+        .download(remote.to_string_lossy().into(), downloaded.clone())
+        .await
+        .unwrap();
+    for path in [&remote, &downloaded] {
+        assert_eq!(std::fs::read(path).unwrap(), content);
+        let metadata = std::fs::metadata(path).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o750);
+        assert_eq!(metadata.modified().unwrap(), mtime);
+    }
+    // 临时文件都已经被重命名.
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 3);
+
+    let missing = client
+        .download(dir.join("missing").to_string_lossy().into(), dir.join("x"))
+        .await;
+    assert!(
+        matches!(missing, Err(Error::TonicStatus(status)) if status.code() == tonic::Code::NotFound)
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// 测试校验和不一致的上传会被拒绝, 且不会留下文件.
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn upload_checksum_mismatch() {
+    let dir = env::temp_dir().join(random_filename());
+    std::fs::create_dir(&dir).unwrap();
+    let remote = dir.join("remote.txt");
+    let mut client = ExecuteClient::connect(spawn_server(Executor::default()))
+        .await
+        .unwrap();
+    let chunks = [
+        Chunk::Header(FileHeader {
+            path: remote.to_string_lossy().into(),
+            size: 5,
+            ..Default::default()
+        }),
+        Chunk::Data(b"hello".to_vec()),
+        Chunk::Sha256("0".repeat(64)),
+    ]
+    .map(|chunk| FileChunk { chunk: Some(chunk) });
+    let status = client.upload(tokio_stream::iter(chunks)).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::DataLoss);
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// 测试目录同步只传输变化的文件, 并且不能访问文件根目录之外的路径.
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn sync_directory() {
+    let dir = env::temp_dir().join(random_filename());
+    let local = dir.join("local");
+    let root = dir.join("root");
+    std::fs::create_dir_all(local.join("src/nested")).unwrap();
+    std::fs::create_dir_all(local.join("target")).unwrap();
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(local.join("Cargo.toml"), "[package]").unwrap();
+    std::fs::write(local.join("src/main.rs"), "fn main() {}").unwrap();
+    std::fs::write(local.join("src/nested/mod.rs"), "").unwrap();
+    std::fs::write(local.join("target/big.bin"), "ignored").unwrap();
+    std::fs::create_dir_all(local.join(".git/objects")).unwrap();
+    std::fs::write(local.join(".git/objects/pack"), "ignored").unwrap();
+
+    let executor = Executor::new(
+        ExecutorSettings::builder()
+            .file_root(FileRoot::new(Some(root.clone())).unwrap())
+            .build(),
+    );
+    let addr = spawn_server(executor);
+    let mut client = ExecutorClient::connect(addr.clone()).await.unwrap();
+    // 匹配目录本身的模式也会排除其中的所有文件.
+    let filter = SyncFilter::new(&[], &["target/**".into(), ".git".into()]).unwrap();
+    let output = client
+        .sync(&local, "project".into(), &filter)
+        .await
+        .unwrap();
+    assert_eq!(output.total, 3);
+    assert_eq!(
+        output.transferred,
+        ["Cargo.toml", "src/main.rs", "src/nested/mod.rs"]
+    );
+    assert_eq!(
+        std::fs::read_to_string(root.join("project/src/main.rs")).unwrap(),
+        "fn main() {}"
+    );
+    assert!(!root.join("project/target").exists());
+    assert!(!root.join("project/.git").exists());
+
+    // 只有修改过的文件会被再次传输.
+    std::fs::write(local.join("src/main.rs"), "fn main() { todo!() }").unwrap();
+    let output = client
+        .sync(&local, "project".into(), &filter)
+        .await
+        .unwrap();
+    assert_eq!(output.transferred, ["src/main.rs"]);
+
+    for outside in ["../escape", "/etc/passwd"] {
+        let result = client.download(outside.into(), dir.join("escape")).await;
+        assert!(
+            matches!(&result, Err(Error::TonicStatus(status)) if status.code() == tonic::Code::PermissionDenied),
+            "{outside}: {result:?}"
+        );
+    }
+    // 对比文件清单时不会创建目录, 目录在上传时才创建.
+    let changed = ExecuteClient::connect(addr)
+        .await
+        .unwrap()
+        .sync(SyncRequest {
+            path: "other".into(),
+            entries: vec![ManifestEntry {
+                path: "a/b.txt".into(),
+                size: 0,
+                sha256: String::new(),
+            }],
+        })
+        .await
+        .unwrap()
+        .into_inner()
+        .changed;
+    assert_eq!(changed, ["a/b.txt"]);
+    assert!(!root.join("other").exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// 测试重新加载配置后新的请求使用新的执行策略, 已经存在的会话不受影响.
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn reload_settings() {
+    let executor = Executor::default();
+    let addr = spawn_server(executor.clone());
+    let mut client = ExecuteClient::connect(addr.clone()).await.unwrap();
+    let mut stream = client
+        .execute(tokio_stream::once(ExecuteRequestChunk {
+            request_chunk: Some(RequestChunk::Command(Command {
+                executable: "sleep".into(),
+                args: vec!["30".into()],
+                leak: true,
+                ..Default::default()
+            })),
+        }))
+        .await
+        .unwrap()
+        .into_inner();
+    let Some(Payload::SessionStarted(started)) = stream.message().await.unwrap().unwrap().payload
+    else {
+        panic!("first message should be SessionStarted");
+    };
+    drop(stream);
+
+    let config: ServerConfig = toml::from_str(
+        r#"
+        [exec]
+        default_cwd = "/tmp"
+        default_action = "deny"
+
+        [[exec.rules]]
+        name = "pwd-only"
+        action = "allow"
+        executable = ["**/pwd"]
+        "#,
+    )
+    .unwrap();
+    executor.reload(config.executor_settings().unwrap());
+
+    let mut client = ExecutorClient::connect(addr).await.unwrap();
+    let options = |executable: &str| {
+        ExecuteOptions::builder()
+            .executable(executable.into())
+            .current_dir(None)
+            .args(vec![])
+            .leak(false)
+            .build()
+    };
+    let output = client.execute(options("pwd")).await.unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "/tmp");
+    let result = client.execute(options("echo")).await;
+    assert!(
+        matches!(&result, Err(Error::TonicStatus(status)) if status.code() == tonic::Code::PermissionDenied),
+        "{result:?}"
+    );
+
+    let info = client
+        .get_session(started.session_id.clone())
+        .await
+        .unwrap();
+    assert_eq!(info.state(), SessionState::Detached);
+    client
+        .terminate_session(started.session_id, None)
+        .await
+        .unwrap();
+}
+
+/// 测试按真实路径匹配执行规则, 符号链接和 `..` 不能绕过规则.
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn policy_uses_real_paths() {
+    let dir = env::temp_dir().join(random_filename());
+    let tools = dir.join("tools");
+    std::fs::create_dir_all(&tools).unwrap();
+    let real_dir = std::fs::canonicalize(&dir).unwrap();
+    let echo = std::fs::canonicalize(which::which("echo").unwrap()).unwrap();
+    std::os::unix::fs::symlink(&echo, tools.join("echo")).unwrap();
+    std::fs::copy(&echo, tools.join("own")).unwrap();
+    std::fs::copy(&echo, dir.join("evil")).unwrap();
+
+    let executor = Executor::default();
+    let addr = spawn_server(executor.clone());
+    let mut client = ExecutorClient::connect(addr).await.unwrap();
+    let mut execute = async |executable: &Path| {
+        let options = ExecuteOptions::builder()
+            .executable(executable.to_string_lossy().into_owned())
+            .current_dir(Some("/tmp".into()))
+            .args(vec!["hi".into()])
+            .leak(false)
+            .build();
+        match client.execute(options).await {
+            Ok(output) => Ok(String::from_utf8(output.stdout).unwrap()),
+            Err(Error::TonicStatus(status)) => Err(status.code()),
+            Err(e) => panic!("{e:?}"),
+        }
+    };
+
+    let config: ServerConfig = toml::from_str(&format!(
+        r#"
+        [exec]
+        default_action = "deny"
+
+        [[exec.rules]]
+        name = "no-echo"
+        action = "deny"
+        executable = ["{echo}"]
+
+        [[exec.rules]]
+        name = "tools"
+        action = "allow"
+        executable = ["{tools}/**"]
+        "#,
+        echo = echo.display(),
+        tools = real_dir.join("tools").display(),
+    ))
+    .unwrap();
+    executor.reload(config.executor_settings().unwrap());
+    assert_eq!(execute(&tools.join("own")).await.as_deref(), Ok("hi\n"));
+    for path in [
+        tools.join("echo"),
+        dir.join("tools/../tools/echo"),
+        dir.join("tools/../evil"),
+    ] {
+        assert_eq!(
+            execute(&path).await,
+            Err(tonic::Code::PermissionDenied),
+            "{}",
+            path.display()
+        );
+    }
+
+    // 已弃用的 `allowed_executables` 仍然可以使用.
+    let config: ServerConfig = toml::from_str(&format!(
+        "[exec]\nallowed_executables = [\"{}/*\"]",
+        real_dir.join("tools").display()
+    ))
+    .unwrap();
+    executor.reload(config.executor_settings().unwrap());
+    assert_eq!(execute(&tools.join("own")).await.as_deref(), Ok("hi\n"));
+    assert_eq!(
+        execute(&dir.join("tools/../evil")).await,
+        Err(tonic::Code::PermissionDenied)
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// 测试审计日志记录执行的启动, 退出, 输入输出字节数, 以及被拒绝的请求.
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn audit_log() {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    let path = env::temp_dir().join(format!("rex-audit-{}", random_filename()));
+    let root = env::temp_dir().join(random_filename());
+    std::fs::create_dir_all(&root).unwrap();
+    let executor = Executor::new(
+        ExecutorSettings::builder()
+            .env_policy(EnvPolicy::new(["PATH"]))
+            .file_root(FileRoot::new(Some(root.clone())).unwrap())
+            .build(),
+    )
+    .with_audit(
+        AuditLog::builder()
+            .path(&path)
+            .max_size(1024 * 1024)
+            .max_files(1)
+            .log_denied(true)
+            .build()
+            .unwrap(),
+    );
+    let addr = spawn_server(executor);
+    let mut client = ExecuteClient::connect(addr.clone()).await.unwrap();
+    let requests = [
+        RequestChunk::Command(Command {
+            executable: "cat".into(),
+            env: HashMap::from([("REX_TEST".into(), "audit".into())]),
+            ..Default::default()
+        }),
+        RequestChunk::StdinChunk(StdinChunk {
+            data: b"hello".to_vec(),
+        }),
+        RequestChunk::StdinEof(StdinEof {}),
+    ]
+    .map(|chunk| ExecuteRequestChunk {
+        request_chunk: Some(chunk),
+    });
+    let mut stream = client
+        .execute(tokio_stream::iter(requests))
+        .await
+        .unwrap()
+        .into_inner();
+    while stream.message().await.unwrap().is_some() {}
+
+    let mut client = ExecutorClient::connect(addr).await.unwrap();
+    client
+        .execute(
+            ExecuteOptions::builder()
+                .executable("bash".into())
+                .current_dir(None)
+                .args(vec![])
+                .leak(false)
+                .env_remove(vec!["PATH".into()])
+                .build(),
+        )
+        .await
+        .unwrap_err();
+    client
+        .download("/etc/passwd".into(), root.join("passwd"))
+        .await
+        .unwrap_err();
+
+    // 审计日志中可能有敏感信息, 只有所有者可以读写.
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    let records: Vec<serde_json::Value> = std::fs::read_to_string(&path)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    std::fs::remove_file(&path).unwrap();
+    std::fs::remove_dir_all(&root).unwrap();
+    let events: Vec<_> = records.iter().map(|it| it["event"].as_str()).collect();
+    assert_eq!(
+        events,
+        [Some("start"), Some("exit"), Some("denied"), Some("denied")]
+    );
+    let (start, exit, denied) = (&records[0], &records[1], &records[2]);
+    assert!(start["executable"].as_str().unwrap().ends_with("/cat"));
+    // 默认只记录环境变量的名称.
+    assert!(start["env"]["REX_TEST"].is_null());
+    assert!(start["env"].get("REX_TEST").is_some());
+    assert_eq!(start["session_id"], exit["session_id"]);
+    assert!(start["pid"].is_u64());
+    assert!(start["timestamp"].is_string());
+    assert_eq!(exit["exit"]["code"], 0);
+    assert_eq!(exit["bytes_in"], 5);
+    assert_eq!(exit["bytes_out"], 5);
+    assert!(exit["kill_reason"].is_null());
+    assert_eq!(denied["rpc"], "execute");
+    assert_eq!(denied["executable"], "bash");
+    assert_eq!(denied["code"], "PermissionDenied");
+    let download = &records[3];
+    assert_eq!(download["rpc"], "download");
+    assert_eq!(download["target"], "/etc/passwd");
+    assert_eq!(download["code"], "PermissionDenied");
+}
+
+/// 测试运行超时, 空闲超时和服务端的超时上限.
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn timeouts() {
+    use exec_with_local_desktop::exec::TimeoutKind;
+
+    let mut client = ExecutorClient::connect(spawn_server(Executor::new(
+        ExecutorSettings::builder()
+            .max_timeout(Duration::from_secs(3))
+            .build(),
+    )))
+    .await
+    .unwrap();
+    let mut run = async |script: &str, timeout: Option<u32>, idle_timeout: Option<u32>| {
+        client
+            .execute(
+                ExecuteOptions::builder()
+                    .executable("bash".into())
+                    .current_dir(None)
+                    .args(vec!["-c".into(), script.into()])
+                    .leak(false)
+                    .maybe_timeout(timeout)
+                    .maybe_idle_timeout(idle_timeout)
+                    .build(),
+            )
+            .await
+            .unwrap()
+    };
+
+    let output = run("echo start; sleep 30", Some(1), None).await;
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "start\n");
+    assert_eq!(output.code, 124);
+    let exit_info = output.exit_info.unwrap();
+    assert!(exit_info.timed_out);
+    assert_eq!(exit_info.timeout_kind(), TimeoutKind::Deadline);
+
+    // 持续有输出时不会空闲超时.
+    let output = run("for i in 1 2 3; do echo $i; sleep 0.5; done", None, Some(1)).await;
+    assert_eq!(output.code, 0);
+    let output = run("echo start; sleep 30", None, Some(1)).await;
+    let exit_info = output.exit_info.unwrap();
+    assert!(exit_info.timed_out);
+    assert_eq!(exit_info.timeout_kind(), TimeoutKind::Idle);
+
+    // 没有请求超时或请求的超时超过上限时使用服务端的上限.
+    let start = std::time::Instant::now();
+    let output = run("sleep 30", Some(60), None).await;
+    assert!(output.exit_info.unwrap().timed_out);
+    assert!(start.elapsed() < Duration::from_secs(10));
+}
+
+/// 测试请求的资源限制和服务端的上限会应用到子进程上, 超出 CPU 时间时退出信息中有记录.
+#[cfg(target_os = "linux")]
+#[tokio::test(flavor = "multi_thread")]
+async fn resource_limits() {
+    use exec_with_local_desktop::exec::ResourceLimits;
+
+    let mut client = ExecutorClient::connect(spawn_server(Executor::new(
+        ExecutorSettings::builder()
+            .max_resources(ResourceLimits {
+                open_files: Some(64),
+                ..Default::default()
+            })
+            .build(),
+    )))
+    .await
+    .unwrap();
+    let options = |script: &str, limits: ResourceLimits| {
+        ExecuteOptions::builder()
+            .executable("bash".into())
+            .current_dir(None)
+            .args(vec!["-c".into(), script.into()])
+            .leak(false)
+            .limits(limits)
+            .build()
+    };
+
+    let output = client
+        .execute(options(
+            "ulimit -n; ulimit -c; ulimit -v",
+            ResourceLimits {
+                open_files: Some(128),
+                core_size: Some(0),
+                address_space: Some(1 << 30),
+                ..Default::default()
+            },
+        ))
+        .await
+        .unwrap();
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        format!("64\n0\n{}\n", 1 << 20)
+    );
+
+    let output = client
+        .execute(options(
+            "while :; do :; done",
+            ResourceLimits {
+                cpu_time: Some(1),
+                ..Default::default()
+            },
+        ))
+        .await
+        .unwrap();
+    let exit_info = output.exit_info.unwrap();
+    assert_eq!(exit_info.signal, Some(libc::SIGXCPU));
+    assert_eq!(exit_info.limit_exceeded.as_deref(), Some("cpu_time"));
+
+    // 服务端没有配置 cgroup 时无法限制内存.
+    let result = client
+        .execute(options(
+            "true",
+            ResourceLimits {
+                memory: Some(1 << 30),
+                ..Default::default()
+            },
+        ))
+        .await;
+    assert!(
+        matches!(&result, Err(Error::TonicStatus(status)) if status.code() == tonic::Code::FailedPrecondition),
+        "{result:?}"
+    );
+}
+
+/// 测试同时运行的子进程数达到上限时请求被拒绝, 或者排队等待并收到排队位置.
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn concurrency_limits() {
+    use exec_with_local_desktop::exec::Queued;
+
+    let command = |args: &[&str]| ExecuteRequestChunk {
+        request_chunk: Some(RequestChunk::Command(Command {
+            executable: "sleep".into(),
+            args: args.iter().map(ToString::to_string).collect(),
+            ..Default::default()
+        })),
+    };
+
+    let addr = spawn_server(Executor::new(
+        ExecutorSettings::builder()
+            .max_running_per_client(1)
+            .build(),
+    ));
+    let mut client = ExecuteClient::connect(addr).await.unwrap();
+    let mut running = client
+        .execute(tokio_stream::once(command(&["30"])))
+        .await
+        .unwrap()
+        .into_inner();
+    running.message().await.unwrap().unwrap();
+    let mut rejected = client
+        .execute(tokio_stream::once(command(&["0"])))
+        .await
+        .unwrap()
+        .into_inner();
+    let status = rejected.message().await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+    drop(running);
+
+    let addr = spawn_server(Executor::new(
+        ExecutorSettings::builder()
+            .max_running(1)
+            .max_queue_wait(Duration::from_secs(10))
+            .build(),
+    ));
+    let mut client = ExecuteClient::connect(addr).await.unwrap();
+    let mut running = client
+        .execute(tokio_stream::once(command(&["1"])))
+        .await
+        .unwrap()
+        .into_inner();
+    running.message().await.unwrap().unwrap();
+    let mut queued = client
+        .execute(tokio_stream::once(command(&["0"])))
+        .await
+        .unwrap()
+        .into_inner();
+    let payload = queued.message().await.unwrap().unwrap().payload;
+    assert_eq!(
+        payload,
+        Some(Payload::Queued(Queued {
+            position: 1,
+            length: 1
+        }))
+    );
+    // 前一个子进程退出后获得空位.
+    let payload = queued.message().await.unwrap().unwrap().payload;
+    assert!(
+        matches!(payload, Some(Payload::SessionStarted(_))),
+        "{payload:?}"
+    );
+    while running.message().await.unwrap().is_some() {}
+    while queued.message().await.unwrap().is_some() {}
+}
+
+/// 测试关闭服务端时通知客户端, 终止 `leak = false` 的子进程, 并返回仍在运行的 `leak = true` 的子进程.
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn graceful_shutdown() {
+    use exec_with_local_desktop::exec::ServerShutdown;
+    use exec_with_local_desktop::server::leaked;
+
+    let executor = Executor::default();
+    let mut client = ExecuteClient::connect(spawn_server(executor.clone()))
+        .await
+        .unwrap();
+    let command = |leak: bool| ExecuteRequestChunk {
+        request_chunk: Some(RequestChunk::Command(Command {
+            executable: "sleep".into(),
+            args: vec!["30".into()],
+            leak,
+            ..Default::default()
+        })),
+    };
+    let mut streams = Vec::new();
+    for leak in [false, true] {
+        let mut stream = client
+            .execute(tokio_stream::once(command(leak)))
+            .await
+            .unwrap()
+            .into_inner();
+        let Some(Payload::SessionStarted(started)) =
+            stream.message().await.unwrap().unwrap().payload
+        else {
+            panic!("first message should be SessionStarted");
+        };
+        streams.push((stream, started.session_id));
+    }
+
+    let shutdown = tokio::spawn({
+        let executor = executor.clone();
+        async move { executor.shutdown(Duration::from_secs(1)).await }
+    });
+    let [(mut killed, _), (mut leaked_stream, leaked_id)] = streams.try_into().unwrap();
+    assert_eq!(
+        killed.message().await.unwrap().unwrap().payload,
+        Some(Payload::ServerShutdown(ServerShutdown {
+            grace_period: 1,
+            terminate: true
+        }))
+    );
+    let Some(Payload::ExitInfo(exit_info)) = killed.message().await.unwrap().unwrap().payload
+    else {
+        panic!("the program should be terminated");
+    };
+    assert_eq!(exit_info.signal, Some(9));
+    assert_eq!(
+        leaked_stream.message().await.unwrap().unwrap().payload,
+        Some(Payload::ServerShutdown(ServerShutdown {
+            grace_period: 1,
+            terminate: false
+        }))
+    );
+    // 断开连接后 leak = true 的子进程继续运行.
+    assert!(leaked_stream.message().await.unwrap().is_none());
+    let leaked = shutdown.await.unwrap();
+    assert_eq!(leaked.len(), 1);
+    assert_eq!(leaked[0].session_id, leaked_id);
+    assert!(leaked[0].is_running());
+
+    let status = client
+        .execute(tokio_stream::once(command(false)))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::Unavailable);
+
+    let path = env::temp_dir().join(format!("rex-leaked-{}", random_filename()));
+    leaked::save(&path, &leaked).await.unwrap();
+    assert_eq!(leaked::load(&path).await.unwrap(), leaked);
+    leaked::save(&path, &[]).await.unwrap();
+    assert!(!path.exists());
+    std::process::Command::new("kill")
+        .arg(leaked[0].pid.to_string())
+        .status()
+        .unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn shutdown_with_stalled_client() {
+    let executor = Executor::default();
+    let mut client = ExecuteClient::connect(spawn_server(executor.clone()))
+        .await
+        .unwrap();
+    // 客户端不接收 `yes` 的输出, 输出通道很快被填满.
+    let _stream = client
+        .execute(tokio_stream::once(ExecuteRequestChunk {
+            request_chunk: Some(RequestChunk::Command(Command {
+                executable: "yes".into(),
+                ..Default::default()
+            })),
+        }))
+        .await
+        .unwrap()
+        .into_inner();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let leaked = tokio::time::timeout(Duration::from_secs(10), executor.shutdown(Duration::ZERO))
+        .await
+        .expect("shutdown should not wait for the client");
+    assert!(leaked.is_empty());
+}
@@ -0,0 +1,124 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use clap::Parser;
+use exec_with_local_desktop::args::{ServerArgs, ServiceKind};
+use exec_with_local_desktop::service::{self, AUTOSTART_ENTRY, SYSTEMD_SOCKET, SYSTEMD_UNIT};
+use rand::Rng;
+
+fn temp_dir() -> PathBuf {
+    env::temp_dir().join(format!("rex-service-{:08x}", rand::rng().random::<u32>()))
+}
+
+#[test]
+fn systemd_unit() {
+    let dir = temp_dir();
+    let program = Path::new("/opt/rex tools/rex");
+    let server = ServerArgs::parse_from([
+        "s",
+        "-b",
+        "[::1]:30521",
+        "-b",
+        "unix:/run/user/1000/rex.sock",
+        "--file-root",
+        "share",
+        "--protected-env",
+        "PATH,LD_PRELOAD",
+    ]);
+    assert!(
+        service::status(ServiceKind::Systemd, &dir)
+            .unwrap()
+            .is_none()
+    );
+
+    // 目录不存在时会被创建.
+    let path = service::install(ServiceKind::Systemd, &dir, program, &server).unwrap();
+    assert_eq!(path, dir.join(SYSTEMD_UNIT));
+    let content = fs::read_to_string(&path).unwrap();
+    let share = env::current_dir().unwrap().join("share");
+    let exec_start = format!(
+        "ExecStart=\"/opt/rex tools/rex\" server --bind [::1]:30521 --bind unix:/run/user/1000/rex.sock --protected-env PATH,LD_PRELOAD --file-root {}\n",
+        share.display()
+    );
+    assert!(content.contains(&exec_start), "{content}");
+    assert!(content.contains("KillMode=process\n"), "{content}");
+    assert!(
+        content.contains("PartOf=graphical-session.target\n"),
+        "{content}"
+    );
+    assert!(
+        content.contains("WantedBy=graphical-session.target\n"),
+        "{content}"
+    );
+
+    let socket = service::install_socket(&dir, &server).unwrap();
+    assert_eq!(socket, dir.join(SYSTEMD_SOCKET));
+    let content = fs::read_to_string(&socket).unwrap();
+    assert!(
+        content.contains(
+            "ListenStream=[::1]:30521\nListenStream=/run/user/1000/rex.sock\nSocketMode=0600\n"
+        ),
+        "{content}"
+    );
+    assert!(
+        content.contains("WantedBy=graphical-session.target\n"),
+        "{content}"
+    );
+    // 没有监听地址时不能生成 socket 单元.
+    assert!(service::install_socket(&dir, &ServerArgs::parse_from(["s"])).is_err());
+
+    let installed = service::status(ServiceKind::Systemd, &dir)
+        .unwrap()
+        .unwrap();
+    assert_eq!(installed.path, path);
+    assert!(installed.starts(program));
+    assert!(!installed.starts(Path::new("/opt/rex")));
+    // 另一种方式没有安装.
+    assert!(
+        service::status(ServiceKind::Autostart, &dir)
+            .unwrap()
+            .is_none()
+    );
+
+    assert!(service::uninstall(ServiceKind::Systemd, &dir).unwrap());
+    assert!(!socket.exists());
+    assert!(!service::uninstall(ServiceKind::Systemd, &dir).unwrap());
+    assert!(
+        service::status(ServiceKind::Systemd, &dir)
+            .unwrap()
+            .is_none()
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn autostart_entry() {
+    let dir = temp_dir();
+    let program = Path::new("/home/me/bin/rex");
+    let server = ServerArgs::parse_from(["s", "--config", "/home/me/50% $HOME.toml"]);
+    let path = service::install(ServiceKind::Autostart, &dir, program, &server).unwrap();
+    assert_eq!(path, dir.join(AUTOSTART_ENTRY));
+    let content = fs::read_to_string(&path).unwrap();
+    assert!(content.starts_with("# Generated by `rex install-service`.\n[Desktop Entry]\n"));
+    // `%` 被转义为 `%%`, 引号中的 `$` 被转义为 `\$`, 键值文件中的反斜杠再被转义一次.
+    assert!(
+        content
+            .contains("Exec=/home/me/bin/rex server --config \"/home/me/50%% \\\\$HOME.toml\"\n"),
+        "{content}"
+    );
+    let installed = service::status(ServiceKind::Autostart, &dir)
+        .unwrap()
+        .unwrap();
+    assert!(installed.starts(program));
+
+    let server = ServerArgs::parse_from(["s", "--config", "/etc/100%.toml"]);
+    let content = ServiceKind::Systemd
+        .render(program, &server.to_args())
+        .unwrap();
+    assert!(
+        content.contains("ExecStart=/home/me/bin/rex server --config /etc/100%%.toml\n"),
+        "{content}"
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
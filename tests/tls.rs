@@ -1,19 +1,22 @@
-use std::{fs, net::IpAddr, thread, time::Duration};
+use std::{fs, net::IpAddr, path::PathBuf, thread, time::Duration};
 
 use exec_with_local_desktop::{
-    CA_CERT, CLIENT_CERT, CLIENT_SECRET, SERVER_CERT, SERVER_SECRET, config_dir,
+    CA_CERT, CA_SECRET, CLIENT_CERT, CLIENT_SECRET, Error, SERVER_CERT, SERVER_SECRET,
+    client::{ExecuteOptions, ExecutorClient},
+    config_dir,
     exec::{
         Command, ExecuteRequestChunk, execute_client::ExecuteClient,
         execute_request_chunk::RequestChunk, execute_server::ExecuteServer,
     },
-    server::Executor,
+    gen_cert::CertGenerator,
+    server::{Executor, config::ServerConfig},
 };
 use rcgen::{
     CertificateParams, DistinguishedName, DnType, IsCa, Issuer, KeyPair, KeyUsagePurpose, SanType,
     SerialNumber,
 };
 use time::OffsetDateTime;
-use tonic::transport::{Channel, Identity, Server, ServerTlsConfig};
+use tonic::transport::{Channel, Identity, Server, ServerTlsConfig, server::TcpIncoming};
 use tracing::{Level, info};
 
 #[test]
@@ -136,7 +139,7 @@ fn tls_server(ca: tonic::transport::Certificate) {
                         .identity(Identity::from_pem(server_cert, server_secret)),
                 )
                 .unwrap()
-                .add_service(ExecuteServer::new(Executor))
+                .add_service(ExecuteServer::new(Executor::default()))
                 .serve(addr),
         )
         .await
@@ -173,6 +176,7 @@ fn tls_client(ca: tonic::transport::Certificate) {
                     args: ["-c".into(), "ls".into()].into(),
                     current_dir: None,
                     leak: false,
+                    ..Default::default()
                 })),
             }))
             .await
@@ -201,3 +205,203 @@ fn tls_grpc() {
     c_join.join().unwrap();
     s_join.join().unwrap();
 }
+
+fn permission_denied<T>(result: &Result<T, Error>) -> bool {
+    matches!(result, Err(Error::TonicStatus(status)) if status.code() == tonic::Code::PermissionDenied)
+}
+
+/// 测试服务端根据客户端证书中的 CN 为客户端分配角色.
+#[tokio::test(flavor = "multi_thread")]
+async fn authorize_by_identity() {
+    let dir = std::env::temp_dir().join(format!("rex-auth-{}", std::process::id()));
+    let (alice_dir, bob_dir) = (dir.join("alice"), dir.join("bob"));
+    for path in [&dir, &alice_dir, &bob_dir] {
+        fs::create_dir_all(path).unwrap();
+    }
+    let cg = CertGenerator::new(dir.clone());
+    let ca_dir = dir.join("ca");
+    let issuer = cg.generate_ca(Some(&ca_dir)).unwrap();
+    cg.generate_server(&issuer);
+    // CA 私钥只保存在单独的目录中, 且只有所有者可以读写.
+    assert!(!dir.join(CA_SECRET).exists());
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt as _;
+        let mode = fs::metadata(ca_dir.join(CA_SECRET))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+    // 使用保存的 CA 私钥签发其他客户端的证书.
+    let issuer = CertGenerator::load_ca(&ca_dir).unwrap();
+    CertGenerator::new(alice_dir.clone()).generate_client(&issuer, "alice");
+    CertGenerator::new(bob_dir.clone()).generate_client(&issuer, "bob");
+    for client_dir in [&alice_dir, &bob_dir] {
+        fs::copy(dir.join(CA_CERT), client_dir.join(CA_CERT)).unwrap();
+    }
+
+    let config: ServerConfig = toml::from_str(
+        r#"
+        [auth.clients]
+        alice = "admin"
+        bob = "runner"
+
+        [auth.roles.admin]
+        sessions = true
+        files = true
+
+        [auth.roles.runner]
+        executables = ["**/echo"]
+        "#,
+    )
+    .unwrap();
+    let incoming = TcpIncoming::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let port = incoming.local_addr().unwrap().port();
+    tokio::spawn(
+        Server::builder()
+            .tls_config(
+                ServerTlsConfig::new()
+                    .client_ca_root(tonic::transport::Certificate::from_pem(
+                        fs::read(dir.join(CA_CERT)).unwrap(),
+                    ))
+                    .identity(Identity::from_pem(
+                        fs::read(dir.join(SERVER_CERT)).unwrap(),
+                        fs::read(dir.join(SERVER_SECRET)).unwrap(),
+                    )),
+            )
+            .unwrap()
+            .add_service(ExecuteServer::new(Executor::new(
+                config.executor_settings().unwrap(),
+            )))
+            .serve_with_incoming(incoming),
+    );
+    let address = format!("https://localhost:{port}");
+    let connect = async |cert_dir: &PathBuf| {
+        ExecutorClient::connect_tls(address.clone(), cert_dir.clone(), "localhost".into())
+            .await
+            .unwrap()
+    };
+    let options = |executable: &str| {
+        ExecuteOptions::builder()
+            .executable(executable.into())
+            .current_dir(Some("/tmp".into()))
+            .args(vec!["hi".into()])
+            .leak(false)
+            .build()
+    };
+    let mut alice = connect(&alice_dir).await;
+    let output = alice.execute(options("printf")).await.unwrap();
+    assert_eq!(output.stdout, b"hi");
+    let mut bob = connect(&bob_dir).await;
+    let output = bob.execute(options("echo")).await.unwrap();
+    assert_eq!(output.stdout, b"hi\n");
+    let result = bob.execute(options("printf")).await;
+    assert!(permission_denied(&result), "{result:?}");
+    let result = bob
+        .download("/etc/hostname".into(), dir.join("hostname"))
+        .await;
+    assert!(permission_denied(&result), "{result:?}");
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// 测试通过 Unix 域套接字使用 TLS 连接服务端, 套接字文件的权限和客户端的 UID 检查.
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn unix_socket() {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    use exec_with_local_desktop::server::listener::{UnixSocketOptions, bind_unix};
+
+    let dir = std::env::temp_dir().join(format!("rex-unix-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let cg = CertGenerator::new(dir.clone());
+    let issuer = cg.generate_ca(None).unwrap();
+    cg.generate_server(&issuer);
+    cg.generate_client(&issuer, "alice");
+
+    let path = dir.join("rex.sock");
+    let options = UnixSocketOptions {
+        mode: 0o600,
+        same_uid: true,
+        ..Default::default()
+    };
+    let incoming = bind_unix(&path, &options).unwrap();
+    assert_eq!(
+        fs::metadata(&path).unwrap().permissions().mode() & 0o7777,
+        0o600
+    );
+    // 仍在监听的套接字不会被覆盖.
+    assert!(bind_unix(&path, &options).is_err());
+    tokio::spawn(
+        Server::builder()
+            .tls_config(
+                ServerTlsConfig::new()
+                    .client_ca_root(tonic::transport::Certificate::from_pem(
+                        fs::read(dir.join(CA_CERT)).unwrap(),
+                    ))
+                    .identity(Identity::from_pem(
+                        fs::read(dir.join(SERVER_CERT)).unwrap(),
+                        fs::read(dir.join(SERVER_SECRET)).unwrap(),
+                    )),
+            )
+            .unwrap()
+            .add_service(ExecuteServer::new(Executor::default()))
+            .serve_with_incoming(incoming),
+    );
+
+    let address = format!("unix:{}", path.display());
+    let mut client = ExecutorClient::connect_tls(address.clone(), dir.clone(), "localhost".into())
+        .await
+        .unwrap();
+    let output = client
+        .execute(
+            ExecuteOptions::builder()
+                .executable("printf".into())
+                .current_dir(None)
+                .args(vec!["hi".into()])
+                .leak(false)
+                .build(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(output.stdout, b"hi");
+
+    let mut sleeper = ExecutorClient::connect_tls(address, dir.clone(), "localhost".into())
+        .await
+        .unwrap();
+    let running = tokio::spawn(async move {
+        sleeper
+            .execute(
+                ExecuteOptions::builder()
+                    .executable("sleep".into())
+                    .current_dir(None)
+                    .args(vec!["30".into()])
+                    .leak(false)
+                    .build(),
+            )
+            .await
+    });
+    let session = loop {
+        if let Some(session) = client.list_sessions().await.unwrap().pop() {
+            break session;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    };
+    assert_eq!(session.identity, "alice");
+    assert!(
+        session
+            .peer_address
+            .starts_with(&format!("unix:uid={}", nix::unistd::getuid())),
+        "{}",
+        session.peer_address
+    );
+    client
+        .terminate_session(session.session_id, None)
+        .await
+        .unwrap();
+    running.await.unwrap().unwrap();
+
+    fs::remove_dir_all(&dir).unwrap();
+}